@@ -0,0 +1,200 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ipnet::IpNet;
+
+use crate::api::AppState;
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+pub async fn allowlist_layer(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let config = state.config();
+    let app = &config.app;
+    if app.allowed_cidrs.is_empty() {
+        return next.run(req).await;
+    }
+
+    let client = client_ip(peer.ip(), req.headers(), &app.trusted_proxies);
+    if !contains(&app.allowed_cidrs, client) {
+        tracing::warn!("Rejected request from disallowed source address {}", client);
+        return (
+            StatusCode::FORBIDDEN,
+            "Forbidden: Source address not allowed\n",
+        )
+            .into_response();
+    }
+
+    tracing::debug!("Accepted request from source address {}", client);
+    next.run(req).await
+}
+
+/// Resolve the originating client address.
+///
+/// `X-Forwarded-For` is only honored when the connecting peer is a trusted proxy, and is walked
+/// right to left so that entries added by trusted proxies are skipped and client-supplied values
+/// can't be used to spoof an allowed address.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    if !contains(trusted_proxies, peer) {
+        return peer;
+    }
+
+    let forwarded = headers
+        .get_all(FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|entry| entry.trim().parse::<IpAddr>())
+        .collect::<Vec<_>>();
+
+    let mut client = peer;
+    for entry in forwarded.into_iter().rev() {
+        match entry {
+            Ok(addr) => {
+                client = addr;
+                if !contains(trusted_proxies, addr) {
+                    break;
+                }
+            }
+            // an unparseable hop can't be trusted, so stop at the last known address
+            Err(_) => break,
+        }
+    }
+
+    client
+}
+
+fn contains(networks: &[IpNet], addr: IpAddr) -> bool {
+    // compare IPv4-mapped IPv6 addresses against IPv4 blocks
+    let addr = match addr {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+        IpAddr::V4(_) => addr,
+    };
+    networks.iter().any(|network| network.contains(&addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks(cidrs: &[&str]) -> Vec<IpNet> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap()).collect()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(FORWARDED_FOR, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_header() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["192.168.1.10"]);
+
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), &headers, &trusted),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip(ip("203.0.113.7"), &headers, &[]),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn trusted_proxy_chain_is_walked_right_to_left() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        // client, then an outer proxy, as appended by the proxies in front of the peer
+        let headers = forwarded_for(&["198.51.100.4, 10.1.0.2", "10.2.0.3"]);
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("198.51.100.4")
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entry_is_skipped() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        // the client sent "192.168.1.10" itself, the proxy appended the real address
+        let headers = forwarded_for(&["192.168.1.10, 203.0.113.7"]);
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn unparseable_hop_stops_at_last_known_address() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["198.51.100.4, unknown, 10.1.0.2"]);
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.1.0.2")
+        );
+    }
+
+    #[test]
+    fn all_trusted_hops_resolve_to_leftmost() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["10.3.0.1, 10.1.0.2"]);
+
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &headers, &trusted),
+            ip("10.3.0.1")
+        );
+        assert_eq!(
+            client_ip(ip("10.0.0.1"), &HeaderMap::new(), &trusted),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn ipv4_mapped_peer_matches_ipv4_networks() {
+        let trusted = networks(&["10.0.0.0/8"]);
+        let headers = forwarded_for(&["198.51.100.4"]);
+
+        assert_eq!(
+            client_ip(ip("::ffff:10.0.0.1"), &headers, &trusted),
+            ip("198.51.100.4")
+        );
+        assert!(contains(
+            &networks(&["203.0.113.0/24"]),
+            ip("::ffff:203.0.113.7")
+        ));
+        assert!(!contains(
+            &networks(&["203.0.113.0/24"]),
+            ip("::ffff:198.51.100.4")
+        ));
+    }
+
+    #[test]
+    fn contains_matches_any_network() {
+        let allowed = networks(&["192.168.0.0/16", "2001:db8::/32"]);
+
+        assert!(contains(&allowed, ip("192.168.4.2")));
+        assert!(contains(&allowed, ip("2001:db8::1")));
+        assert!(!contains(&allowed, ip("192.169.0.1")));
+        assert!(!contains(&allowed, ip("2001:db9::1")));
+        assert!(!contains(&[], ip("192.168.4.2")));
+    }
+}
@@ -1,125 +1,921 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use axum::{
-    Router,
-    extract::{Path, State},
-    http::StatusCode,
+    BoxError, Router,
+    body::Body,
+    error_handling::HandleErrorLayer,
+    extract::{
+        Path, Query, Request, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::{HeaderMap, StatusCode, header},
     middleware,
     response::{IntoResponse, Json, Response},
     routing::{get, post},
 };
+use axum_server::tls_rustls::RustlsConfig;
+use hyper_util::rt::TokioTimer;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Socket, TcpKeepalive, Type};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+use tower::ServiceBuilder;
+use tower::limit::ConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower_http::timeout::TimeoutLayer;
 use tower_http::trace::TraceLayer;
-use tokio::sync::Semaphore;
+use tracing::Instrument;
 
+use crate::allowlist;
 use crate::auth;
-use crate::config;
+use crate::cancel::RunningBuilds;
+use crate::config::{self, AppConfig, LimitMode};
+use crate::diag;
+use crate::events::{BuildEvent, EventBus};
+use crate::idempotency::{self, IdempotencyCache};
+use crate::logging::{self, LogHandle};
+use crate::project::{BuildRequest, CloneLimit, ProjectConfig, TriggerMetadata};
+use crate::schedule::{self, Schedule};
+use crate::shutdown;
+use crate::status::{self, BuildRecord, BuildStatus};
+use crate::webhook;
 
-pub struct BuildHookResponse;
+/// Largest webhook body accepted, matching axum's default body limit.
+const BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// How long the deep health check waits for a project's repository to answer.
+const REMOTE_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long cancelled builds get to wind down on shutdown once their processes are terminated.
+const CANCEL_GRACE: Duration = Duration::from_secs(10);
+
+/// Address the server listens on.
+const LISTEN_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 3000);
+
+pub struct BuildHookResponse {
+    /// Path of the project's status, used to point clients at where to follow progress.
+    status_path: String,
+    build_id: u64,
+    /// Whether the trigger coalesced into a pending build instead of starting one.
+    coalesced: bool,
+    /// Whether the client asked for JSON with its `Accept` header.
+    json: bool,
+}
 
 impl IntoResponse for BuildHookResponse {
     fn into_response(self) -> Response {
-        // State is accessed here in the IntoResponse implementation
-        (
-            StatusCode::OK,
-            "Build started; rollout restart will run after build completes\n",
-        )
-            .into_response()
+        // builds run in the background, so point clients at where to follow progress
+        let location = [(header::LOCATION, self.status_path)];
+        if self.json {
+            let status = if self.coalesced {
+                "coalesced"
+            } else {
+                "started"
+            };
+            let body = Json(serde_json::json!({
+                "status": status,
+                "build_id": self.build_id,
+            }));
+            return (StatusCode::ACCEPTED, location, body).into_response();
+        }
+
+        let body = if self.coalesced {
+            format!("Build coalesced into pending build {}\n", self.build_id)
+        } else {
+            "Build started; rollout restart will run after build completes\n".to_string()
+        };
+        (StatusCode::ACCEPTED, location, body).into_response()
     }
 }
 
+/// Whether the request's `Accept` header asks for a JSON response.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|media_type| media_type.trim() == "application/json")
+        })
+}
+
 pub struct AppState {
-    config: config::HookConfig,
+    /// Active configuration, replaced on reload.
+    config: RwLock<Arc<config::HookConfig>>,
+    status: BuildStatus,
     github_token: String,
-    build_locks: HashMap<String, Arc<Semaphore>>,
+    /// Per-project build locks, created on first use so reloaded projects get one too.
+    build_locks: Mutex<HashMap<String, Arc<Semaphore>>>,
+    /// Limit on concurrent builds across all projects, when configured.
+    global_builds: Option<Arc<Semaphore>>,
+    /// Limit on concurrent clones across all builds, when configured.
+    clone_limit: Option<Arc<CloneLimit>>,
+    /// Recently handled webhook deliveries, per project.
+    deliveries: webhook::DeliveryCache,
+    /// Builds started per client idempotency key, per project.
+    idempotency: IdempotencyCache,
+    /// Handle for changing the log filter at runtime.
+    log_handle: LogHandle,
+    /// Build events streamed to WebSocket subscribers.
+    events: EventBus,
+    /// Running builds, for cancellation.
+    running: RunningBuilds,
+    /// Build per project waiting to start in coalesce mode, with its request.
+    pending: Mutex<HashMap<String, (u64, SharedRequest)>>,
+    /// When each project last had a build queued, for `min_interval_secs`.
+    last_queued: Mutex<HashMap<String, Instant>>,
+    /// `app.instance_name` the server started with, labelled on metrics like it is on log spans.
+    instance_name: Option<String>,
 }
 
-pub async fn start(config: config::HookConfig, github_token: String) {
-    let build_locks: HashMap<String, Arc<Semaphore>> = config
-        .projects
-        .keys()
-        .map(|slug| (slug.clone(), Arc::new(Semaphore::new(1))))
-        .collect();
-    let app_state = Arc::new(AppState {
-        config,
+/// Request of a queued build, which coalesced triggers may update until it starts.
+type SharedRequest = Arc<Mutex<BuildRequest>>;
+
+/// Outcome of recording a triggered build.
+enum Enqueued {
+    Queued(u64, SharedRequest),
+    /// Folded into the pending build with this id.
+    Coalesced(u64),
+    /// Rejected because this many builds are already waiting.
+    Full(usize),
+}
+
+impl AppState {
+    /// Snapshot of the active configuration.
+    pub fn config(&self) -> Arc<config::HookConfig> {
+        Arc::clone(&self.config.read().unwrap())
+    }
+
+    /// Record a triggered build as waiting to start.
+    ///
+    /// When coalescing, a trigger for a project that already has a pending build updates that
+    /// build's request instead.
+    fn enqueue(
+        &self,
+        slug: &str,
+        request: BuildRequest,
+        coalesce: bool,
+        limit: Option<usize>,
+    ) -> Enqueued {
+        let mut pending = self.pending.lock().unwrap();
+        if coalesce && let Some((build_id, pending_request)) = pending.get(slug) {
+            pending_request.lock().unwrap().coalesce(request);
+            return Enqueued::Coalesced(*build_id);
+        }
+
+        let build_id = match self.status.queue(slug, limit) {
+            Ok(build_id) => build_id,
+            Err(depth) => return Enqueued::Full(depth),
+        };
+        self.last_queued
+            .lock()
+            .unwrap()
+            .insert(slug.to_string(), Instant::now());
+        let request = Arc::new(Mutex::new(request));
+        if coalesce {
+            pending.insert(slug.to_string(), (build_id, Arc::clone(&request)));
+        }
+        Enqueued::Queued(build_id, request)
+    }
+
+    /// Time left until `slug` may queue another build, when it queued one less than
+    /// `min_interval` ago.
+    fn cool_down(&self, slug: &str, min_interval: Duration) -> Option<Duration> {
+        let last_queued = self.last_queued.lock().unwrap();
+        let elapsed = last_queued.get(slug)?.elapsed();
+        min_interval
+            .checked_sub(elapsed)
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Take the final request of a build that's about to start, so later triggers queue anew.
+    fn start_pending(
+        &self,
+        slug: &str,
+        build_id: u64,
+        request: &Mutex<BuildRequest>,
+    ) -> BuildRequest {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(slug).is_some_and(|(id, _)| *id == build_id) {
+            pending.remove(slug);
+        }
+        request.lock().unwrap().clone()
+    }
+
+    fn build_lock(&self, slug: &str) -> Arc<Semaphore> {
+        let mut build_locks = self.build_locks.lock().unwrap();
+        let lock = build_locks
+            .entry(slug.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(1)));
+        Arc::clone(lock)
+    }
+}
+
+/// Shared state of a server starting with `config`.
+fn new_state(
+    config: config::HookConfig,
+    status: BuildStatus,
+    github_token: String,
+    log_handle: LogHandle,
+) -> Arc<AppState> {
+    let global_builds = config
+        .app
+        .max_global_builds
+        .map(|limit| Arc::new(Semaphore::new(limit)));
+    let clone_limit = config
+        .app
+        .max_concurrent_clones
+        .map(|limit| Arc::new(CloneLimit::new(limit)));
+    let deliveries =
+        webhook::DeliveryCache::new(Duration::from_secs(config.app.delivery_window_secs));
+    let idempotency =
+        IdempotencyCache::new(Duration::from_secs(config.app.idempotency_window_secs));
+    let instance_name = config.app.instance_name.clone();
+    Arc::new(AppState {
+        config: RwLock::new(Arc::new(config)),
+        status,
         github_token,
-        build_locks,
-    });
+        build_locks: Mutex::new(HashMap::new()),
+        global_builds,
+        clone_limit,
+        deliveries,
+        idempotency,
+        log_handle,
+        events: EventBus::new(),
+        running: RunningBuilds::new(),
+        pending: Mutex::new(HashMap::new()),
+        last_queued: Mutex::new(HashMap::new()),
+        instance_name,
+    })
+}
 
+/// Public and protected routes, without the server-wide layers.
+fn routes(app_state: Arc<AppState>) -> Router {
     // Public routes (no auth required)
     let public_routes = Router::new().route("/health", get(healthcheck));
 
     // Protected routes (auth required)
     let protected_routes = Router::new()
+        .route("/build-all", post(build_all))
         .route("/{project}", post(handler))
-        .route_layer(middleware::from_fn(auth::auth_layer));
+        .route("/{project}/cancel", post(cancel_build))
+        .route("/{project}/image/{image}", post(image_handler))
+        .route("/health/deep", get(deep_healthcheck))
+        .route("/diag", get(diagnostics))
+        .route("/metrics", get(metrics))
+        .route("/status", get(status_all))
+        .route("/status/{project}", get(status_project))
+        .route("/history", get(history_all))
+        .route("/history/{project}", get(history_project))
+        .route("/log-level", post(set_log_level))
+        .route("/reload", post(reload_config))
+        .route("/validate", post(validate_config))
+        .route("/config", get(show_config))
+        .route("/ws/{project}", get(events_socket))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&app_state),
+            auth::auth_layer,
+        ))
+        // source address checks run before auth
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&app_state),
+            allowlist::allowlist_layer,
+        ));
 
-    // build our application with public and protected routes
-    let app = Router::new()
+    Router::new()
         .merge(public_routes)
         .merge(protected_routes)
         .with_state(app_state)
-        .layer(TraceLayer::new_for_http());
+}
 
-    tracing::info!("Server starting on 0.0.0.0:3000");
+pub async fn start(
+    config: config::HookConfig,
+    status: BuildStatus,
+    github_token: String,
+    log_handle: LogHandle,
+) {
+    let app_state = new_state(config, status, github_token, log_handle);
+
+    tokio::spawn(run_schedules(Arc::clone(&app_state)).in_current_span());
+
+    let instance_span = tracing::Span::current();
+    let base_path = app_state.config().app.base_path.clone();
+    let tls = app_state.config().app.tls();
+    let request_timeout = Duration::from_secs(app_state.config().app.request_timeout_secs);
+    let max_connections = app_state.config().app.max_connections;
+    let idle_timeout = Duration::from_secs(app_state.config().app.idle_timeout_secs);
+    let tcp_keepalive = app_state
+        .config()
+        .app
+        .tcp_keepalive_secs
+        .map(Duration::from_secs);
+    let shutdown_timeout = Duration::from_secs(app_state.config().app.shutdown_timeout_secs);
+    let server_handle = axum_server::Handle::new();
+    let shutdown = tokio::spawn(
+        shut_down_on_signal(
+            Arc::clone(&app_state),
+            server_handle.clone(),
+            shutdown_timeout,
+        )
+        .in_current_span(),
+    );
+
+    let routes = routes(app_state);
+
+    // axum can't nest at the root, so only nest when a prefix is configured
+    let app = if base_path.is_empty() {
+        routes
+    } else {
+        Router::new().nest(&base_path, routes)
+    }
+    // builds are offloaded, so a handler that doesn't answer in time is stuck
+    .layer(TimeoutLayer::with_status_code(
+        StatusCode::GATEWAY_TIMEOUT,
+        request_timeout,
+    ))
+    // connections are served on their own tasks, so request spans name the instance's as parent
+    .layer(
+        TraceLayer::new_for_http().make_span_with(move |request: &Request| {
+            tracing::debug_span!(
+                parent: &instance_span,
+                "request",
+                method = %request.method(),
+                uri = %request.uri(),
+                version = ?request.version(),
+            )
+        }),
+    );
+
+    // shed requests over the limit right away rather than queueing them behind a burst
+    let app = match max_connections {
+        Some(limit) => app.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }))
+                .layer(LoadShedLayer::new())
+                .layer(ConcurrencyLimitLayer::new(limit)),
+        ),
+        None => app,
+    };
+
+    let listener = match bind_listener(tcp_keepalive) {
+        Ok(listener) => listener,
+        Err(e) => crate::fail_startup(format!(
+            "Could not listen on {}: {}",
+            SocketAddr::from(LISTEN_ADDR),
+            e
+        )),
+    };
+
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    match tls {
+        Some(tls) => {
+            // fail at startup on a bad certificate rather than on the first handshake
+            let rustls = match RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path).await {
+                Ok(rustls) => rustls,
+                Err(e) => crate::fail_startup(format!(
+                    "Could not load TLS certificate `{}` and key `{}`: {}",
+                    tls.cert_path.display(),
+                    tls.key_path.display(),
+                    e
+                )),
+            };
+
+            tracing::info!("Server starting on https://0.0.0.0:3000{}", base_path);
+            let mut server = axum_server::from_tcp_rustls(listener, rustls);
+            set_idle_timeout(server.http_builder(), idle_timeout);
+            server.handle(server_handle).serve(service).await.unwrap();
+        }
+        None => {
+            tracing::info!("Server starting on 0.0.0.0:3000{}", base_path);
+
+            let mut server = axum_server::from_tcp(listener);
+            set_idle_timeout(server.http_builder(), idle_timeout);
+            server.handle(server_handle).serve(service).await.unwrap();
+        }
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // the runtime stops with the server, so wait for running builds to wind down first
+    let _ = shutdown.await;
+    tracing::info!("Server stopped");
 }
 
-async fn healthcheck() -> Json<serde_json::Value> {
+/// On SIGINT or SIGTERM, stop accepting requests and give running builds `timeout` to finish,
+/// then terminate their build processes so none are left behind.
+async fn shut_down_on_signal(
+    state: Arc<AppState>,
+    server_handle: axum_server::Handle,
+    timeout: Duration,
+) {
+    shutdown::signalled().await;
+    tracing::info!(
+        "Shutting down, waiting up to {:?} for {} running build(s)",
+        timeout,
+        state.running.count()
+    );
+    state.running.shut_down();
+    server_handle.graceful_shutdown(Some(timeout));
+
+    if !wait_for_builds(&state, timeout).await {
+        tracing::warn!(
+            "Terminating {} build(s) still running after {:?}",
+            state.running.count(),
+            timeout
+        );
+        state.running.cancel_all();
+        if !wait_for_builds(&state, CANCEL_GRACE).await {
+            tracing::warn!(
+                "{} cancelled build(s) still running, exiting anyway",
+                state.running.count()
+            );
+        }
+    }
+}
+
+/// Wait up to `timeout` for running builds to finish, returning whether they all did.
+async fn wait_for_builds(state: &AppState, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while state.running.count() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    true
+}
+
+/// Bind the listening socket. Accepted connections inherit its TCP keep-alive settings.
+fn bind_listener(tcp_keepalive: Option<Duration>) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if let Some(time) = tcp_keepalive {
+        socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+    }
+    socket.bind(&SocketAddr::from(LISTEN_ADDR).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Close HTTP/1 connections that don't send the next request's headers in time, so idle
+/// keep-alive connections don't accumulate.
+fn set_idle_timeout(
+    builder: &mut hyper_util::server::conn::auto::Builder<hyper_util::rt::TokioExecutor>,
+    idle_timeout: Duration,
+) {
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout);
+}
+
+/// Query parameters of the health check.
+#[derive(Debug, Default, Deserialize)]
+struct HealthParams {
+    /// Formerly ran the deep check, which now needs a token at `/health/deep`.
+    #[serde(default)]
+    deep: bool,
+}
+
+async fn healthcheck(Query(params): Query<HealthParams>) -> Response {
+    // the deep check lists projects and runs a git command per project, so anonymous callers
+    // are pointed at the authenticated route rather than silently getting the liveness check
+    if params.deep {
+        return (
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized: deep health checks require a bearer token at /health/deep",
+        )
+            .into_response();
+    }
+
     Json(serde_json::json!({
         "status": "ok"
     }))
+    .into_response()
 }
 
-async fn handler(Path(slug): Path<String>, State(state): State<Arc<AppState>>) -> Response {
-    match state.config.projects.get(&slug) {
+/// Check the repositories of the projects the token can access can be reached.
+async fn deep_healthcheck(State(state): State<Arc<AppState>>) -> Response {
+    let config = state.config();
+    let mut checks = tokio::task::JoinSet::new();
+    for slug in config.projects.keys().filter(|slug| auth::can_access(slug)) {
+        let slug = slug.clone();
+        let config = Arc::clone(&config);
+        let github_token = state.github_token.clone();
+        checks.spawn(async move {
+            let project_slug = slug.clone();
+            let check = tokio::task::spawn_blocking(move || {
+                config.projects[&project_slug].check_remote(&config.app, &github_token)
+            });
+            let result = match tokio::time::timeout(REMOTE_CHECK_TIMEOUT, check).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => Some(Err(format!("Remote check task failed: {}", e))),
+                Err(_) => Some(Err(format!(
+                    "No answer within {}s",
+                    REMOTE_CHECK_TIMEOUT.as_secs()
+                ))),
+            };
+            (slug, result)
+        });
+    }
+
+    let mut projects = serde_json::Map::new();
+    let mut healthy = true;
+    while let Some(check) = checks.join_next().await {
+        let Ok((slug, result)) = check else {
+            continue;
+        };
+        // tarball projects have no repository to reach
+        let Some(result) = result else {
+            continue;
+        };
+        if let Err(e) = &result {
+            tracing::warn!("Repository of project `{}` is unreachable: {}", slug, e);
+            healthy = false;
+        }
+        projects.insert(slug, serde_json::json!({ "reachable": result.is_ok() }));
+    }
+
+    // like /diag, an unhealthy check answers 503 so monitors can alert on the status code alone
+    let (status, text) = if healthy {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    (
+        status,
+        Json(serde_json::json!({
+            "status": text,
+            "projects": projects,
+        })),
+    )
+        .into_response()
+}
+
+/// Query parameters of the build triggers.
+#[derive(Debug, Default, Deserialize)]
+struct TriggerParams {
+    /// Push over existing tags of images with `immutable_tags`.
+    #[serde(default)]
+    force: bool,
+}
+
+async fn handler(
+    Path(slug): Path<String>,
+    Query(params): Query<TriggerParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let request = BuildRequest {
+        force: params.force,
+        ..BuildRequest::default()
+    };
+    trigger_build(slug, request, state, headers, body).await
+}
+
+async fn image_handler(
+    Path((slug, image)): Path<(String, String)>,
+    Query(params): Query<TriggerParams>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let config = state.config();
+    match config
+        .projects
+        .get(&slug)
+        .filter(|_| auth::can_access(&slug))
+    {
+        Some(project) if !project.has_image(&image) => {
+            tracing::warn!("No image `{}` configured for project `{}`", image, slug);
+            (
+                StatusCode::NOT_FOUND,
+                format!("No image `{}` configured for project `{}`\n", image, slug),
+            )
+                .into_response()
+        }
+        _ => {
+            let request = BuildRequest {
+                images: Some(vec![image]),
+                force: params.force,
+                ..BuildRequest::default()
+            };
+            trigger_build(slug, request, state, headers, body).await
+        }
+    }
+}
+
+/// Start a build of the project, or of the images named by the route or a `{"images": [...]}`
+/// body.
+async fn trigger_build(
+    slug: String,
+    mut request: BuildRequest,
+    state: Arc<AppState>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let config = state.config();
+    // projects outside the token's scope look the same as unknown ones
+    match config
+        .projects
+        .get(&slug)
+        .filter(|_| auth::can_access(&slug))
+    {
         Some(project) => {
+            // a provider sends every event type to the same URL, only some of them should build
+            if let Some(event) = webhook::event_type(&headers) {
+                if event == webhook::PING_EVENT {
+                    tracing::info!("Received ping for project `{}`", slug);
+                    return (StatusCode::OK, "pong\n").into_response();
+                }
+                if !project.builds_on_event(&event) {
+                    tracing::info!(
+                        "Event `{}` doesn't trigger builds for project `{}`, skipping...",
+                        event,
+                        slug
+                    );
+                    return (
+                        StatusCode::OK,
+                        format!("Skipped: event `{}` not watched\n", event),
+                    )
+                        .into_response();
+                }
+            }
+
             tracing::info!(
                 "Received build hook for project `{}`, building...",
                 project.slug()
             );
-            let build_lock = match state.build_locks.get(&slug) {
-                Some(lock) => Arc::clone(lock),
-                None => {
-                    tracing::error!("No build lock configured for project `{}`", slug);
-                    return (
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Build lock missing for project `{}`\n", slug),
-                    )
-                        .into_response();
+
+            // buffer the body before any permit is taken, bounded so a slow client can't stall
+            let body_timeout = Duration::from_secs(config.app.body_timeout_secs);
+            let body =
+                match tokio::time::timeout(body_timeout, axum::body::to_bytes(body, BODY_LIMIT))
+                    .await
+                {
+                    Ok(Ok(body)) => body,
+                    Ok(Err(e)) => {
+                        tracing::warn!("Could not read body for project `{}`: {}", slug, e);
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            format!("Could not read request body: {}\n", e),
+                        )
+                            .into_response();
+                    }
+                    Err(_) => {
+                        tracing::warn!(
+                            "Timed out reading body for project `{}` after {:?}",
+                            slug,
+                            body_timeout
+                        );
+                        return (
+                            StatusCode::REQUEST_TIMEOUT,
+                            "Timed out reading request body\n",
+                        )
+                            .into_response();
+                    }
+                };
+
+            if !project.webhook_secret_envs().is_empty()
+                && let Err(e) =
+                    webhook::verify_signature(&headers, &body, project.webhook_secret_envs())
+            {
+                tracing::warn!("Rejecting trigger for project `{}`: {}", slug, e);
+                return (StatusCode::UNAUTHORIZED, format!("{}\n", e)).into_response();
+            }
+
+            let delivery = webhook::delivery_id(&headers);
+            if let Some(delivery) = &delivery
+                && state.deliveries.is_duplicate(&slug, delivery)
+            {
+                tracing::info!(
+                    "Ignoring duplicate delivery `{}` for project `{}`",
+                    delivery,
+                    slug
+                );
+                return (StatusCode::OK, "Duplicate delivery ignored\n").into_response();
+            }
+
+            if request.images.is_none()
+                && let Some(images) = webhook::requested_images(&body)
+            {
+                let unknown: Vec<&str> = images
+                    .iter()
+                    .filter(|image| !project.has_image(image))
+                    .map(String::as_str)
+                    .collect();
+                if images.is_empty() || !unknown.is_empty() {
+                    let message = if images.is_empty() {
+                        "Requested images must name at least one image".to_string()
+                    } else {
+                        format!(
+                            "No image(s) `{}` configured for project `{}`",
+                            unknown.join("`, `"),
+                            slug
+                        )
+                    };
+                    tracing::warn!("Rejecting trigger for project `{}`: {}", slug, message);
+                    return (StatusCode::BAD_REQUEST, format!("{}\n", message)).into_response();
                 }
+                request.images = Some(images);
+            }
+
+            request.trigger = TriggerMetadata {
+                delivery_id: delivery.clone(),
+                request_id: webhook::request_id(&headers),
+                event: webhook::event_type(&headers),
             };
-            let permit = match build_lock.try_acquire_owned() {
-                Ok(permit) => permit,
-                Err(_) => {
-                    tracing::warn!("Build already in progress for project `{}`", slug);
+
+            // a retried request answers with the build it already started, before any permit
+            // is taken so it isn't rejected as busy by its own build
+            let idempotency_key = idempotency::idempotency_key(&headers);
+            if let Some(key) = &idempotency_key
+                && let Some(build_id) = state.idempotency.build_for(&slug, key)
+            {
+                return existing_build(&state, &config.app.base_path, &slug, build_id);
+            }
+
+            if project.filters_branches()
+                && let Some(branch) = webhook::pushed_branch(&body)
+            {
+                if !project.watches_branch(&branch) {
+                    tracing::info!(
+                        "Branch `{}` not watched for project `{}`, skipping...",
+                        branch,
+                        slug
+                    );
                     return (
-                        StatusCode::CONFLICT,
-                        format!("Build already in progress for project `{}`\n", slug),
+                        StatusCode::OK,
+                        format!("Skipped: branch `{}` not watched\n", branch),
                     )
                         .into_response();
                 }
-            };
+                request.branch = Some(branch);
+            }
+
+            // coalesced triggers don't start a build, so only throttle when none is pending
+            if let Some(min_interval) = project.min_interval()
+                && !state.pending.lock().unwrap().contains_key(&slug)
+                && let Some(left) = state.cool_down(&slug, min_interval)
+            {
+                let retry_after = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                tracing::warn!(
+                    "Project `{}` built less than {:?} ago, rejecting build",
+                    slug,
+                    min_interval
+                );
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    format!(
+                        "Project `{}` was built recently, retry in {}s\n",
+                        slug, retry_after
+                    ),
+                )
+                    .into_response();
+            }
+
+            let build_lock = state.build_lock(&slug);
+
+            // in reject mode permits are taken up front so busy limits can be reported,
+            // in queue and coalesce modes the build task waits for them instead
+            let coalesce = config.app.limit_mode == LimitMode::Coalesce;
+            let queue = config.app.limit_mode == LimitMode::Queue || coalesce;
+            let mut project_permit = None;
+            let mut global_permit = None;
+            if !queue {
+                project_permit = match build_lock.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        tracing::warn!("Build already in progress for project `{}`", slug);
+                        return (
+                            StatusCode::CONFLICT,
+                            format!("Build already in progress for project `{}`\n", slug),
+                        )
+                            .into_response();
+                    }
+                };
+                if let Some(global) = &state.global_builds {
+                    global_permit = match Arc::clone(global).try_acquire_owned() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => {
+                            tracing::warn!(
+                                "Global build limit reached, rejecting build for project `{}`",
+                                slug
+                            );
+                            return (
+                                StatusCode::SERVICE_UNAVAILABLE,
+                                "Global build limit reached, try again later\n",
+                            )
+                                .into_response();
+                        }
+                    };
+                }
+            }
 
-            let registry = &state.config.app.registry;
-            let github_token = &state.github_token;
             let project = project.clone();
-            let registry = registry.clone();
-            let github_token = github_token.clone();
+            let health_project = project.clone();
+            let health = tokio::task::spawn_blocking(move || health_project.check_deployments())
+                .await
+                .unwrap_or_else(|e| Err(format!("Health check task failed: {}", e)));
+            if let Err(e) = health {
+                tracing::warn!("Refusing to build project `{}`: {}", slug, e);
+                return (
+                    StatusCode::PRECONDITION_FAILED,
+                    format!("Refusing to build project `{}`: {}\n", slug, e),
+                )
+                    .into_response();
+            }
+
+            let app = config.app.clone();
             let slug = project.slug().to_string();
             let slug_for_log = slug.clone();
-
-            tokio::task::spawn_blocking(move || {
-                let _permit = permit;
-                if let Err(e) = project.build(&registry, &github_token) {
-                    tracing::error!("Build failed for project `{}`: {}", slug, e);
+            let queue_limit = config.app.max_queue_depth.filter(|_| queue);
+            let enqueue = || {
+                let enqueued = state.enqueue(&slug, request, coalesce, queue_limit);
+                let build_id = match &enqueued {
+                    Enqueued::Queued(build_id, _) | Enqueued::Coalesced(build_id) => {
+                        Some(*build_id)
+                    }
+                    Enqueued::Full(_) => None,
+                };
+                (enqueued, build_id)
+            };
+            let enqueued = match &idempotency_key {
+                Some(key) => match state.idempotency.start(&slug, key, enqueue) {
+                    Ok(enqueued) => enqueued,
+                    Err(build_id) => {
+                        return existing_build(&state, &config.app.base_path, &slug, build_id);
+                    }
+                },
+                None => enqueue().0,
+            };
+            let (build_id, request) = match enqueued {
+                Enqueued::Queued(build_id, request) => (build_id, request),
+                Enqueued::Coalesced(build_id) => {
+                    if let Some(delivery) = &delivery {
+                        state.deliveries.record(&slug, delivery);
+                    }
+                    tracing::info!(
+                        "Coalesced trigger into pending build {} for project `{}`",
+                        build_id,
+                        slug
+                    );
+                    return BuildHookResponse {
+                        status_path: format!("{}/status/{}", config.app.base_path, slug),
+                        build_id,
+                        coalesced: true,
+                        json: accepts_json(&headers),
+                    }
+                    .into_response();
                 }
-            });
+                Enqueued::Full(depth) => {
+                    tracing::warn!(
+                        "Build queue full for project `{}` ({} waiting), rejecting build",
+                        slug,
+                        depth
+                    );
+                    return (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        format!("Build queue full for project `{}`, try again later\n", slug),
+                    )
+                        .into_response();
+                }
+            };
+            // only accepted builds are remembered, so retries of rejected deliveries still build
+            if let Some(delivery) = &delivery {
+                state.deliveries.record(&slug, delivery);
+            }
+            let status_path = format!("{}/status/{}", config.app.base_path, slug);
+            spawn_build(
+                Arc::clone(&state),
+                app,
+                project,
+                build_id,
+                request,
+                project_permit,
+                global_permit,
+            );
 
-            tracing::info!("Build started for project `{}`", slug_for_log);
-            BuildHookResponse.into_response()
+            if queue {
+                tracing::info!("Build queued for project `{}`", slug_for_log);
+            } else {
+                tracing::info!("Build started for project `{}`", slug_for_log);
+            }
+            BuildHookResponse {
+                status_path,
+                build_id,
+                coalesced: false,
+                json: accepts_json(&headers),
+            }
+            .into_response()
         }
 
         None => {
@@ -132,3 +928,685 @@ async fn handler(Path(slug): Path<String>, State(state): State<Arc<AppState>>) -
         }
     }
 }
+
+/// Run an enqueued build in the background once its project and global permits are free.
+/// Permits taken up front, as in reject mode, are used as given.
+fn spawn_build(
+    state: Arc<AppState>,
+    app: AppConfig,
+    project: ProjectConfig,
+    build_id: u64,
+    request: SharedRequest,
+    project_permit: Option<OwnedSemaphorePermit>,
+    global_permit: Option<OwnedSemaphorePermit>,
+) {
+    let slug = project.slug().to_string();
+    let build_lock = state.build_lock(&slug);
+    let events = state.events.publisher(&slug);
+    let github_token = state.github_token.clone();
+    // a child of the triggering request's span, so the build's logs and timing trace back to it
+    let span = tracing::info_span!("build", project = %slug, build_id);
+
+    let build = async move {
+        // semaphores are never closed, so acquiring only fails if the server is going away
+        let _project_permit = match project_permit {
+            Some(permit) => Some(permit),
+            None => build_lock.acquire_owned().await.ok(),
+        };
+        let _global_permit = match (global_permit, &state.global_builds) {
+            (Some(permit), _) => Some(permit),
+            (None, Some(global)) => Arc::clone(global).acquire_owned().await.ok(),
+            (None, None) => None,
+        };
+
+        // later triggers may have updated a pending build until now
+        let mut request = state.start_pending(&slug, build_id, &request);
+        // read once the previous build finished, since this build waited for it
+        request.previous_commit = state.status.last_built_commit(&slug);
+        state.status.running(build_id);
+        events.publish(BuildEvent::BuildStarted { build_id });
+        let cancel = state.running.register(&slug);
+        let build_events = events.clone();
+        let build_cancel = Arc::clone(&cancel);
+        let build_span = tracing::Span::current();
+        let clone_limit = state.clone_limit.clone();
+        let report_dir = app.report_dir.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            build_span.in_scope(|| {
+                project.build(
+                    &app,
+                    &github_token,
+                    build_id,
+                    &request,
+                    clone_limit.as_deref(),
+                    &build_events,
+                    &build_cancel,
+                )
+            })
+        })
+        .await
+        .unwrap_or_else(|e| Err(build_task_error(e)));
+        state.running.remove(&slug, &cancel);
+        if let Err(e) = &result {
+            tracing::error!("Build failed for project `{}`: {}", slug, e);
+        }
+        let record = state.status.finish(build_id, &result);
+        // reports are best-effort, like the status file, a failed write doesn't fail the build
+        if let (Some(dir), Some(record)) = (&report_dir, &record) {
+            match status::write_report(dir, record) {
+                Ok(path) => tracing::info!("Wrote build report to `{}`", path.display()),
+                Err(e) => tracing::warn!("{}", e),
+            }
+        }
+        events.publish(BuildEvent::BuildFinished {
+            build_id,
+            success: result.is_ok(),
+            error: result.as_ref().err().cloned(),
+        });
+    };
+    tokio::spawn(build.instrument(span));
+}
+
+/// A build queued by `POST /build-all`.
+#[derive(Serialize)]
+struct QueuedBuild {
+    project: String,
+    build_id: u64,
+}
+
+/// Queue a build of every project, e.g. after a base image update. Builds wait for their
+/// project's and the global permits, whatever the limit mode.
+async fn build_all(State(state): State<Arc<AppState>>) -> Response {
+    if !auth::has_full_access() {
+        return full_access_required();
+    }
+
+    let config = state.config();
+    let coalesce = config.app.limit_mode == LimitMode::Coalesce;
+    let mut slugs: Vec<&String> = config.projects.keys().collect();
+    slugs.sort();
+
+    let mut queued = Vec::new();
+    for slug in slugs {
+        if let Some(build_id) = queue_build(&state, &config, slug, coalesce).await {
+            queued.push(QueuedBuild {
+                project: slug.clone(),
+                build_id,
+            });
+        }
+    }
+
+    tracing::info!("Queued builds of {} project(s)", queued.len());
+    (StatusCode::ACCEPTED, Json(queued)).into_response()
+}
+
+/// Queue a build of `slug` that waits for its project's and the global permits, returning its id.
+///
+/// Projects failing `require_healthy` or with a full queue are skipped.
+async fn queue_build(
+    state: &Arc<AppState>,
+    config: &config::HookConfig,
+    slug: &str,
+    coalesce: bool,
+) -> Option<u64> {
+    let project = config.projects[slug].clone();
+    let health_project = project.clone();
+    let health = tokio::task::spawn_blocking(move || health_project.check_deployments())
+        .await
+        .unwrap_or_else(|e| Err(format!("Health check task failed: {}", e)));
+    if let Err(e) = health {
+        tracing::warn!("Refusing to build project `{}`: {}", slug, e);
+        return None;
+    }
+
+    let request = BuildRequest::default();
+    match state.enqueue(slug, request, coalesce, config.app.max_queue_depth) {
+        Enqueued::Queued(build_id, request) => {
+            spawn_build(
+                Arc::clone(state),
+                config.app.clone(),
+                project,
+                build_id,
+                request,
+                None,
+                None,
+            );
+            Some(build_id)
+        }
+        Enqueued::Coalesced(build_id) => Some(build_id),
+        Enqueued::Full(depth) => {
+            tracing::warn!(
+                "Build queue full for project `{}` ({} waiting), skipping it",
+                slug,
+                depth
+            );
+            None
+        }
+    }
+}
+
+/// Build projects with a `schedule` whenever it fires, checking once a minute. Schedules are read
+/// from the active config on every check, so reloads take effect right away.
+async fn run_schedules(state: Arc<AppState>) {
+    let now = unix_now();
+    let config = state.config();
+    let mut slugs: Vec<&String> = config.projects.keys().collect();
+    slugs.sort();
+    for slug in slugs {
+        if let Some(schedule) = config.projects[slug].schedule() {
+            log_next_scheduled(slug, &schedule, now);
+        }
+    }
+
+    let mut last_minute = now / 60;
+    loop {
+        let now = unix_now();
+        tokio::time::sleep(Duration::from_secs(60 - now % 60)).await;
+        // a clock adjusted backwards could otherwise fire the same minute twice
+        let now = unix_now();
+        if now / 60 <= last_minute {
+            continue;
+        }
+        last_minute = now / 60;
+
+        let config = state.config();
+        let coalesce = config.app.limit_mode == LimitMode::Coalesce;
+        let mut slugs: Vec<&String> = config.projects.keys().collect();
+        slugs.sort();
+        for slug in slugs {
+            let Some(schedule) = config.projects[slug].schedule() else {
+                continue;
+            };
+            if !schedule.matches(now) {
+                continue;
+            }
+            if let Some(build_id) = queue_build(&state, &config, slug, coalesce).await {
+                tracing::info!("Scheduled build {} of project `{}`", build_id, slug);
+            }
+            log_next_scheduled(slug, &schedule, now);
+        }
+    }
+}
+
+fn log_next_scheduled(slug: &str, schedule: &Schedule, now: u64) {
+    match schedule.next_after(now) {
+        Some(next) => tracing::info!(
+            "Next scheduled build of project `{}` at {}",
+            slug,
+            schedule::format_timestamp(next)
+        ),
+        None => tracing::warn!("Schedule of project `{}` never fires", slug),
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Answer a request repeating an idempotency key with the status of the build it started.
+fn existing_build(state: &AppState, base_path: &str, slug: &str, build_id: u64) -> Response {
+    tracing::info!(
+        "Idempotency key already started build {} for project `{}`",
+        build_id,
+        slug
+    );
+    let location = [(header::LOCATION, format!("{}/status/{}", base_path, slug))];
+    match state.status.get(build_id) {
+        Some(record) => (StatusCode::OK, location, Json(record)).into_response(),
+        // the record may have aged out of the history
+        None => (
+            StatusCode::OK,
+            location,
+            format!("Build {} already started for this key\n", build_id),
+        )
+            .into_response(),
+    }
+}
+
+async fn cancel_build(Path(slug): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    if !state.config().projects.contains_key(&slug) || !auth::can_access(&slug) {
+        return project_not_found(&slug);
+    }
+
+    // terminating the process blocks briefly on `kill`
+    let cancel_state = Arc::clone(&state);
+    let cancel_slug = slug.clone();
+    let cancelled = tokio::task::spawn_blocking(move || cancel_state.running.cancel(&cancel_slug))
+        .await
+        .unwrap_or(false);
+    if !cancelled {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("No build running for project `{}`\n", slug),
+        )
+            .into_response();
+    }
+
+    tracing::info!("Cancelling build for project `{}`", slug);
+    (
+        StatusCode::OK,
+        format!("Cancelling build for project `{}`\n", slug),
+    )
+        .into_response()
+}
+
+/// Describe a build task that didn't return, including the panic message if it panicked.
+fn build_task_error(error: tokio::task::JoinError) -> String {
+    if !error.is_panic() {
+        return format!("Build task failed: {}", error);
+    }
+
+    let panic = error.into_panic();
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string());
+    format!("Build panicked: {}", message)
+}
+
+/// Prometheus metrics for every configured project.
+async fn metrics(State(state): State<Arc<AppState>>) -> Response {
+    let config = state.config();
+    let mut slugs: Vec<&String> = config.projects.keys().collect();
+    slugs.sort();
+
+    let instance_label = match &state.instance_name {
+        Some(name) => format!("instance=\"{}\",", label_value(name)),
+        None => String::new(),
+    };
+    let mut body = String::from(
+        "# HELP build_hook_queue_depth Builds waiting to start, per project.\n\
+         # TYPE build_hook_queue_depth gauge\n",
+    );
+    for slug in slugs.into_iter().filter(|slug| auth::can_access(slug)) {
+        body.push_str(&format!(
+            "build_hook_queue_depth{{{}project=\"{}\"}} {}\n",
+            instance_label,
+            label_value(slug),
+            state.status.queue_depth(slug)
+        ));
+    }
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+/// Escape a Prometheus label value, e.g. a slug holding a quote would otherwise end the label.
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn diagnostics(State(state): State<Arc<AppState>>) -> Response {
+    let config = state.config();
+    let diagnostics = match tokio::task::spawn_blocking(move || diag::collect(&config.app)).await {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => {
+            tracing::error!("Diagnostics task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // unhealthy diagnostics answer 503 so monitors can alert on the status code alone
+    let status = if diagnostics.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(diagnostics)).into_response()
+}
+
+/// Latest build of a project, with the number of its builds waiting to start.
+#[derive(Serialize)]
+struct ProjectStatus {
+    #[serde(flatten)]
+    record: BuildRecord,
+    queue_depth: usize,
+}
+
+impl ProjectStatus {
+    fn new(status: &BuildStatus, record: BuildRecord) -> Self {
+        let queue_depth = status.queue_depth(&record.project);
+        ProjectStatus {
+            record,
+            queue_depth,
+        }
+    }
+}
+
+async fn status_all(State(state): State<Arc<AppState>>) -> Json<HashMap<String, ProjectStatus>> {
+    let latest = state.status.latest();
+    Json(
+        latest
+            .into_iter()
+            .filter(|(slug, _)| auth::can_access(slug))
+            .map(|(slug, record)| (slug, ProjectStatus::new(&state.status, record)))
+            .collect(),
+    )
+}
+
+async fn status_project(Path(slug): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    if !state.config().projects.contains_key(&slug) || !auth::can_access(&slug) {
+        return project_not_found(&slug);
+    }
+
+    match state.status.latest_for(&slug) {
+        Some(record) => Json(ProjectStatus::new(&state.status, record)).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("No builds recorded for project `{}`\n", slug),
+        )
+            .into_response(),
+    }
+}
+
+async fn history_all(State(state): State<Arc<AppState>>) -> Json<Vec<BuildRecord>> {
+    let mut history = state.status.history(None);
+    history.retain(|record| auth::can_access(&record.project));
+    Json(history)
+}
+
+async fn history_project(Path(slug): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    if !state.config().projects.contains_key(&slug) || !auth::can_access(&slug) {
+        return project_not_found(&slug);
+    }
+
+    Json(state.status.history(Some(&slug))).into_response()
+}
+
+async fn events_socket(
+    Path(slug): Path<String>,
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if !state.config().projects.contains_key(&slug) || !auth::can_access(&slug) {
+        return project_not_found(&slug);
+    }
+
+    let events = state.events.subscribe(&slug);
+    ws.on_upgrade(move |socket| stream_events(socket, slug, events))
+}
+
+/// Send build events to a WebSocket client as JSON text messages until either side closes.
+async fn stream_events(
+    mut socket: WebSocket,
+    slug: String,
+    mut events: broadcast::Receiver<BuildEvent>,
+) {
+    tracing::debug!("Streaming build events for project `{}`", slug);
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!(
+                            "Event subscriber for project `{}` fell behind, dropped {} event(s)",
+                            slug,
+                            missed
+                        );
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Ok(text) = serde_json::to_string(&event) else {
+                    continue;
+                };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => {
+                // clients only listen, so anything but a close is ignored
+                if matches!(message, None | Some(Err(_)) | Some(Ok(Message::Close(_)))) {
+                    break;
+                }
+            }
+        }
+    }
+    tracing::debug!("Stopped streaming build events for project `{}`", slug);
+}
+
+fn project_not_found(slug: &str) -> Response {
+    (
+        StatusCode::NOT_FOUND,
+        format!("No configuration found for project `{}`\n", slug),
+    )
+        .into_response()
+}
+
+/// Rejection of a project-scoped token on an endpoint that manages the whole service.
+fn full_access_required() -> Response {
+    tracing::warn!("Rejected project-scoped token on a service endpoint");
+    (
+        StatusCode::FORBIDDEN,
+        "Forbidden: This endpoint requires a token from BEARER_TOKENS\n",
+    )
+        .into_response()
+}
+
+async fn set_log_level(State(state): State<Arc<AppState>>, directive: String) -> Response {
+    if !auth::has_full_access() {
+        return full_access_required();
+    }
+
+    let directive = directive.trim();
+    match logging::set_filter(&state.log_handle, directive) {
+        Ok(()) => {
+            tracing::info!("Log filter changed to `{}`", directive);
+            (
+                StatusCode::OK,
+                format!("Log filter set to `{}`\n", directive),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::warn!("{}", e);
+            (StatusCode::BAD_REQUEST, format!("{}\n", e)).into_response()
+        }
+    }
+}
+
+/// Outcome of validating a proposed config.
+#[derive(Serialize)]
+struct ValidationReport {
+    valid: bool,
+    errors: Vec<config::ValidationError>,
+}
+
+/// Validate the config file in the body against the running version's rules, without applying
+/// it.
+async fn validate_config(body: String) -> Response {
+    if !auth::has_full_access() {
+        return full_access_required();
+    }
+
+    // validation reads referenced files, e.g. `code.allowed_signers`
+    let errors = match tokio::task::spawn_blocking(move || config::check(&body)).await {
+        Ok(errors) => errors,
+        Err(e) => {
+            tracing::error!("Config validation task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    Json(ValidationReport {
+        valid: errors.is_empty(),
+        errors,
+    })
+    .into_response()
+}
+
+/// The active configuration, with defaults applied and secrets redacted.
+async fn show_config(State(state): State<Arc<AppState>>) -> Response {
+    if !auth::has_full_access() {
+        return full_access_required();
+    }
+
+    let config = state.config();
+    Json(config.as_ref()).into_response()
+}
+
+async fn reload_config(State(state): State<Arc<AppState>>) -> Response {
+    if !auth::has_full_access() {
+        return full_access_required();
+    }
+
+    tracing::info!("Reloading configuration...");
+
+    let config = match tokio::task::spawn_blocking(config::load).await {
+        Ok(Ok(config)) => config,
+        Ok(Err(e)) => {
+            tracing::error!("Could not reload config, keeping current config: {}", e);
+            return (
+                StatusCode::BAD_REQUEST,
+                format!("Could not reload config: {}\n", e),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            tracing::error!("Config reload task failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut current = state.config.write().unwrap();
+    let diff = config::ConfigDiff::between(&current, &config);
+    *current = Arc::new(config);
+    drop(current);
+
+    tracing::info!(
+        "Configuration reloaded: added {:?}, removed {:?}, changed {:?}",
+        diff.added,
+        diff.removed,
+        diff.changed
+    );
+    Json(diff).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::ConnectInfo;
+    use tower::ServiceExt;
+    use tracing_subscriber::{EnvFilter, reload};
+
+    const CONFIG: &str = r#"
+[app]
+registry = "registry.example.com"
+token_scopes = [{ name = "team-api", token_env = "API_TEST_SCOPED_TOKEN", projects = ["api"] }]
+
+[[projects]]
+name = "API"
+slug = "api"
+code = { url = "https://github.com/example/api" }
+image = [{ repository = "api", location = ".", tag = "latest" }]
+deployments = { namespace = "default", resources = ["deployment/api"] }
+
+[[projects]]
+name = "Web"
+slug = "web"
+code = { url = "https://github.com/example/web" }
+image = [{ repository = "web", location = ".", tag = "latest" }]
+deployments = { namespace = "default", resources = ["deployment/web"] }
+"#;
+
+    fn test_routes() -> Router {
+        // SAFETY: only these router tests read the variables, and they all set the same values
+        unsafe {
+            std::env::set_var("BEARER_TOKENS", "full-token");
+            std::env::set_var("API_TEST_SCOPED_TOKEN", "scoped-token");
+        }
+        let config = config::parse(CONFIG).unwrap();
+        let (_, log_handle) = reload::Layer::new(EnvFilter::new("info"));
+        let status = BuildStatus::load(None).unwrap();
+        routes(new_state(config, status, String::new(), log_handle))
+    }
+
+    async fn send(method: &str, uri: &str, token: Option<&str>) -> (StatusCode, String) {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let response = test_routes().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), BODY_LIMIT)
+            .await
+            .unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[tokio::test]
+    async fn scoped_token_only_reaches_its_projects() {
+        // the project is visible, it just hasn't built yet
+        assert_eq!(
+            send("GET", "/status/api", Some("scoped-token")).await,
+            (
+                StatusCode::NOT_FOUND,
+                "No builds recorded for project `api`\n".to_string()
+            )
+        );
+        assert_eq!(
+            send("GET", "/history/api", Some("scoped-token")).await,
+            (StatusCode::OK, "[]".to_string())
+        );
+        // other projects look like they don't exist
+        assert_eq!(
+            send("GET", "/status/web", Some("scoped-token")).await,
+            (
+                StatusCode::NOT_FOUND,
+                "No configuration found for project `web`\n".to_string()
+            )
+        );
+        assert_eq!(
+            send("POST", "/reload", Some("scoped-token")).await.0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn full_token_reaches_every_project() {
+        assert_eq!(
+            send("GET", "/status/web", Some("full-token")).await,
+            (
+                StatusCode::NOT_FOUND,
+                "No builds recorded for project `web`\n".to_string()
+            )
+        );
+        assert_eq!(
+            send("GET", "/history/web", Some("full-token")).await.0,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn protected_routes_require_a_known_token() {
+        assert_eq!(
+            send("GET", "/status/api", None).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            send("GET", "/status/api", Some("other-token")).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(send("GET", "/health", None).await.0, StatusCode::OK);
+        assert_eq!(
+            send("GET", "/health?deep=true", None).await.0,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(label_value("prod-eu"), "prod-eu");
+        assert_eq!(label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -1,13 +1,40 @@
+use std::sync::Arc;
+
 use axum::{
-    extract::Request,
+    extract::{Request, State},
     http::{StatusCode, header},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use serde::{Deserialize, Serialize};
 use tokio::task_local;
 
+use crate::api::AppState;
+
+/// A bearer token limited to some projects, e.g. for one team on a shared instance.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TokenScope {
+    /// Name of the scope, used in logs, e.g. "team-payments".
+    pub name: String,
+    /// Environment variable holding the token, so it isn't written to the config file.
+    pub token_env: String,
+    /// Slugs of the projects the token can see and trigger.
+    pub projects: Vec<String>,
+}
+
 #[derive(Clone)]
-pub struct CurrentUser;
+pub struct CurrentUser {
+    /// Projects the token is limited to, or `None` for a `BEARER_TOKENS` token with full access.
+    projects: Option<Vec<String>>,
+}
+
+impl CurrentUser {
+    fn can_access(&self, slug: &str) -> bool {
+        self.projects
+            .as_ref()
+            .is_none_or(|projects| projects.iter().any(|project| project == slug))
+    }
+}
 
 task_local! {
     pub static USER: CurrentUser;
@@ -23,19 +50,50 @@ fn parse_bearer(header_value: &str) -> Option<&str> {
     }
 }
 
-async fn authorize_bearer(token: &str) -> Option<CurrentUser> {
-    let bearer_tokens = load_bearer_tokens_from_env();
+/// The user holding `token`: full access for one of `bearer_tokens`, or the projects of the first
+/// scope whose `token_env` holds it.
+fn authorize_bearer(
+    token: &str,
+    bearer_tokens: &[String],
+    scopes: &[TokenScope],
+) -> Option<CurrentUser> {
+    if bearer_tokens
+        .iter()
+        .any(|bearer_token| bearer_token == token)
+    {
+        tracing::info!("Valid bearer token");
+        return Some(CurrentUser { projects: None });
+    }
 
-    if !bearer_tokens.contains(&token.to_string()) {
-        tracing::warn!("Invalid bearer token");
-        return None;
+    let scope = scopes.iter().find(|scope| {
+        std::env::var(&scope.token_env).is_ok_and(|scope_token| scope_token.trim() == token)
+    });
+    match scope {
+        Some(scope) => {
+            tracing::info!("Valid bearer token for scope `{}`", scope.name);
+            Some(CurrentUser {
+                projects: Some(scope.projects.clone()),
+            })
+        }
+        None => {
+            tracing::warn!("Invalid bearer token");
+            None
+        }
     }
+}
+
+/// Whether the authenticated token can see and trigger the project `slug`.
+pub fn can_access(slug: &str) -> bool {
+    USER.try_with(|user| user.can_access(slug)).unwrap_or(false)
+}
 
-    tracing::info!("Valid bearer token");
-    Some(CurrentUser)
+/// Whether the authenticated token has full access, as required to manage the service itself.
+pub fn has_full_access() -> bool {
+    USER.try_with(|user| user.projects.is_none())
+        .unwrap_or(false)
 }
 
-pub async fn auth_layer(req: Request, next: Next) -> Response {
+pub async fn auth_layer(State(state): State<Arc<AppState>>, req: Request, next: Next) -> Response {
     tracing::info!("Authenticating request...");
 
     let header_value = match req
@@ -66,7 +124,8 @@ pub async fn auth_layer(req: Request, next: Next) -> Response {
         }
     };
 
-    let user = match authorize_bearer(token).await {
+    let scopes = state.config().app.token_scopes.clone();
+    let user = match authorize_bearer(token, &load_bearer_tokens_from_env(), &scopes) {
         Some(user) => user,
         None => {
             return (
@@ -93,3 +152,90 @@ pub fn load_bearer_tokens_from_env() -> Vec<String> {
         .map(|s| s.trim().to_string())
         .collect::<Vec<String>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(name: &str, token_env: &str, projects: &[&str]) -> TokenScope {
+        TokenScope {
+            name: name.to_string(),
+            token_env: token_env.to_string(),
+            projects: projects.iter().map(|project| project.to_string()).collect(),
+        }
+    }
+
+    /// Set a token variable only this test reads, so tests running in parallel don't race.
+    fn set_token(name: &str, token: &str) {
+        // SAFETY: every test uses its own variable names and nothing else reads them
+        unsafe { std::env::set_var(name, token) };
+    }
+
+    #[test]
+    fn bearer_token_has_full_access() {
+        let bearer_tokens = ["first".to_string(), "second".to_string()];
+
+        let user = authorize_bearer("second", &bearer_tokens, &[]).unwrap();
+
+        assert!(user.can_access("api"));
+        assert!(user.can_access("web"));
+        assert!(USER.sync_scope(user, has_full_access));
+    }
+
+    #[test]
+    fn scoped_token_sees_only_its_projects() {
+        set_token("AUTH_TEST_SCOPED_PAYMENTS", " payments-token\n");
+        set_token("AUTH_TEST_SCOPED_SEARCH", "search-token");
+        let scopes = [
+            scope(
+                "team-payments",
+                "AUTH_TEST_SCOPED_PAYMENTS",
+                &["api", "worker"],
+            ),
+            scope("team-search", "AUTH_TEST_SCOPED_SEARCH", &["web"]),
+        ];
+
+        // surrounding whitespace of a token variable, e.g. from a mounted secret, is ignored
+        let user = authorize_bearer("payments-token", &["full".to_string()], &scopes).unwrap();
+
+        assert!(user.can_access("api"));
+        assert!(user.can_access("worker"));
+        assert!(!user.can_access("web"));
+        assert!(USER.sync_scope(user.clone(), || can_access("api")));
+        assert!(!USER.sync_scope(user.clone(), || can_access("web")));
+        assert!(!USER.sync_scope(user, has_full_access));
+    }
+
+    #[test]
+    fn unknown_token_is_rejected() {
+        set_token("AUTH_TEST_UNKNOWN_SCOPE", "scoped-token");
+        let scopes = [scope("team", "AUTH_TEST_UNKNOWN_SCOPE", &["api"])];
+
+        assert!(authorize_bearer("other-token", &["full".to_string()], &scopes).is_none());
+        // a prefix of a valid token isn't the token
+        assert!(authorize_bearer("ful", &["full".to_string()], &scopes).is_none());
+    }
+
+    #[test]
+    fn scope_with_unset_token_env_matches_nothing() {
+        let scopes = [scope("team", "AUTH_TEST_UNSET_SCOPE", &["api"])];
+
+        assert!(authorize_bearer("", &[], &scopes).is_none());
+        assert!(authorize_bearer("token", &[], &scopes).is_none());
+    }
+
+    #[test]
+    fn access_is_denied_outside_a_request() {
+        assert!(!can_access("api"));
+        assert!(!has_full_access());
+    }
+
+    #[test]
+    fn parses_bearer_header() {
+        assert_eq!(parse_bearer("Bearer abc"), Some("abc"));
+        assert_eq!(parse_bearer("bearer abc"), Some("abc"));
+        assert_eq!(parse_bearer("Bearer "), None);
+        assert_eq!(parse_bearer("Basic abc"), None);
+        assert_eq!(parse_bearer("abc"), None);
+    }
+}
@@ -1,42 +1,360 @@
-use std::process::{Command, Output};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
 
-const BUILDER_NAME: &str = "builder";
-// BuildKit daemon endpoint (deployed separately via k8s.yaml)
-const BUILDKITD_ENDPOINT: &str = "tcp://buildkitd.build.svc.cluster.local:1234";
+use serde::{Deserialize, Serialize};
 
-pub fn initialize() -> Result<(), String> {
-    tracing::info!(
-        "Initializing buildx builder: {} with remote endpoint: {}",
-        BUILDER_NAME,
-        BUILDKITD_ENDPOINT
-    );
+use crate::command::CommandRunner;
+
+const DEFAULT_BUILDER_NAME: &str = "builder";
+/// Docker CLI config directory, holding the registry credentials buildx pushes with.
+const DOCKER_CONFIG_DIR: &str = "/root/.docker";
+/// Namespace the BuildKit daemon is deployed to (separately via k8s.yaml) when not configured.
+pub const DEFAULT_BUILDER_NAMESPACE: &str = "build";
+/// Port the BuildKit daemon's service listens on.
+const BUILDKITD_PORT: u16 = 1234;
+/// How often a bootstrapping builder is checked for completion.
+const BOOTSTRAP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often a bootstrapped builder is inspected while waiting for its nodes to run.
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Driver buildx runs a builder's BuildKit daemon with.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuilderDriver {
+    /// Connect to an already running BuildKit daemon at the builder's endpoint.
+    #[default]
+    Remote,
+    /// Run BuildKit in a container on the local Docker daemon, for use outside a cluster.
+    DockerContainer,
+    /// Run BuildKit in pods that buildx deploys to the cluster.
+    Kubernetes,
+}
+
+impl BuilderDriver {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BuilderDriver::Remote => "remote",
+            BuilderDriver::DockerContainer => "docker-container",
+            BuilderDriver::Kubernetes => "kubernetes",
+        }
+    }
+}
+
+/// Driver options only the kubernetes driver understands. Options ending in '.' are prefixes.
+pub const KUBERNETES_ONLY_OPTS: &[&str] = &[
+    "namespace",
+    "replicas",
+    "requests.",
+    "limits.",
+    "nodeselector",
+    "annotations",
+    "labels",
+    "tolerations",
+    "serviceaccount",
+    "schedulername",
+    "rootless",
+    "loadbalance",
+    "timeout",
+    "qemu.",
+];
+
+/// Whether `opt` is a driver option only the kubernetes driver understands.
+pub fn is_kubernetes_only_opt(opt: &str) -> bool {
+    KUBERNETES_ONLY_OPTS
+        .iter()
+        .any(|known| match known.strip_suffix('.') {
+            Some(prefix) => opt.starts_with(prefix) && opt[prefix.len()..].starts_with('.'),
+            None => opt == *known,
+        })
+}
+
+/// Periodic pruning of the builders' BuildKit cache.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PruneConfig {
+    /// How often (in seconds) the cache is pruned. Defaults to once a day.
+    #[serde(default = "default_prune_interval_secs")]
+    pub interval_secs: u64,
+    /// Only prune cache records unused for longer than this, e.g. "72h". Defaults to 72 hours.
+    #[serde(default = "default_prune_until")]
+    pub until: String,
+    /// Amount of cache to keep regardless of age, e.g. "20gb".
+    pub keep_storage: Option<String>,
+}
+
+fn default_prune_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_prune_until() -> String {
+    "72h".to_string()
+}
+
+/// Kubernetes secret of type `kubernetes.io/dockerconfigjson` holding the registry credentials.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RegistrySecretConfig {
+    /// Name of the secret.
+    pub name: String,
+    /// Namespace of the secret. Defaults to the service account's namespace.
+    pub namespace: Option<String>,
+    /// How often (in seconds) the secret is read again, so rotated credentials are picked up.
+    /// Defaults to 5 minutes.
+    #[serde(default = "default_registry_secret_refresh_secs")]
+    pub refresh_secs: u64,
+}
+
+fn default_registry_secret_refresh_secs() -> u64 {
+    300
+}
+
+/// A buildx builder, by default connected to a BuildKit daemon with the remote driver.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BuilderConfig {
+    /// Builder name, referenced by `image.builder`.
+    pub name: String,
+    /// Driver that runs the builder's BuildKit daemon.
+    #[serde(default)]
+    pub driver: BuilderDriver,
+    /// BuildKit daemon endpoint, e.g. "tcp://buildkitd-arm64.build.svc.cluster.local:1234".
+    /// Required by the remote driver, and only used by it.
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Options passed to the driver with `--driver-opt`, e.g. `image = "moby/buildkit:latest"`.
+    #[serde(default)]
+    pub driver_opts: HashMap<String, String>,
+    /// Node labels the kubernetes driver's BuildKit pods must match, e.g. a build node pool.
+    #[serde(default)]
+    pub node_selector: BTreeMap<String, String>,
+    /// Taints the kubernetes driver's BuildKit pods tolerate.
+    #[serde(default)]
+    pub tolerations: Vec<Toleration>,
+    /// Annotations set on the kubernetes driver's BuildKit pods.
+    #[serde(default)]
+    pub annotations: BTreeMap<String, String>,
+    /// Labels set on the kubernetes driver's BuildKit pods.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Platforms the builder is pinned to, e.g. ["linux/arm64"].
+    /// When empty, the platforms are detected from the daemon.
+    #[serde(default)]
+    pub platforms: Vec<String>,
+}
+
+/// A Kubernetes toleration for the kubernetes driver's BuildKit pods.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Toleration {
+    pub key: Option<String>,
+    /// "Equal" or "Exists".
+    pub operator: Option<String>,
+    pub value: Option<String>,
+    /// "NoSchedule", "PreferNoSchedule", or "NoExecute".
+    pub effect: Option<String>,
+}
+
+impl Toleration {
+    /// Toleration in the format of the kubernetes driver, e.g. "key=pool,value=build,effect=NoSchedule".
+    fn driver_opt(&self) -> String {
+        [
+            ("key", &self.key),
+            ("operator", &self.operator),
+            ("value", &self.value),
+            ("effect", &self.effect),
+        ]
+        .iter()
+        .filter_map(|(field, value)| value.as_ref().map(|value| format!("{}={}", field, value)))
+        .collect::<Vec<_>>()
+        .join(",")
+    }
+}
+
+impl BuilderConfig {
+    /// Whether any pod scheduling or metadata option is set, which only the kubernetes driver uses.
+    pub fn has_pod_options(&self) -> bool {
+        !self.node_selector.is_empty()
+            || !self.tolerations.is_empty()
+            || !self.annotations.is_empty()
+            || !self.labels.is_empty()
+    }
 
+    /// Driver options passed to `docker buildx create`, including the pod options, sorted so the
+    /// command is the same on every start.
+    fn all_driver_opts(&self) -> Vec<(String, String)> {
+        let mut opts: Vec<_> = self
+            .driver_opts
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        let join = |map: &BTreeMap<String, String>| {
+            map.iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        if !self.node_selector.is_empty() {
+            opts.push(("nodeselector".to_string(), join(&self.node_selector)));
+        }
+        if !self.tolerations.is_empty() {
+            let tolerations: Vec<_> = self
+                .tolerations
+                .iter()
+                .map(Toleration::driver_opt)
+                .collect();
+            opts.push(("tolerations".to_string(), tolerations.join(";")));
+        }
+        if !self.annotations.is_empty() {
+            opts.push(("annotations".to_string(), join(&self.annotations)));
+        }
+        if !self.labels.is_empty() {
+            opts.push(("labels".to_string(), join(&self.labels)));
+        }
+
+        opts.sort();
+        opts
+    }
+
+    /// Default builder, connected to the `buildkitd` service in `namespace`.
+    fn in_namespace(namespace: &str) -> Self {
+        BuilderConfig {
+            name: DEFAULT_BUILDER_NAME.to_string(),
+            driver: BuilderDriver::Remote,
+            endpoint: Some(format!(
+                "tcp://buildkitd.{}.svc.cluster.local:{}",
+                namespace, BUILDKITD_PORT
+            )),
+            driver_opts: HashMap::new(),
+            node_selector: BTreeMap::new(),
+            tolerations: Vec::new(),
+            annotations: BTreeMap::new(),
+            labels: BTreeMap::new(),
+            platforms: Vec::new(),
+        }
+    }
+}
+
+/// Builders to manage, falling back to the single default builder in `namespace` when none
+/// are configured. Kubernetes builders deploy to `namespace` unless their driver options say
+/// otherwise.
+pub fn builders(configured: &[BuilderConfig], namespace: &str) -> Vec<BuilderConfig> {
+    if configured.is_empty() {
+        return vec![BuilderConfig::in_namespace(namespace)];
+    }
+
+    configured
+        .iter()
+        .cloned()
+        .map(|mut builder| {
+            if builder.driver == BuilderDriver::Kubernetes {
+                builder
+                    .driver_opts
+                    .entry("namespace".to_string())
+                    .or_insert_with(|| namespace.to_string());
+            }
+            builder
+        })
+        .collect()
+}
+
+/// Why builder initialization failed.
+#[derive(Debug)]
+pub enum InitError {
+    /// A builder wasn't ready within the bootstrap timeout, e.g. its pod couldn't be scheduled.
+    Timeout(String),
+    Failed(String),
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::Timeout(e) | InitError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<String> for InitError {
+    fn from(e: String) -> Self {
+        InitError::Failed(e)
+    }
+}
+
+pub fn initialize(
+    runner: &dyn CommandRunner,
+    builders: &[BuilderConfig],
+    bootstrap_timeout: Duration,
+    ready_timeout: Option<Duration>,
+    registry_secret: Option<&RegistrySecretConfig>,
+) -> Result<(), InitError> {
     // Ensure Docker config directory exists (if not already created by volume mount)
     // Ignore errors as the directory may already exist or be created by volume mounts
-    let _ = std::fs::create_dir_all("/root/.docker");
+    let _ = std::fs::create_dir_all(DOCKER_CONFIG_DIR);
+
+    if let Some(secret) = registry_secret {
+        write_registry_credentials(runner, secret)?;
+    }
+
+    for builder in builders {
+        initialize_builder(runner, builder, bootstrap_timeout)?;
+        if let Some(ready_timeout) = ready_timeout {
+            wait_until_running(runner, &builder.name, ready_timeout)?;
+        }
+    }
+
+    // the first builder is the default for images that don't pick one
+    if let Some(builder) = builders.first() {
+        use_builder(runner, &builder.name)?;
+    }
+
+    Ok(())
+}
+
+fn initialize_builder(
+    runner: &dyn CommandRunner,
+    builder: &BuilderConfig,
+    bootstrap_timeout: Duration,
+) -> Result<(), InitError> {
+    match &builder.endpoint {
+        Some(endpoint) if builder.driver == BuilderDriver::Remote => tracing::info!(
+            "Initializing buildx builder: {} with remote endpoint: {}",
+            builder.name,
+            endpoint
+        ),
+        _ => tracing::info!(
+            "Initializing buildx builder: {} with {} driver",
+            builder.name,
+            builder.driver.as_str()
+        ),
+    }
 
     // Check if builder already exists
-    let builder_exists = check_builder_exists()?;
+    let builder_exists = check_builder_exists(runner, &builder.name)?;
 
     if builder_exists {
         tracing::info!(
             "Builder {} already exists, using existing builder",
-            BUILDER_NAME
+            builder.name
         );
-        use_builder()?;
     } else {
-        tracing::info!("Creating new buildx builder: {}", BUILDER_NAME);
-        create_builder()?;
-        bootstrap_builder()?;
+        tracing::info!("Creating new buildx builder: {}", builder.name);
+        create_builder(runner, builder)?;
+        bootstrap_builder(runner, &builder.name, bootstrap_timeout)?;
     }
 
-    tracing::info!("Buildx builder ready");
+    tracing::info!("Buildx builder {} ready", builder.name);
     Ok(())
 }
 
-fn run_command_output(command: &mut Command, description: &str) -> Result<Output, String> {
-    let output = command
-        .output()
+fn run_command_output(
+    runner: &dyn CommandRunner,
+    command: &mut Command,
+    description: &str,
+) -> Result<Output, String> {
+    let output = runner
+        .output(command)
         .map_err(|e| format!("Failed to run {}: {}", description, e))?;
 
     if !output.status.success() && !output.stderr.is_empty() {
@@ -50,26 +368,96 @@ fn run_command_output(command: &mut Command, description: &str) -> Result<Output
     Ok(output)
 }
 
-fn check_builder_exists() -> Result<bool, String> {
+fn check_builder_exists(runner: &dyn CommandRunner, name: &str) -> Result<bool, String> {
+    // inspecting by name only succeeds for an existing builder
+    let output = runner
+        .output(Command::new("docker").args(["buildx", "inspect", name]))
+        .map_err(|e| format!("Failed to run docker buildx inspect: {}", e))?;
+
+    Ok(output.status.success())
+}
+
+/// Check that a builder exists and its BuildKit daemon is running.
+pub fn check_builder(runner: &dyn CommandRunner, name: &str) -> Result<(), String> {
     let output = run_command_output(
-        Command::new("docker").args(["buildx", "ls"]),
-        "docker buildx ls",
+        runner,
+        Command::new("docker").args(["buildx", "inspect", name]),
+        "docker buildx inspect",
     )?;
 
     if !output.status.success() {
         return Err(format!(
-            "Failed to list builders: {}",
-            String::from_utf8_lossy(&output.stderr)
+            "Failed to inspect builder: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
         ));
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    Ok(stdout.contains(BUILDER_NAME))
+    let status = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Status:"))
+        .map(str::trim)
+        .unwrap_or("unknown");
+    if status != "running" {
+        return Err(format!("Builder status is `{}`", status));
+    }
+
+    Ok(())
 }
 
-fn use_builder() -> Result<(), String> {
+/// Wait until every node of a builder reports `running`, since a bootstrapped BuildKit pod may
+/// not serve builds yet.
+fn wait_until_running(
+    runner: &dyn CommandRunner,
+    name: &str,
+    timeout: Duration,
+) -> Result<(), InitError> {
+    let started = Instant::now();
+    loop {
+        let output = run_command_output(
+            runner,
+            Command::new("docker").args(["buildx", "inspect", name]),
+            "docker buildx inspect",
+        )?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let statuses: Vec<&str> = stdout
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("Status:"))
+            .map(str::trim)
+            .collect();
+        if output.status.success()
+            && !statuses.is_empty()
+            && statuses.iter().all(|status| *status == "running")
+        {
+            tracing::info!("Builder {} has {} running node(s)", name, statuses.len());
+            return Ok(());
+        }
+
+        if started.elapsed() >= timeout {
+            return Err(InitError::Timeout(format!(
+                "Builder {} wasn't running within {:?}, node status: {}",
+                name,
+                timeout,
+                if statuses.is_empty() {
+                    "unknown".to_string()
+                } else {
+                    statuses.join(", ")
+                }
+            )));
+        }
+        tracing::debug!(
+            "Waiting for builder {} to run, node status: {}",
+            name,
+            statuses.join(", ")
+        );
+        std::thread::sleep(READY_POLL_INTERVAL);
+    }
+}
+
+fn use_builder(runner: &dyn CommandRunner, name: &str) -> Result<(), String> {
     let output = run_command_output(
-        Command::new("docker").args(["buildx", "use", BUILDER_NAME]),
+        runner,
+        Command::new("docker").args(["buildx", "use", name]),
         "docker buildx use",
     )?;
 
@@ -80,22 +468,37 @@ fn use_builder() -> Result<(), String> {
     Ok(())
 }
 
-fn create_builder() -> Result<(), String> {
-    // Use the remote driver to connect to buildkitd via TCP.
-    // This avoids the cgroup v2 exec issues with the kubernetes driver.
-    let output = run_command_output(
-        Command::new("docker").args([
-            "buildx",
-            "create",
-            "--driver",
-            "remote",
-            "--name",
-            BUILDER_NAME,
-            BUILDKITD_ENDPOINT,
-            "--use",
-        ]),
-        "docker buildx create",
-    )?;
+fn create_builder(runner: &dyn CommandRunner, builder: &BuilderConfig) -> Result<(), String> {
+    // The remote driver connects to buildkitd via TCP, which avoids the cgroup v2 exec
+    // issues with the kubernetes driver, so it stays the default.
+    let mut command = Command::new("docker");
+    command.args([
+        "buildx",
+        "create",
+        "--driver",
+        builder.driver.as_str(),
+        "--name",
+        &builder.name,
+    ]);
+
+    for (key, value) in builder.all_driver_opts() {
+        command.args(["--driver-opt", &driver_opt_arg(&key, &value)]);
+    }
+
+    if !builder.platforms.is_empty() {
+        command.args(["--platform", &builder.platforms.join(",")]);
+    }
+
+    // only the remote driver takes an endpoint, the others start their own daemon
+    if builder.driver == BuilderDriver::Remote {
+        let endpoint = builder
+            .endpoint
+            .as_deref()
+            .ok_or_else(|| format!("Builder {} has no endpoint", builder.name))?;
+        command.arg(endpoint);
+    }
+
+    let output = run_command_output(runner, &mut command, "docker buildx create")?;
 
     if !output.status.success() {
         return Err("Failed to create builder".to_string());
@@ -104,15 +507,332 @@ fn create_builder() -> Result<(), String> {
     Ok(())
 }
 
-fn bootstrap_builder() -> Result<(), String> {
-    let output = run_command_output(
-        Command::new("docker").args(["buildx", "inspect", "--bootstrap"]),
-        "docker buildx inspect --bootstrap",
-    )?;
+/// A `--driver-opt` value. buildx reads it as CSV, so options whose value holds a list, like
+/// "nodeselector=pool=build,arch=arm64", are quoted to stay a single option.
+fn driver_opt_arg(key: &str, value: &str) -> String {
+    let opt = format!("{}={}", key, value);
+    if opt.contains([',', '"']) {
+        format!("\"{}\"", opt.replace('"', "\"\""))
+    } else {
+        opt
+    }
+}
+
+fn bootstrap_builder(
+    runner: &dyn CommandRunner,
+    name: &str,
+    timeout: Duration,
+) -> Result<(), InitError> {
+    let mut child = runner
+        .spawn(
+            Command::new("docker")
+                .args(["buildx", "inspect", "--bootstrap", name])
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped()),
+        )
+        .map_err(|e| format!("Failed to run docker buildx inspect --bootstrap: {}", e))?;
+
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if started.elapsed() >= timeout => {
+                let _ = child.kill();
+                let _ = child.wait_with_output();
+                return Err(InitError::Timeout(format!(
+                    "Builder {} wasn't ready within {:?}, check that the BuildKit daemon is scheduled and reachable",
+                    name, timeout
+                )));
+            }
+            Ok(None) => std::thread::sleep(BOOTSTRAP_POLL_INTERVAL),
+            Err(e) => return Err(format!("Failed to check bootstrap status: {}", e).into()),
+        }
+    }
+
+    let output = child.wait_with_output().map_err(|e| {
+        format!(
+            "Failed to wait for docker buildx inspect --bootstrap: {}",
+            e
+        )
+    })?;
+    if !output.status.success() {
+        tracing::warn!(
+            "docker buildx inspect --bootstrap stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err("Failed to bootstrap builder".to_string().into());
+    }
+
+    Ok(())
+}
+
+/// Prune the cache of every builder each interval, until the service stops.
+pub async fn prune_periodically(
+    runner: &'static dyn CommandRunner,
+    builders: Vec<String>,
+    config: PruneConfig,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    // the first tick completes immediately, builders were just initialized
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        for builder in &builders {
+            let name = builder.clone();
+            let config = config.clone();
+            match tokio::task::spawn_blocking(move || prune(runner, &name, &config)).await {
+                Ok(Ok(reclaimed)) => {
+                    tracing::info!(
+                        "Pruned cache of builder {}, reclaimed {}",
+                        builder,
+                        reclaimed
+                    )
+                }
+                Ok(Err(e)) => tracing::warn!("Failed to prune cache of builder {}: {}", builder, e),
+                Err(e) => tracing::warn!("Cache prune task for builder {} failed: {}", builder, e),
+            }
+        }
+    }
+}
+
+/// Remove cache records of `builder` matching the prune thresholds, returning the reclaimed size.
+fn prune(
+    runner: &dyn CommandRunner,
+    builder: &str,
+    config: &PruneConfig,
+) -> Result<String, String> {
+    tracing::info!(
+        "Pruning cache of builder {} unused for {}",
+        builder,
+        config.until
+    );
+
+    let mut command = Command::new("docker");
+    command.args([
+        "buildx",
+        "prune",
+        "--force",
+        "--builder",
+        builder,
+        "--filter",
+        &format!("until={}", config.until),
+    ]);
+    if let Some(keep_storage) = &config.keep_storage {
+        command.args(["--keep-storage", keep_storage]);
+    }
+
+    let output = run_command_output(runner, &mut command, "docker buildx prune")?;
+    if !output.status.success() {
+        return Err("Failed to prune builder cache".to_string());
+    }
+
+    // buildx reports the reclaimed size on a "Total:" line, and prints nothing when none was
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let reclaimed = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Total:"))
+        .map(str::trim)
+        .unwrap_or("0B");
+
+    Ok(reclaimed.to_string())
+}
+
+/// Read the registry credentials from the secret again each refresh interval, until the service
+/// stops.
+pub async fn refresh_registry_credentials(
+    runner: &'static dyn CommandRunner,
+    secret: RegistrySecretConfig,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(secret.refresh_secs));
+    // the first tick completes immediately, the credentials were just written on initialize
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        let task_secret = secret.clone();
+        match tokio::task::spawn_blocking(move || write_registry_credentials(runner, &task_secret))
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!(
+                "Failed to refresh registry credentials from secret {}, keeping the current ones: {}",
+                secret.name,
+                e
+            ),
+            Err(e) => tracing::warn!("Registry credentials refresh task failed: {}", e),
+        }
+    }
+}
 
+/// Write the docker config JSON of the registry secret to the Docker CLI config.
+fn write_registry_credentials(
+    runner: &dyn CommandRunner,
+    secret: &RegistrySecretConfig,
+) -> Result<(), String> {
+    let mut command = Command::new("kubectl");
+    command.args(["get", "secret", &secret.name]);
+    if let Some(namespace) = &secret.namespace {
+        command.args(["-n", namespace]);
+    }
+    // decoded by kubectl, so the credentials never pass through an extra process
+    command.args([
+        "-o",
+        r#"go-template={{index .data ".dockerconfigjson" | base64decode}}"#,
+    ]);
+
+    let output = run_command_output(runner, &mut command, "kubectl get secret")?;
     if !output.status.success() {
-        return Err("Failed to bootstrap builder".to_string());
+        return Err(format!("Failed to read registry secret {}", secret.name));
+    }
+    if serde_json::from_slice::<serde_json::Value>(&output.stdout).is_err() {
+        return Err(format!(
+            "Registry secret {} doesn't hold a valid `.dockerconfigjson`",
+            secret.name
+        ));
     }
 
+    // written next to the config and renamed over it, so a build never reads a partial file
+    let config_path = Path::new(DOCKER_CONFIG_DIR).join("config.json");
+    let partial_path = Path::new(DOCKER_CONFIG_DIR).join("config.json.partial");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&partial_path)
+        .map_err(|e| format!("Failed to write {}: {}", partial_path.display(), e))?;
+    file.write_all(&output.stdout)
+        .map_err(|e| format!("Failed to write {}: {}", partial_path.display(), e))?;
+    fs::rename(&partial_path, &config_path)
+        .map_err(|e| format!("Failed to replace {}: {}", config_path.display(), e))?;
+
+    tracing::info!(
+        "Wrote registry credentials from secret {} to {}",
+        secret.name,
+        config_path.display()
+    );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::mock::MockRunner;
+
+    const RUNNING: &str =
+        "Name: builder\nDriver: remote\n\nNodes:\nName: builder0\nStatus: running\n";
+
+    #[test]
+    fn initialize_creates_missing_builder() {
+        let runner = MockRunner::default();
+        // inspect fails for a builder that doesn't exist yet
+        runner.push_output(1, "", "ERROR: no builder \"builder\" found");
+        let builders = builders(&[], "build");
+
+        initialize(&runner, &builders, Duration::from_secs(5), None, None).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            [
+                "docker buildx inspect builder",
+                "docker buildx create --driver remote --name builder tcp://buildkitd.build.svc.cluster.local:1234",
+                "docker buildx inspect --bootstrap builder",
+                "docker buildx use builder",
+            ]
+        );
+    }
+
+    #[test]
+    fn initialize_reuses_existing_builder_and_waits_until_running() {
+        let runner = MockRunner::default();
+        runner.push_output(0, RUNNING, "");
+        runner.push_output(0, RUNNING, "");
+        let builders = builders(&[], "build");
+
+        initialize(
+            &runner,
+            &builders,
+            Duration::from_secs(5),
+            Some(Duration::from_secs(5)),
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            [
+                "docker buildx inspect builder",
+                "docker buildx inspect builder",
+                "docker buildx use builder",
+            ]
+        );
+    }
+
+    #[test]
+    fn initialize_passes_kubernetes_driver_opts() {
+        let runner = MockRunner::default();
+        runner.push_output(1, "", "");
+        let builders = builders(
+            &[BuilderConfig {
+                name: "k8s".to_string(),
+                driver: BuilderDriver::Kubernetes,
+                endpoint: None,
+                driver_opts: HashMap::from([("replicas".to_string(), "2".to_string())]),
+                node_selector: BTreeMap::from([
+                    ("arch".to_string(), "arm64".to_string()),
+                    ("pool".to_string(), "build".to_string()),
+                ]),
+                tolerations: Vec::new(),
+                annotations: BTreeMap::new(),
+                labels: BTreeMap::new(),
+                platforms: vec!["linux/arm64".to_string()],
+            }],
+            "build",
+        );
+
+        initialize(&runner, &builders, Duration::from_secs(5), None, None).unwrap();
+
+        assert_eq!(
+            runner.commands()[1],
+            "docker buildx create --driver kubernetes --name k8s \
+             --driver-opt namespace=build \
+             --driver-opt \"nodeselector=arch=arm64,pool=build\" \
+             --driver-opt replicas=2 --platform linux/arm64"
+        );
+    }
+
+    #[test]
+    fn wait_until_running_times_out_on_stopped_node() {
+        let runner = MockRunner::default();
+        runner.push_output(
+            0,
+            "Name: builder\nNodes:\nName: builder0\nStatus: running\nName: builder1\nStatus: inactive\n",
+            "",
+        );
+
+        let err = wait_until_running(&runner, "builder", Duration::ZERO).unwrap_err();
+
+        assert!(matches!(err, InitError::Timeout(_)));
+        assert!(err.to_string().contains("running, inactive"), "{}", err);
+        assert_eq!(runner.commands(), ["docker buildx inspect builder"]);
+    }
+
+    #[test]
+    fn wait_until_running_accepts_all_running_nodes() {
+        let runner = MockRunner::default();
+        runner.push_output(0, RUNNING, "");
+
+        wait_until_running(&runner, "builder", Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn wait_until_running_requires_a_status() {
+        let runner = MockRunner::default();
+        runner.push_output(1, "", "ERROR: no builder \"builder\" found");
+
+        let err = wait_until_running(&runner, "builder", Duration::ZERO).unwrap_err();
+
+        assert!(err.to_string().contains("node status: unknown"), "{}", err);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Running builds per project, so they can be cancelled.
+pub struct RunningBuilds {
+    running: Mutex<HashMap<String, Arc<CancelHandle>>>,
+    /// Set once the server shuts down, so builds starting afterwards are cancelled right away.
+    shutting_down: AtomicBool,
+}
+
+impl RunningBuilds {
+    pub fn new() -> Self {
+        RunningBuilds {
+            running: Mutex::new(HashMap::new()),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Register a build of `project` that has started running.
+    pub fn register(&self, project: &str) -> Arc<CancelHandle> {
+        let handle = Arc::new(CancelHandle::default());
+        if self.shutting_down.load(Ordering::SeqCst) {
+            handle.cancel();
+        }
+        let mut running = self.running.lock().unwrap();
+        running.insert(project.to_string(), Arc::clone(&handle));
+        handle
+    }
+
+    /// Forget a finished build, unless a newer build of the project replaced it.
+    pub fn remove(&self, project: &str, handle: &Arc<CancelHandle>) {
+        let mut running = self.running.lock().unwrap();
+        if running
+            .get(project)
+            .is_some_and(|current| Arc::ptr_eq(current, handle))
+        {
+            running.remove(project);
+        }
+    }
+
+    /// Number of builds running.
+    pub fn count(&self) -> usize {
+        self.running.lock().unwrap().len()
+    }
+
+    /// Stop starting builds: builds registered from now on are cancelled right away.
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Cancel every running build.
+    pub fn cancel_all(&self) {
+        let running = self.running.lock().unwrap();
+        for handle in running.values() {
+            handle.cancel();
+        }
+    }
+
+    /// Cancel the running build of `project`, returning false if none is running.
+    pub fn cancel(&self, project: &str) -> bool {
+        let running = self.running.lock().unwrap();
+        match running.get(project) {
+            Some(handle) => {
+                handle.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Cancellation state of a single running build.
+#[derive(Default)]
+pub struct CancelHandle {
+    cancelled: AtomicBool,
+    /// Pid of the build tool process currently running, if any.
+    pid: Mutex<Option<u32>>,
+}
+
+impl CancelHandle {
+    /// Mark the build cancelled and terminate its current build process.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+
+        let pid = self.pid.lock().unwrap();
+        if let Some(pid) = *pid {
+            tracing::info!("Terminating build process {}", pid);
+            // SAFETY: kill only sends a signal and touches no memory; the pid is cleared right
+            // after the build process is waited for, so it is only signalled while it's ours
+            let result = unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            if result != 0 {
+                tracing::warn!(
+                    "Failed to terminate build process {}: {}",
+                    pid,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fail with "Build cancelled" once the build was cancelled, for checks between steps.
+    pub fn check(&self) -> Result<(), String> {
+        if self.is_cancelled() {
+            return Err("Build cancelled".to_string());
+        }
+        Ok(())
+    }
+
+    /// Set the build process to terminate on cancel, or clear it once the process exited.
+    pub fn set_process(&self, pid: Option<u32>) {
+        *self.pid.lock().unwrap() = pid;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+
+    #[test]
+    fn register_after_shut_down_is_cancelled() {
+        let builds = RunningBuilds::new();
+        let before = builds.register("api");
+        builds.shut_down();
+        let after = builds.register("web");
+
+        assert!(!before.is_cancelled());
+        assert!(after.is_cancelled());
+        assert_eq!(after.check(), Err("Build cancelled".to_string()));
+        assert_eq!(builds.count(), 2);
+    }
+
+    #[test]
+    fn cancel_terminates_the_build_process() {
+        let builds = RunningBuilds::new();
+        let handle = builds.register("api");
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        handle.set_process(Some(child.id()));
+
+        assert!(builds.cancel("api"));
+        assert!(handle.is_cancelled());
+        let status = child.wait().unwrap();
+        handle.set_process(None);
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+
+    #[test]
+    fn remove_keeps_a_newer_build() {
+        let builds = RunningBuilds::new();
+        let old = builds.register("api");
+        let new = builds.register("api");
+
+        builds.remove("api", &old);
+        assert_eq!(builds.count(), 1);
+        assert!(!builds.cancel("web"));
+        builds.remove("api", &new);
+        assert_eq!(builds.count(), 0);
+        assert!(!builds.cancel("api"));
+    }
+}
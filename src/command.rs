@@ -0,0 +1,164 @@
+use std::io::{self, Read};
+use std::process::{Child, Command, ExitStatus, Output};
+
+/// Runs the external commands (`docker`, `kubectl`, build tools) a module issues, so they can be
+/// recorded instead of executed.
+pub trait CommandRunner: Send + Sync {
+    /// Run `command` to completion, capturing its output.
+    fn output(&self, command: &mut Command) -> io::Result<Output>;
+
+    /// Start `command` without waiting for it to finish.
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>>;
+}
+
+/// A started command.
+pub trait Process: Send {
+    /// Pid of the process, if it's a real one that can be signalled.
+    fn id(&self) -> Option<u32>;
+
+    /// Take the piped stderr of the process, if it was piped and not taken yet.
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>>;
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+
+    fn kill(&mut self) -> io::Result<()>;
+
+    /// Wait for the process to exit, collecting the output that wasn't taken.
+    fn wait_with_output(self: Box<Self>) -> io::Result<Output>;
+}
+
+/// Runs commands as real processes. Commands are debug-logged, so they must not carry secrets in
+/// their arguments; pass those through the environment instead.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn output(&self, command: &mut Command) -> io::Result<Output> {
+        tracing::debug!("Running `{}`", describe(command));
+        command.output()
+    }
+
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>> {
+        tracing::debug!("Starting `{}`", describe(command));
+        Ok(Box::new(command.spawn()?))
+    }
+}
+
+impl Process for Child {
+    fn id(&self) -> Option<u32> {
+        Some(Child::id(self))
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.stderr
+            .take()
+            .map(|stderr| Box::new(stderr) as Box<dyn Read + Send>)
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        Child::kill(self)
+    }
+
+    fn wait_with_output(self: Box<Self>) -> io::Result<Output> {
+        Child::wait_with_output(*self)
+    }
+}
+
+/// Program and arguments of a command, e.g. "kubectl rollout restart -n app deployment/api".
+pub fn describe(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Command runner for unit tests, recording commands and answering with canned outputs.
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+    use std::io::{self, Read};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::{Command, ExitStatus, Output};
+    use std::sync::Mutex;
+
+    use super::{CommandRunner, Process, describe};
+
+    #[derive(Default)]
+    pub struct MockRunner {
+        commands: Mutex<Vec<String>>,
+        outputs: Mutex<VecDeque<Output>>,
+    }
+
+    impl MockRunner {
+        /// Queue the output of the next command, commands without one succeed with no output.
+        pub fn push_output(&self, code: i32, stdout: &str, stderr: &str) {
+            self.outputs.lock().unwrap().push_back(Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: stderr.as_bytes().to_vec(),
+            });
+        }
+
+        /// Commands run so far, as described by [`describe`].
+        pub fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+
+        fn next_output(&self, command: &Command) -> Output {
+            self.commands.lock().unwrap().push(describe(command));
+            self.outputs
+                .lock()
+                .unwrap()
+                .pop_front()
+                .unwrap_or_else(|| Output {
+                    status: ExitStatus::from_raw(0),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                })
+        }
+    }
+
+    impl CommandRunner for MockRunner {
+        fn output(&self, command: &mut Command) -> io::Result<Output> {
+            Ok(self.next_output(command))
+        }
+
+        fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn Process>> {
+            Ok(Box::new(MockProcess {
+                output: self.next_output(command),
+            }))
+        }
+    }
+
+    /// A process that already exited with its canned output.
+    struct MockProcess {
+        output: Output,
+    }
+
+    impl Process for MockProcess {
+        fn id(&self) -> Option<u32> {
+            None
+        }
+
+        fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+            let stderr = std::mem::take(&mut self.output.stderr);
+            Some(Box::new(io::Cursor::new(stderr)))
+        }
+
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            Ok(Some(self.output.status))
+        }
+
+        fn kill(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn wait_with_output(self: Box<Self>) -> io::Result<Output> {
+            Ok(self.output)
+        }
+    }
+}
@@ -1,8 +1,19 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 
-use crate::project::ProjectConfig;
+use crate::auth::TokenScope;
+use crate::buildx::{self, BuilderConfig, BuilderDriver, PruneConfig, RegistrySecretConfig};
+use crate::project::{
+    self, BuildBackend, BuildLogMode, BuildProgress, GitCredential, ProjectConfig,
+};
+use crate::proxy::ProxyConfig;
+
+/// Default config location, relative to the working directory.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
 
 /// Raw config file model parsed from config.toml.
 #[derive(Debug, Deserialize)]
@@ -13,17 +24,278 @@ pub struct ConfigFile {
     projects: Vec<ProjectConfig>,
 }
 
-/// Application-level settings loaded from config.toml.
+/// A single file within a config directory, holding app settings, projects, or a single project.
 #[derive(Debug, Deserialize)]
+struct PartialConfigFile {
+    app: Option<AppConfig>,
+    #[serde(default)]
+    projects: Vec<ProjectConfig>,
+}
+
+/// Application-level settings loaded from config.toml.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct AppConfig {
-    /// Base image registry hostname used to tag images.
+    /// Base image registry hostname used to tag images, optionally with a path prefix.
+    /// A URL scheme like `https://` is dropped from image references.
     /// Can be any valid container registry (e.g., Docker Hub, ECR, GCR).
     /// Examples: "ghcr.io/my-org", "123456789012.dkr.ecr.us-west-2.amazonaws.com/my-repo"
     pub registry: String,
+    /// Path prepended to every `image.repository`, e.g. "org/team" to push "api" as
+    /// "org/team/api". Repositories starting with `/` are used as they are.
+    pub repository_prefix: Option<String>,
+    /// Glob patterns `image.location` must match, e.g. ["docker/*/Dockerfile"], so a shared
+    /// instance only builds expected Dockerfiles. Any location is allowed when empty.
+    #[serde(default)]
+    pub allowed_locations: Vec<String>,
+    /// Tokens limited to some projects, accepted next to the full access `BEARER_TOKENS`.
+    #[serde(default)]
+    pub token_scopes: Vec<TokenScope>,
+    /// Tokens for cloning from git hosts `GITHUB_TOKEN` doesn't cover, picked by repository host
+    /// or by a project's `code.credential`.
+    #[serde(default)]
+    pub git_credentials: Vec<GitCredential>,
+    /// Source CIDR blocks allowed to call the build hook endpoints.
+    /// When empty, requests from any address are accepted.
+    /// e.g. ["140.82.112.0/20", "2a0a:a440::/29"] for GitHub webhooks
+    #[serde(default)]
+    pub allowed_cidrs: Vec<IpNet>,
+    /// Reverse proxy CIDR blocks whose `X-Forwarded-For` header is trusted
+    /// when resolving the client address.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Tool used to build images, either "buildx" (default) or "kaniko".
+    #[serde(default)]
+    pub backend: BuildBackend,
+    /// File where build status and history are persisted across restarts.
+    /// When unset, build history is only kept in memory.
+    pub status_file: Option<PathBuf>,
+    /// Directory a JSON report of every finished build is written to, as
+    /// "<project>-<build id>.json". When unset, no reports are written.
+    pub report_dir: Option<PathBuf>,
+    /// Path prefix all routes are served under, e.g. "/hooks".
+    /// Empty by default, serving routes from the root.
+    #[serde(default)]
+    pub base_path: String,
+    /// Name of this instance, e.g. "prod-eu", labelled on every metric and logged on every span
+    /// so several instances can share a monitoring backend. Unset by default.
+    pub instance_name: Option<String>,
+    /// Maximum number of builds running at once across all projects.
+    /// When unset, only the per-project limit of one build applies.
+    pub max_global_builds: Option<usize>,
+    /// Maximum number of repository clones running at once across all builds, taken before
+    /// the clone and released once the source is fetched. When unset, clones aren't limited.
+    pub max_concurrent_clones: Option<usize>,
+    /// How triggers are handled when a build limit is reached.
+    #[serde(default)]
+    pub limit_mode: LimitMode,
+    /// Maximum number of builds per project waiting to start in queue mode.
+    /// When unset, the queue is unbounded.
+    pub max_queue_depth: Option<usize>,
+    /// Buildx builders to create, selected per image with `image.builder`.
+    /// When empty, a single builder named "builder" connects to the default BuildKit endpoint.
+    #[serde(default)]
+    pub builders: Vec<BuilderConfig>,
+    /// Namespace of the BuildKit daemon the default builder connects to, which may differ from
+    /// the deployments' namespaces. Falls back to `BUILDER_NAMESPACE`, then "build".
+    pub builder_namespace: Option<String>,
+    /// Periodically prune the builders' BuildKit cache so it doesn't fill the node's disk.
+    /// Disabled when unset.
+    pub prune: Option<PruneConfig>,
+    /// Kubernetes secret to read the registry push credentials from, instead of a mounted
+    /// Docker config. Only used by the buildx backend.
+    pub registry_secret: Option<RegistrySecretConfig>,
+    /// How long (in seconds) a webhook delivery id is remembered, so provider retries of the
+    /// same delivery don't trigger duplicate builds. Defaults to one hour.
+    #[serde(default = "default_delivery_window_secs")]
+    pub delivery_window_secs: u64,
+    /// How long (in seconds) a client's `Idempotency-Key` is remembered, so repeating a trigger
+    /// with the same key returns the build it started. Defaults to one day.
+    #[serde(default = "default_idempotency_window_secs")]
+    pub idempotency_window_secs: u64,
+    /// HTTP proxy used by git clones, image pushes, and `RUN` steps during builds.
+    /// Falls back to the service's proxy environment variables when unset.
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// How build tool output is written to the log: every line, only the tail of successful
+    /// builds, or only failed builds.
+    #[serde(default)]
+    pub build_log_mode: BuildLogMode,
+    /// Progress output style of `docker buildx build`: "auto", "plain", or "tty". Defaults to
+    /// "plain", since the output is captured rather than shown on a terminal.
+    #[serde(default)]
+    pub build_progress: BuildProgress,
+    /// Lines of output logged for a successful build in the tail mode. Defaults to 50.
+    #[serde(default = "default_build_log_tail_lines")]
+    pub build_log_tail_lines: usize,
+    /// Most bytes of build output kept in memory per image outside the full log mode, dropping
+    /// the earliest lines beyond it. Defaults to 10 MiB.
+    #[serde(default = "default_max_captured_output_bytes")]
+    pub max_captured_output_bytes: usize,
+    /// Keep the cloned repository of a failed build for debugging, with the failure reason
+    /// written to `build-hook-failure.txt` in it. Workspaces are removed by default.
+    #[serde(default)]
+    pub keep_failed_workspace: bool,
+    /// How long (in seconds) a client has to send the full webhook body before the request is
+    /// rejected, so a slow client can't hold a connection open. Defaults to 10 seconds.
+    #[serde(default = "default_body_timeout_secs")]
+    pub body_timeout_secs: u64,
+    /// How long (in seconds) any request may take before it's answered with `504`. Builds run in
+    /// the background, so hitting this points at a bug. Defaults to 30 seconds.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Requests handled at once across all connections. Requests over the limit are answered
+    /// with `503` instead of queueing. Unlimited by default.
+    pub max_connections: Option<usize>,
+    /// How long (in seconds) a connection may wait between requests before it's closed, which
+    /// also bounds how long a client may take to send request headers. Defaults to 60 seconds.
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Idle time (in seconds) before TCP keep-alive probes are sent on a connection, so dead
+    /// peers are dropped. Keep-alive probes are off by default.
+    pub tcp_keepalive_secs: Option<u64>,
+    /// How long (in seconds) running builds may take to finish on shutdown before their build
+    /// processes are terminated. Defaults to 30 seconds.
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Refuse to start when a project's deployment resources are missing from the cluster.
+    /// Missing resources are only logged as warnings by default.
+    #[serde(default)]
+    pub require_resources: bool,
+    /// How long (in seconds) to wait for a new buildx builder to bootstrap before failing
+    /// startup. Defaults to 5 minutes.
+    #[serde(default = "default_bootstrap_timeout_secs")]
+    pub bootstrap_timeout_secs: u64,
+    /// How long (in seconds) to wait at startup for every node of each buildx builder to report
+    /// `running`, failing startup otherwise. When unset, builders aren't waited on.
+    pub builder_ready_timeout_secs: Option<u64>,
+    /// Paths whose filesystems' free space is reported by `/diag`, e.g. the cache volume.
+    /// Defaults to ["/tmp"], where workspaces are cloned.
+    #[serde(default = "default_disk_paths")]
+    pub disk_paths: Vec<PathBuf>,
+    /// Free space (percent) below which `/diag` reports a disk as unhealthy. Defaults to 10.
+    #[serde(default = "default_min_free_percent")]
+    pub min_free_percent: u8,
+    /// PEM bundle of CA certificates trusted by git clones, tarball downloads, and the build tool,
+    /// e.g. for self-hosted infrastructure with a private CA. Falls back to `CA_BUNDLE`.
+    pub ca_bundle: Option<PathBuf>,
+    /// Serve HTTPS with this certificate and key instead of plain HTTP.
+    /// Falls back to `TLS_CERT_PATH` and `TLS_KEY_PATH` when unset.
+    pub tls: Option<TlsConfig>,
+}
+
+/// PEM certificate and private key the server terminates TLS with.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TlsConfig {
+    /// Certificate chain, e.g. "/etc/build-hook/tls/tls.crt".
+    pub cert_path: PathBuf,
+    /// Private key for the certificate, e.g. "/etc/build-hook/tls/tls.key".
+    pub key_path: PathBuf,
+}
+
+fn default_delivery_window_secs() -> u64 {
+    3600
+}
+
+fn default_idempotency_window_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_build_log_tail_lines() -> usize {
+    50
+}
+
+fn default_max_captured_output_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_body_timeout_secs() -> u64 {
+    10
+}
+
+fn default_disk_paths() -> Vec<PathBuf> {
+    vec![PathBuf::from("/tmp")]
+}
+
+fn default_min_free_percent() -> u8 {
+    10
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    60
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+fn default_bootstrap_timeout_secs() -> u64 {
+    300
+}
+
+impl AppConfig {
+    /// Configured buildx builders, or the default builder when none are configured.
+    pub fn builders(&self) -> Vec<BuilderConfig> {
+        buildx::builders(&self.builders, &self.builder_namespace())
+    }
+
+    /// TLS settings from the config, or from the environment when both paths are set there.
+    pub fn tls(&self) -> Option<TlsConfig> {
+        self.tls.clone().or_else(|| {
+            Some(TlsConfig {
+                cert_path: std::env::var_os("TLS_CERT_PATH")?.into(),
+                key_path: std::env::var_os("TLS_KEY_PATH")?.into(),
+            })
+        })
+    }
+
+    /// Environment variables pointing git, curl, and Go-based build tools at the CA bundle.
+    pub fn ca_env(&self) -> Vec<(String, String)> {
+        let Some(ca_bundle) = self
+            .ca_bundle
+            .clone()
+            .or_else(|| std::env::var_os("CA_BUNDLE").map(PathBuf::from))
+        else {
+            return Vec::new();
+        };
+
+        let ca_bundle = ca_bundle.to_string_lossy().to_string();
+        ["GIT_SSL_CAINFO", "CURL_CA_BUNDLE", "SSL_CERT_FILE"]
+            .into_iter()
+            .map(|name| (name.to_string(), ca_bundle.clone()))
+            .collect()
+    }
+
+    /// Namespace of the default builder's BuildKit daemon.
+    pub fn builder_namespace(&self) -> String {
+        self.builder_namespace
+            .clone()
+            .or_else(|| std::env::var("BUILDER_NAMESPACE").ok())
+            .filter(|namespace| !namespace.trim().is_empty())
+            .unwrap_or_else(|| buildx::DEFAULT_BUILDER_NAMESPACE.to_string())
+    }
+}
+
+/// Behavior when a project or global build limit is reached.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitMode {
+    /// Reject the trigger, with `409` for a busy project or `503` at the global limit.
+    #[default]
+    Reject,
+    /// Accept the trigger and wait for the build to be allowed to start.
+    Queue,
+    /// Like `Queue`, but keep at most one build per project waiting to start. Later triggers
+    /// update that pending build's target instead of queueing more builds.
+    Coalesce,
 }
 
 /// Runtime configuration parsed from `config.toml`.
 /// Used in shared application state.
+#[derive(Serialize)]
 pub struct HookConfig {
     /// Application-wide configuration.
     pub app: AppConfig,
@@ -31,52 +303,879 @@ pub struct HookConfig {
     pub projects: HashMap<String, ProjectConfig>,
 }
 
+/// Project slugs that differ between two configurations.
+#[derive(Debug, Default, Serialize)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl ConfigDiff {
+    pub fn between(old: &HookConfig, new: &HookConfig) -> Self {
+        let mut diff = ConfigDiff::default();
+
+        for (slug, project) in &new.projects {
+            match old.projects.get(slug) {
+                None => diff.added.push(slug.clone()),
+                Some(old_project) if old_project != project => diff.changed.push(slug.clone()),
+                Some(_) => {}
+            }
+        }
+        for slug in old.projects.keys() {
+            if !new.projects.contains_key(slug) {
+                diff.removed.push(slug.clone());
+            }
+        }
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+        diff
+    }
+}
+
+/// Placeholder shown in place of secret values when serializing the config.
+const REDACTED: &str = "[redacted]";
+
+/// Serialize a map with its values redacted, keeping the keys visible.
+pub fn redact_values<S: serde::Serializer>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.keys().map(|key| (key, REDACTED)))
+}
+
+/// Serialize a URL with any `user:password@` credentials redacted.
+pub fn redact_url_credentials<S: serde::Serializer>(
+    url: &Option<String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let redacted = url.as_ref().map(|url| {
+        let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+        let host_start = rest.find('/').unwrap_or(rest.len());
+        match rest[..host_start].rsplit_once('@') {
+            Some((_, host)) if scheme.is_empty() => {
+                format!("{}@{}{}", REDACTED, host, &rest[host_start..])
+            }
+            Some((_, host)) => format!("{}://{}@{}{}", scheme, REDACTED, host, &rest[host_start..]),
+            None => url.clone(),
+        }
+    });
+    serializer.serialize_some(&redacted)
+}
+
 /// Load and validate configuration from `config.toml`.
 ///
 /// Expects `config.toml` to be in the current working directory, which is `/app` when running
-/// in containers or the project root when running locally.
+/// in containers or the project root when running locally. `CONFIG_PATH` overrides the location,
+/// and may point at a directory of `*.toml` files that are merged together.
 pub fn load() -> Result<HookConfig, String> {
-    // read projects config file
-    let file_string = std::fs::read_to_string("config.toml")
-        .map_err(|_| "Could not read config.toml file!".to_string())?;
+    let path = std::env::var("CONFIG_PATH").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let path = Path::new(&path);
+
+    let config_file = if path.is_dir() {
+        load_dir(path)?
+    } else {
+        load_file(path)?
+    };
 
-    let config_file: ConfigFile = toml::from_str(&file_string)
-        .map_err(|e| format!("Could not parse config.toml file: {}", e))?;
+    validate(&config_file).map_err(|errors| {
+        let errors: Vec<String> = errors
+            .iter()
+            .map(|error| format!("\n  - {}", error))
+            .collect();
+        format!("{} problem(s) found:{}", errors.len(), errors.concat())
+    })?;
 
+    let config = index_projects(config_file);
+
+    log(&config);
+
+    Ok(config)
+}
+
+/// The validated configuration held by contents of a single config file, for tests.
+#[cfg(test)]
+pub fn parse(contents: &str) -> Result<HookConfig, Vec<ValidationError>> {
+    let config_file = toml::from_str::<ConfigFile>(contents)
+        .map_err(|e| vec![ValidationError::new("", e.to_string())])?;
     validate(&config_file)?;
+    Ok(index_projects(config_file))
+}
 
+/// Key the projects of a validated config file by slug.
+fn index_projects(config_file: ConfigFile) -> HookConfig {
     let mut config: HashMap<String, ProjectConfig> = HashMap::new();
 
     config_file.projects.into_iter().for_each(|project| {
         config.insert(project.slug().to_owned(), project);
     });
 
-    let config = HookConfig {
+    HookConfig {
         app: config_file.app,
         projects: config,
-    };
+    }
+}
 
-    log(&config);
+/// Read a single config file holding the app settings and all projects.
+fn load_file(path: &Path) -> Result<ConfigFile, String> {
+    let file_string = std::fs::read_to_string(path)
+        .map_err(|_| format!("Could not read {} file!", path.display()))?;
 
-    Ok(config)
+    toml::from_str(&file_string)
+        .map_err(|e| format!("Could not parse {} file: {}", path.display(), e))
+}
+
+/// Read and merge every `*.toml` file in a config directory.
+///
+/// Each file may hold the `[app]` table and/or `[[projects]]`, or the fields of a single project
+/// at the top level. Exactly one file must define `[app]`, and slugs must be unique across files.
+fn load_dir(dir: &Path) -> Result<ConfigFile, String> {
+    let entries = std::fs::read_dir(dir)
+        .map_err(|e| format!("Could not read config directory {}: {}", dir.display(), e))?;
+
+    let mut paths = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("Could not read config directory {}: {}", dir.display(), e))?
+            .path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+            paths.push(path);
+        }
+    }
+    // sorted so merge order and error messages are deterministic
+    paths.sort();
+
+    let mut app: Option<(AppConfig, PathBuf)> = None;
+    let mut projects: Vec<ProjectConfig> = Vec::new();
+    let mut sources: HashMap<String, PathBuf> = HashMap::new();
+
+    for path in paths {
+        let file_string = std::fs::read_to_string(&path)
+            .map_err(|_| format!("Could not read {} file!", path.display()))?;
+        let table: toml::Table = toml::from_str(&file_string)
+            .map_err(|e| format!("Could not parse {} file: {}", path.display(), e))?;
+
+        // files without `app` or `projects` tables hold a single project
+        let partial = if table.contains_key("app") || table.contains_key("projects") {
+            table.try_into::<PartialConfigFile>()
+        } else {
+            table
+                .try_into::<ProjectConfig>()
+                .map(|project| PartialConfigFile {
+                    app: None,
+                    projects: vec![project],
+                })
+        }
+        .map_err(|e| format!("Could not parse {} file: {}", path.display(), e))?;
+
+        if let Some(file_app) = partial.app {
+            if let Some((_, app_path)) = &app {
+                return Err(format!(
+                    "`app` is defined in both {} and {}!",
+                    app_path.display(),
+                    path.display()
+                ));
+            }
+            app = Some((file_app, path.clone()));
+        }
+
+        for project in partial.projects {
+            if let Some(other) = sources.get(project.slug()) {
+                return Err(format!(
+                    "Project slug `{}` is defined in both {} and {}!",
+                    project.slug(),
+                    other.display(),
+                    path.display()
+                ));
+            }
+            sources.insert(project.slug().to_string(), path.clone());
+            projects.push(project);
+        }
+    }
+
+    let (app, _) = app.ok_or_else(|| {
+        format!(
+            "No file in config directory {} defines `app`!",
+            dir.display()
+        )
+    })?;
+
+    Ok(ConfigFile { app, projects })
+}
+
+/// A problem with one field of the configuration.
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    /// Path of the offending field, e.g. `projects[api].image[0].tag`.
+    pub path: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        ValidationError {
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.message)
+    }
 }
 
-/// Fail-fast validation of the loaded configuration.
-fn validate(config: &ConfigFile) -> Result<(), String> {
+/// Every problem with the contents of a single config file, without loading it, e.g. to check a
+/// proposed change. A file that doesn't parse has one problem with an empty path.
+pub fn check(contents: &str) -> Vec<ValidationError> {
+    match toml::from_str::<ConfigFile>(contents) {
+        Ok(config_file) => validate(&config_file).err().unwrap_or_default(),
+        Err(e) => vec![ValidationError::new(
+            "",
+            format!("Could not parse config: {}", e),
+        )],
+    }
+}
+
+/// Validation of the loaded configuration, collecting every problem so they can be fixed at once.
+fn validate(config: &ConfigFile) -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+    let app = &config.app;
+
     // app.registry should not be empty
-    if config.app.registry.trim().is_empty() {
-        return Err("`app.registry` must not be empty!".to_string());
+    if app.registry.trim().is_empty() {
+        errors.push(ValidationError::new("app.registry", "must not be empty"));
+    } else if let Err(e) = project::validate_registry(&app.registry) {
+        errors.push(ValidationError::new("app.registry", e));
+    }
+
+    // app.repository_prefix should be a repository path, slashes around it are dropped
+    if let Some(prefix) = &app.repository_prefix
+        && let Err(e) = project::validate_repository(prefix.trim_matches('/'))
+    {
+        errors.push(ValidationError::new("app.repository_prefix", e));
+    }
+
+    // app.base_path should be empty or an absolute path without a trailing slash
+    let base_path = &app.base_path;
+    if !base_path.is_empty() && (!base_path.starts_with('/') || base_path.ends_with('/')) {
+        errors.push(ValidationError::new(
+            "app.base_path",
+            "must start with `/` and not end with `/`, e.g. \"/hooks\"",
+        ));
+    }
+
+    // an empty app.instance_name would label every metric with nothing
+    if let Some(name) = &app.instance_name
+        && name.trim().is_empty()
+    {
+        errors.push(ValidationError::new(
+            "app.instance_name",
+            "must not be empty",
+        ));
+    }
+
+    // app.body_timeout_secs of zero would reject every request with a body
+    if app.body_timeout_secs == 0 {
+        errors.push(ValidationError::new(
+            "app.body_timeout_secs",
+            "must be at least 1",
+        ));
+    }
+
+    // app.builder_namespace should not be empty when set
+    if app
+        .builder_namespace
+        .as_ref()
+        .is_some_and(|namespace| namespace.trim().is_empty())
+    {
+        errors.push(ValidationError::new(
+            "app.builder_namespace",
+            "must not be empty",
+        ));
+    }
+
+    // app.request_timeout_secs of zero would time out every request, and a shorter timeout
+    // than the body read would report slow clients as server errors
+    if app.request_timeout_secs <= app.body_timeout_secs {
+        errors.push(ValidationError::new(
+            "app.request_timeout_secs",
+            "must be greater than `app.body_timeout_secs`",
+        ));
+    }
+
+    // app.max_connections of zero would shed every request
+    if app.max_connections == Some(0) {
+        errors.push(ValidationError::new(
+            "app.max_connections",
+            "must be at least 1",
+        ));
+    }
+
+    // app.idle_timeout_secs of zero would close connections before the first request
+    if app.idle_timeout_secs == 0 {
+        errors.push(ValidationError::new(
+            "app.idle_timeout_secs",
+            "must be at least 1",
+        ));
+    }
+
+    if app.tcp_keepalive_secs == Some(0) {
+        errors.push(ValidationError::new(
+            "app.tcp_keepalive_secs",
+            "must be at least 1",
+        ));
+    }
+
+    // app.ca_bundle should point at a file
+    if let Some(ca_bundle) = &app.ca_bundle
+        && !ca_bundle.is_file()
+    {
+        errors.push(ValidationError::new(
+            "app.ca_bundle",
+            format!("`{}` is not a file", ca_bundle.display()),
+        ));
+    }
+
+    // app.min_free_percent is a percentage
+    if app.min_free_percent > 100 {
+        errors.push(ValidationError::new(
+            "app.min_free_percent",
+            "must be at most 100",
+        ));
+    }
+
+    // app.bootstrap_timeout_secs of zero would fail every new builder
+    if app.bootstrap_timeout_secs == 0 {
+        errors.push(ValidationError::new(
+            "app.bootstrap_timeout_secs",
+            "must be at least 1",
+        ));
+    }
+
+    // app.builder_ready_timeout_secs of zero would fail every startup
+    if app.builder_ready_timeout_secs == Some(0) {
+        errors.push(ValidationError::new(
+            "app.builder_ready_timeout_secs",
+            "must be at least 1",
+        ));
+    }
+
+    // app.max_queue_depth of zero would reject every queued build
+    if app.max_queue_depth == Some(0) {
+        errors.push(ValidationError::new(
+            "app.max_queue_depth",
+            "must be at least 1",
+        ));
+    }
+
+    // app.max_global_builds of zero would block every build
+    if app.max_global_builds == Some(0) {
+        errors.push(ValidationError::new(
+            "app.max_global_builds",
+            "must be at least 1",
+        ));
+    }
+
+    // app.max_concurrent_clones of zero would block every clone
+    if app.max_concurrent_clones == Some(0) {
+        errors.push(ValidationError::new(
+            "app.max_concurrent_clones",
+            "must be at least 1",
+        ));
+    }
+
+    // app.allowed_locations patterns should not be empty
+    if app
+        .allowed_locations
+        .iter()
+        .any(|pattern| pattern.trim().is_empty())
+    {
+        errors.push(ValidationError::new(
+            "app.allowed_locations",
+            "must not contain empty patterns",
+        ));
+    }
+
+    // kaniko has no progress display to configure
+    if app.build_progress != BuildProgress::Plain && app.backend == BuildBackend::Kaniko {
+        errors.push(ValidationError::new(
+            "app.build_progress",
+            "is not supported by the kaniko backend",
+        ));
+    }
+
+    // app.registry_secret needs a name and a refresh interval, and is only read for buildx
+    if let Some(secret) = &app.registry_secret {
+        if secret.name.trim().is_empty() {
+            errors.push(ValidationError::new(
+                "app.registry_secret.name",
+                "must not be empty",
+            ));
+        }
+        if secret
+            .namespace
+            .as_ref()
+            .is_some_and(|namespace| namespace.trim().is_empty())
+        {
+            errors.push(ValidationError::new(
+                "app.registry_secret.namespace",
+                "must not be empty",
+            ));
+        }
+        if secret.refresh_secs == 0 {
+            errors.push(ValidationError::new(
+                "app.registry_secret.refresh_secs",
+                "must be at least 1",
+            ));
+        }
+        if app.backend == BuildBackend::Kaniko {
+            errors.push(ValidationError::new(
+                "app.registry_secret",
+                "is not supported by the kaniko backend",
+            ));
+        }
+    }
+
+    // app.prune needs a positive interval and an age to prune by
+    if let Some(prune) = &app.prune {
+        if prune.interval_secs == 0 {
+            errors.push(ValidationError::new(
+                "app.prune.interval_secs",
+                "must be at least 1",
+            ));
+        }
+        if prune.until.trim().is_empty() {
+            errors.push(ValidationError::new("app.prune.until", "must not be empty"));
+        }
+        if prune
+            .keep_storage
+            .as_ref()
+            .is_some_and(|keep_storage| keep_storage.trim().is_empty())
+        {
+            errors.push(ValidationError::new(
+                "app.prune.keep_storage",
+                "must not be empty",
+            ));
+        }
+    }
+
+    // app.builders need unique names, and options that suit their driver
+    let mut builder_names = Vec::new();
+    for (index, builder) in app.builders.iter().enumerate() {
+        let path = format!("app.builders[{}]", index);
+        if builder.name.trim().is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                "must not be empty",
+            ));
+        }
+        match builder.driver {
+            BuilderDriver::Remote => {
+                if builder
+                    .endpoint
+                    .as_deref()
+                    .is_none_or(|endpoint| endpoint.trim().is_empty())
+                {
+                    errors.push(ValidationError::new(
+                        format!("{}.endpoint", path),
+                        "must be set for the remote driver",
+                    ));
+                }
+            }
+            BuilderDriver::DockerContainer | BuilderDriver::Kubernetes => {
+                if builder.endpoint.is_some() {
+                    errors.push(ValidationError::new(
+                        format!("{}.endpoint", path),
+                        "is only used by the remote driver",
+                    ));
+                }
+            }
+        }
+        if builder.driver != BuilderDriver::Kubernetes && builder.has_pod_options() {
+            errors.push(ValidationError::new(
+                path.clone(),
+                format!(
+                    "`node_selector`, `tolerations`, `annotations`, and `labels` only apply to the kubernetes driver, not the {} driver",
+                    builder.driver.as_str()
+                ),
+            ));
+        }
+        // the pod options are passed as driver options, so they can't be set twice
+        let mut duplicated_opts: Vec<_> = [
+            ("nodeselector", builder.node_selector.is_empty()),
+            ("tolerations", builder.tolerations.is_empty()),
+            ("annotations", builder.annotations.is_empty()),
+            ("labels", builder.labels.is_empty()),
+        ]
+        .into_iter()
+        .filter(|(opt, unset)| !unset && builder.driver_opts.contains_key(*opt))
+        .map(|(opt, _)| opt)
+        .collect();
+        if !duplicated_opts.is_empty() {
+            duplicated_opts.sort();
+            errors.push(ValidationError::new(
+                format!("{}.driver_opts", path),
+                format!(
+                    "options {} are also set by the builder's pod options, set them once",
+                    duplicated_opts.join(", ")
+                ),
+            ));
+        }
+        if builder.driver != BuilderDriver::Kubernetes {
+            let mut kubernetes_opts: Vec<_> = builder
+                .driver_opts
+                .keys()
+                .filter(|opt| buildx::is_kubernetes_only_opt(opt))
+                .map(String::as_str)
+                .collect();
+            if !kubernetes_opts.is_empty() {
+                kubernetes_opts.sort();
+                errors.push(ValidationError::new(
+                    format!("{}.driver_opts", path),
+                    format!(
+                        "options {} only apply to the kubernetes driver, not the {} driver",
+                        kubernetes_opts.join(", "),
+                        builder.driver.as_str()
+                    ),
+                ));
+            }
+        }
+        if builder_names.contains(&&builder.name) {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                format!("must be unique, `{}` is used more than once", builder.name),
+            ));
+        }
+        builder_names.push(&builder.name);
+    }
+
+    for (index, project) in config.projects.iter().enumerate() {
+        errors.extend(project.validate(index, app));
+    }
+
+    // app.token_scopes need a unique name, a token, and only configured projects
+    let mut scope_names = Vec::new();
+    for (index, scope) in app.token_scopes.iter().enumerate() {
+        let path = format!("app.token_scopes[{}]", index);
+        if scope.name.trim().is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                "must not be empty",
+            ));
+        } else if scope_names.contains(&&scope.name) {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                format!("must be unique, `{}` is used more than once", scope.name),
+            ));
+        }
+        scope_names.push(&scope.name);
+        if scope.token_env.trim().is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.token_env", path),
+                "must not be empty",
+            ));
+        }
+        if scope.projects.is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.projects", path),
+                "must have at least one project",
+            ));
+        }
+        for slug in &scope.projects {
+            if !config.projects.iter().any(|project| project.slug() == slug) {
+                errors.push(ValidationError::new(
+                    format!("{}.projects", path),
+                    format!("`{}` is not a configured project", slug),
+                ));
+            }
+        }
+    }
+
+    // app.git_credentials need a unique name and a token, and hosts may only have one each
+    let mut credential_names = Vec::new();
+    let mut credential_hosts = Vec::new();
+    for (index, credential) in app.git_credentials.iter().enumerate() {
+        let path = format!("app.git_credentials[{}]", index);
+        if credential.name.trim().is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                "must not be empty",
+            ));
+        } else if credential_names.contains(&&credential.name) {
+            errors.push(ValidationError::new(
+                format!("{}.name", path),
+                format!(
+                    "must be unique, `{}` is used more than once",
+                    credential.name
+                ),
+            ));
+        }
+        credential_names.push(&credential.name);
+        if credential.token_env.trim().is_empty() {
+            errors.push(ValidationError::new(
+                format!("{}.token_env", path),
+                "must not be empty",
+            ));
+        }
+        if let Some(host) = &credential.host {
+            let host = host.to_ascii_lowercase();
+            if host.trim().is_empty() || host.contains("://") || host.contains('/') {
+                errors.push(ValidationError::new(
+                    format!("{}.host", path),
+                    "must be a host name, e.g. `gitlab.example.com`",
+                ));
+            } else if credential_hosts.contains(&host) {
+                errors.push(ValidationError::new(
+                    format!("{}.host", path),
+                    format!("must be unique, `{}` is used more than once", host),
+                ));
+            }
+            credential_hosts.push(host);
+        }
     }
 
+    // project.slug must be unique, otherwise later projects silently replace earlier ones
+    let mut by_slug: HashMap<&str, Vec<&str>> = HashMap::new();
     for project in &config.projects {
-        project.validate()?;
+        by_slug
+            .entry(project.slug())
+            .or_default()
+            .push(project.name());
+    }
+    let mut duplicates: Vec<_> = by_slug
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .collect();
+    duplicates.sort();
+    for (slug, names) in duplicates {
+        errors.push(ValidationError::new(
+            format!("projects[{}].slug", slug),
+            format!("must be unique, used by {}", names.join(", ")),
+        ));
     }
 
-    Ok(())
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 /// Emit debug logs about loaded configuration.
 fn log(config: &HookConfig) {
     tracing::info!("Configured image registry: {}", config.app.registry);
+    tracing::info!("Configured build backend: {:?}", config.app.backend);
+    if !config.app.allowed_cidrs.is_empty() {
+        tracing::info!(
+            "Restricting hook requests to {} source CIDR block(s)",
+            config.app.allowed_cidrs.len()
+        );
+    }
     tracing::info!("Loaded {} project(s):", config.projects.len());
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const PROJECT: &str = r#"
+name = "API"
+slug = "api"
+code = { url = "https://github.com/example/api" }
+image = [{ repository = "api", location = ".", tag = "latest" }]
+deployments = { namespace = "default", resources = ["deployment/api"] }
+"#;
+
+    fn paths(errors: &[ValidationError]) -> Vec<String> {
+        errors
+            .iter()
+            .map(|error| format!("{} {}", error.path, error.message))
+            .collect()
+    }
+
+    /// Config directory in the temp directory, unique to the test and removed on drop.
+    struct ConfigDir(PathBuf);
+
+    impl ConfigDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "build-hook-config-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            for (file, contents) in files {
+                fs::write(dir.join(file), contents).unwrap();
+            }
+            ConfigDir(dir)
+        }
+    }
+
+    impl Drop for ConfigDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        let config = format!(
+            "[app]\nregistry = \"registry.example.com\"\n[[projects]]{}",
+            PROJECT
+        );
+
+        assert!(check(&config).is_empty(), "{:?}", paths(&check(&config)));
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_path() {
+        let config = r#"
+[app]
+registry = ""
+base_path = "hooks/"
+body_timeout_secs = 0
+
+[[projects]]
+name = ""
+slug = "api"
+code = { url = "http://github.com/example/api" }
+image = [{ repository = "api", location = ".", tag = "latest" }]
+deployments = { namespace = "default", resources = ["deployment/api"] }
+
+[[projects]]
+name = "Web"
+slug = ""
+code = { url = "https://github.com/example/web" }
+image = [
+    { repository = "web", location = ".", tag = "latest" },
+    { repository = "Web", location = "/srv/web", tag = "{version}" },
+]
+deployments = { namespace = "default", resources = ["deployment/web"] }
+"#;
+
+        assert_eq!(
+            paths(&check(config)),
+            [
+                "app.registry must not be empty",
+                "app.base_path must start with `/` and not end with `/`, e.g. \"/hooks\"",
+                "app.body_timeout_secs must be at least 1",
+                "projects[api].name must not be empty",
+                "projects[api].code.url must be a valid HTTPS URL",
+                "projects[1].slug must not be empty",
+                "projects[1].image[1].repository `Web` must be lowercase letters and digits, separated by `.`, `_`, `__`, or `-`",
+                "projects[1].image[1].location must be a relative path",
+                "projects[1].image[1].tag unknown tag token `{version}`, expected one of: sha, short_sha, branch, date, timestamp",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_error_has_empty_path() {
+        let errors = check("[app\nregistry = 1");
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, "");
+        assert!(
+            errors[0].message.starts_with("Could not parse config:"),
+            "{}",
+            errors[0].message
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_slugs() {
+        let config = format!(
+            "[app]\nregistry = \"registry.example.com\"\n[[projects]]{}[[projects]]{}",
+            PROJECT,
+            PROJECT.replace("name = \"API\"", "name = \"API v2\"")
+        );
+
+        assert_eq!(
+            paths(&check(&config)),
+            ["projects[api].slug must be unique, used by API, API v2"]
+        );
+    }
+
+    #[test]
+    fn load_dir_merges_files() {
+        let dir = ConfigDir::new(
+            "merge",
+            &[
+                ("app.toml", "[app]\nregistry = \"registry.example.com\"\n"),
+                ("api.toml", PROJECT),
+                (
+                    "web.toml",
+                    &PROJECT.replace("slug = \"api\"", "slug = \"web\""),
+                ),
+                ("notes.txt", "not a config file"),
+            ],
+        );
+
+        let config = load_dir(&dir.0).unwrap();
+
+        assert_eq!(config.app.registry, "registry.example.com");
+        let slugs: Vec<&str> = config
+            .projects
+            .iter()
+            .map(|project| project.slug())
+            .collect();
+        assert_eq!(slugs, ["api", "web"]);
+        assert!(validate(&config).is_ok());
+    }
+
+    #[test]
+    fn load_dir_rejects_duplicate_slugs_across_files() {
+        let dir = ConfigDir::new(
+            "duplicate-slug",
+            &[
+                ("a.toml", "[app]\nregistry = \"registry.example.com\"\n"),
+                ("b.toml", PROJECT),
+                ("c.toml", &format!("[[projects]]{}", PROJECT)),
+            ],
+        );
+
+        assert_eq!(
+            load_dir(&dir.0).err(),
+            Some(format!(
+                "Project slug `api` is defined in both {} and {}!",
+                dir.0.join("b.toml").display(),
+                dir.0.join("c.toml").display()
+            ))
+        );
+    }
+
+    #[test]
+    fn load_dir_requires_one_app() {
+        let twice = ConfigDir::new(
+            "app-twice",
+            &[
+                ("a.toml", "[app]\nregistry = \"registry.example.com\"\n"),
+                ("b.toml", "[app]\nregistry = \"registry.example.com\"\n"),
+            ],
+        );
+        assert_eq!(
+            load_dir(&twice.0).err(),
+            Some(format!(
+                "`app` is defined in both {} and {}!",
+                twice.0.join("a.toml").display(),
+                twice.0.join("b.toml").display()
+            ))
+        );
+
+        let missing = ConfigDir::new("app-missing", &[("api.toml", PROJECT)]);
+        assert_eq!(
+            load_dir(&missing.0).err(),
+            Some(format!(
+                "No file in config directory {} defines `app`!",
+                missing.0.display()
+            ))
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::Serialize;
+
+use crate::buildx;
+use crate::command::SystemRunner;
+use crate::config::AppConfig;
+use crate::project::BuildBackend;
+
+/// Health details of the volumes and builders builds depend on.
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    /// Whether every disk has enough free space and every builder is running.
+    pub healthy: bool,
+    pub disks: Vec<DiskUsage>,
+    pub builders: Vec<BuilderStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskUsage {
+    pub path: PathBuf,
+    pub total_bytes: Option<u64>,
+    pub available_bytes: Option<u64>,
+    pub healthy: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BuilderStatus {
+    pub name: String,
+    pub healthy: bool,
+    pub error: Option<String>,
+}
+
+/// Check free space on the configured disk paths and the status of every buildx builder.
+pub fn collect(app: &AppConfig) -> Diagnostics {
+    let disks: Vec<DiskUsage> = app
+        .disk_paths
+        .iter()
+        .map(|path| disk_usage(path, app.min_free_percent))
+        .collect();
+
+    let builders: Vec<BuilderStatus> = match app.backend {
+        BuildBackend::Buildx => app
+            .builders()
+            .iter()
+            .map(|builder| {
+                let result = buildx::check_builder(&SystemRunner, &builder.name);
+                BuilderStatus {
+                    name: builder.name.clone(),
+                    healthy: result.is_ok(),
+                    error: result.err(),
+                }
+            })
+            .collect(),
+        BuildBackend::Kaniko => Vec::new(),
+    };
+
+    Diagnostics {
+        healthy: disks.iter().all(|disk| disk.healthy)
+            && builders.iter().all(|builder| builder.healthy),
+        disks,
+        builders,
+    }
+}
+
+fn disk_usage(path: &Path, min_free_percent: u8) -> DiskUsage {
+    match read_disk_usage(path) {
+        Ok((total_bytes, available_bytes)) => DiskUsage {
+            path: path.to_path_buf(),
+            total_bytes: Some(total_bytes),
+            available_bytes: Some(available_bytes),
+            healthy: available_bytes.saturating_mul(100)
+                >= total_bytes.saturating_mul(u64::from(min_free_percent)),
+            error: None,
+        },
+        Err(e) => DiskUsage {
+            path: path.to_path_buf(),
+            total_bytes: None,
+            available_bytes: None,
+            healthy: false,
+            error: Some(e),
+        },
+    }
+}
+
+/// Total and available bytes of the filesystem holding `path`, read from POSIX `df` output.
+fn read_disk_usage(path: &Path) -> Result<(u64, u64), String> {
+    let output = Command::new("df")
+        .args(["-P", "-k"])
+        .arg(path)
+        .output()
+        .map_err(|e| format!("Failed to run df: {}", e))?;
+
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout
+        .lines()
+        .nth(1)
+        .ok_or("Missing df output")?
+        .split_whitespace()
+        .collect();
+    let parse = |index: usize| {
+        fields
+            .get(index)
+            .and_then(|field| field.parse::<u64>().ok())
+            .map(|blocks| blocks * 1024)
+            .ok_or_else(|| format!("Could not parse df output: {}", stdout.trim()))
+    };
+
+    Ok((parse(1)?, parse(3)?))
+}
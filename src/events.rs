@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events buffered per project for slow subscribers before they start missing events.
+const EVENT_BUFFER: usize = 256;
+
+/// Structured progress of a build, published as it runs.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BuildEvent {
+    BuildStarted {
+        build_id: u64,
+    },
+    ImageStarted {
+        tag: String,
+    },
+    /// A line of build tool output for an image.
+    ImageProgress {
+        tag: String,
+        line: String,
+    },
+    ImageFinished {
+        tag: String,
+        success: bool,
+        digest: Option<String>,
+        duration_secs: f64,
+    },
+    RolloutStarted {
+        namespace: String,
+        resources: Vec<String>,
+    },
+    RolloutFinished {
+        namespace: String,
+        success: bool,
+    },
+    BuildFinished {
+        build_id: u64,
+        success: bool,
+        error: Option<String>,
+    },
+}
+
+/// Per-project broadcast channels for build events.
+pub struct EventBus {
+    channels: Mutex<HashMap<String, broadcast::Sender<BuildEvent>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Receive events published for `project` from now on.
+    pub fn subscribe(&self, project: &str) -> broadcast::Receiver<BuildEvent> {
+        self.sender(project).subscribe()
+    }
+
+    /// Publisher for events of a build of `project`.
+    pub fn publisher(&self, project: &str) -> EventPublisher {
+        EventPublisher {
+            sender: self.sender(project),
+        }
+    }
+
+    fn sender(&self, project: &str) -> broadcast::Sender<BuildEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        channels
+            .entry(project.to_string())
+            .or_insert_with(|| broadcast::channel(EVENT_BUFFER).0)
+            .clone()
+    }
+}
+
+/// Handle the build pipeline publishes its events through.
+#[derive(Clone)]
+pub struct EventPublisher {
+    sender: broadcast::Sender<BuildEvent>,
+}
+
+impl EventPublisher {
+    pub fn publish(&self, event: BuildEvent) {
+        // events are only delivered to current subscribers, having none is fine
+        let _ = self.sender.send(event);
+    }
+}
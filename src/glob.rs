@@ -0,0 +1,106 @@
+/// Match `text` against a glob `pattern`.
+///
+/// Supports `*` (any run of characters except `/`), `**` (any run of characters, including `/`)
+/// and `?` (any single character except `/`). All other characters match literally.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_from(&pattern, &text)
+}
+
+/// Match `text` against any of `patterns`.
+pub fn matches_any(patterns: &[String], text: &str) -> bool {
+    patterns.iter().any(|pattern| matches(pattern, text))
+}
+
+fn matches_from(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // let "**/" also match zero directories
+            if rest.first() == Some(&'/') && matches_from(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|skip| matches_from(rest, &text[skip..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for skip in 0..=text.len() {
+                if matches_from(rest, &text[skip..]) {
+                    return true;
+                }
+                if text.get(skip) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => match text.first() {
+            Some(c) if *c != '/' => matches_from(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(c) => text.first() == Some(c) && matches_from(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns() {
+        for (pattern, text, expected) in [
+            // literals
+            ("main", "main", true),
+            ("main", "mainline", false),
+            ("", "", true),
+            ("", "a", false),
+            // `*` stays within a path segment
+            ("*", "main", true),
+            ("*", "", true),
+            ("*", "feature/login", false),
+            ("feature/*", "feature/login", true),
+            ("feature/*", "feature/login/form", false),
+            ("feature/*", "feature/", true),
+            ("*.md", "README.md", true),
+            ("*.md", "docs/README.md", false),
+            ("release-*-rc", "release-1.2-rc", true),
+            // `**` crosses segments, and `**/` also matches none
+            ("**", "a/b/c", true),
+            ("**/x", "x", true),
+            ("**/x", "a/x", true),
+            ("**/x", "a/b/x", true),
+            ("**/x", "a/bx", false),
+            ("docs/**", "docs/a/b.md", true),
+            ("docs/**", "src/docs/a.md", false),
+            ("src/**/*.rs", "src/main.rs", true),
+            ("src/**/*.rs", "src/project/mod.rs", true),
+            ("src/**/*.rs", "src/project/mod.toml", false),
+            // `?` is one character other than `/`
+            ("v?", "v1", true),
+            ("v?", "v", false),
+            ("v?", "v10", false),
+            ("a?b", "a/b", false),
+            ("??", "é1", true),
+        ] {
+            assert_eq!(
+                matches(pattern, text),
+                expected,
+                "`{}` against `{}`",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn matches_any_pattern() {
+        let patterns = ["main".to_string(), "release/*".to_string()];
+
+        assert!(matches_any(&patterns, "main"));
+        assert!(matches_any(&patterns, "release/1.0"));
+        assert!(!matches_any(&patterns, "feature/login"));
+        assert!(!matches_any(&[], "main"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+
+/// Key an API client sends so a retried trigger doesn't start a second build.
+const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+
+/// Read the client's idempotency key from the request headers.
+pub fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(IDEMPOTENCY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string)
+}
+
+/// Builds started per project and idempotency key, remembered for a window.
+pub struct IdempotencyCache {
+    window: Duration,
+    builds: Mutex<HashMap<(String, String), (u64, Instant)>>,
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> Self {
+        IdempotencyCache {
+            window,
+            builds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Id of the build started for `key` of `project` within the window, if any.
+    pub fn build_for(&self, project: &str, key: &str) -> Option<u64> {
+        let mut builds = self.builds.lock().unwrap();
+        self.expire(&mut builds);
+        builds
+            .get(&(project.to_string(), key.to_string()))
+            .map(|(id, _)| *id)
+    }
+
+    /// Start a build for `key` of `project` unless one was already started, returning the
+    /// existing build's id as the error.
+    ///
+    /// `start` runs with the cache locked, so concurrent requests with the same key can't both
+    /// start a build. It returns the id of the started build, or `None` when none was started.
+    pub fn start<T>(
+        &self,
+        project: &str,
+        key: &str,
+        start: impl FnOnce() -> (T, Option<u64>),
+    ) -> Result<T, u64> {
+        let mut builds = self.builds.lock().unwrap();
+        self.expire(&mut builds);
+
+        let entry = (project.to_string(), key.to_string());
+        if let Some((id, _)) = builds.get(&entry) {
+            return Err(*id);
+        }
+
+        let (started, id) = start();
+        if let Some(id) = id {
+            builds.insert(entry, (id, Instant::now()));
+        }
+        Ok(started)
+    }
+
+    fn expire(&self, builds: &mut HashMap<(String, String), (u64, Instant)>) {
+        builds.retain(|_, (_, started_at)| started_at.elapsed() <= self.window);
+    }
+}
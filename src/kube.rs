@@ -1,39 +1,417 @@
+use std::path::PathBuf;
 use std::process::{Command, Output};
+use std::time::Duration;
 
-pub fn rollout_restart(namespace: &str, resources: &[String]) -> Result<(), String> {
+use crate::command::CommandRunner;
+
+/// Delay before the first retry, doubled after every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long one `kubectl rollout status` waits for a resource to finish rolling out.
+const ROLLOUT_STATUS_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Delay before re-checking the status of a rollout that failed, e.g. a pod that failed
+/// readiness while a dependency was still starting.
+const ROLLOUT_RECHECK_DELAY: Duration = Duration::from_secs(10);
+
+/// Error fragments from kubectl that indicate a temporary API server problem worth retrying.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "connection refused",
+    "connection reset",
+    "timeout",
+    "timed out",
+    "eof",
+    "the server is currently unable to handle the request",
+    "service unavailable",
+    "too many requests",
+];
+
+/// A resource to roll the new build out to.
+#[derive(Clone, Debug)]
+pub struct Rollout {
+    /// "<resource_type>/<resource_name>", e.g. "deployment/my-app".
+    pub resource: String,
+    pub action: RolloutAction,
+}
+
+/// How a resource picks up the new build.
+#[derive(Clone, Debug)]
+pub enum RolloutAction {
+    /// `kubectl rollout restart`, for resources pulling a moving tag.
+    Restart,
+    /// `kubectl set image` of `container` ("*" for all of them) to `image`.
+    SetImage { container: String, image: String },
+    /// `kubectl apply` of the manifest at `manifest`.
+    Apply { manifest: PathBuf },
+}
+
+impl Rollout {
+    fn verb(&self) -> &'static str {
+        match self.action {
+            RolloutAction::Restart => "restart",
+            RolloutAction::SetImage { .. } => "set the image of",
+            RolloutAction::Apply { .. } => "apply",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self.action {
+            RolloutAction::Restart => "kubectl rollout restart",
+            RolloutAction::SetImage { .. } => "kubectl set image",
+            RolloutAction::Apply { .. } => "kubectl apply",
+        }
+    }
+
+    fn command(&self, namespace: &str) -> Command {
+        let mut command = Command::new("kubectl");
+        match &self.action {
+            RolloutAction::Restart => {
+                command.args(["rollout", "restart", "-n", namespace, &self.resource]);
+            }
+            RolloutAction::SetImage { container, image } => {
+                command
+                    .args(["set", "image", "-n", namespace, &self.resource])
+                    .arg(format!("{}={}", container, image));
+            }
+            RolloutAction::Apply { manifest } => {
+                command.args(["apply", "-n", namespace, "-f"]).arg(manifest);
+            }
+        }
+        command
+    }
+}
+
+/// Roll the new build out to every resource, `parallelism` at a time.
+///
+/// With a parallelism of one, resources roll out in order and the first failure stops the rest.
+/// Otherwise all resources are attempted and the failures are reported together.
+///
+/// With `status_retries`, each rollout is also waited on with `kubectl rollout status`, which is
+/// re-checked up to that many times after failing before the rollout counts as failed.
+pub fn roll_out(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    rollouts: &[Rollout],
+    retries: u32,
+    status_retries: Option<u32>,
+    parallelism: usize,
+) -> Result<(), String> {
     tracing::info!(
-        "Starting rollout restarts in namespace `{}` for {} resource(s)",
+        "Starting rollouts in namespace `{}` for {} resource(s)",
         namespace,
-        resources.len()
+        rollouts.len()
     );
-    for resource in resources {
-        tracing::info!(
+
+    if parallelism <= 1 {
+        for rollout in rollouts {
+            roll_out_resource(runner, namespace, rollout, retries, status_retries)?;
+        }
+    } else {
+        let mut failures = Vec::new();
+        for batch in rollouts.chunks(parallelism) {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = batch
+                    .iter()
+                    .map(|rollout| {
+                        scope.spawn(move || {
+                            roll_out_resource(runner, namespace, rollout, retries, status_retries)
+                        })
+                    })
+                    .collect();
+                for handle in handles {
+                    match handle.join() {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => failures.push(e),
+                        Err(_) => failures.push("Rollout thread panicked".to_string()),
+                    }
+                }
+            });
+        }
+        if !failures.is_empty() {
+            return Err(failures.join("; "));
+        }
+    }
+
+    tracing::info!("Completed rollouts in namespace `{}`", namespace);
+    Ok(())
+}
+
+fn roll_out_resource(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    rollout: &Rollout,
+    retries: u32,
+    status_retries: Option<u32>,
+) -> Result<(), String> {
+    match &rollout.action {
+        RolloutAction::Restart => tracing::info!(
             "Restarting resource `{}` in namespace `{}`",
-            resource,
+            rollout.resource,
+            namespace
+        ),
+        RolloutAction::SetImage { container, image } => tracing::info!(
+            "Setting image of `{}` container `{}` in namespace `{}` to `{}`",
+            rollout.resource,
+            container,
+            namespace,
+            image
+        ),
+        RolloutAction::Apply { manifest } => tracing::info!(
+            "Applying `{}` for resource `{}` in namespace `{}`",
+            manifest.display(),
+            rollout.resource,
             namespace
+        ),
+    }
+    roll_out_with_retries(runner, namespace, rollout, retries)?;
+    match status_retries {
+        Some(status_retries) => {
+            wait_for_rollout(runner, namespace, &rollout.resource, status_retries)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Wait for `resource` to finish rolling out, re-checking its status up to `retries` times after
+/// a failure without rolling it out again.
+fn wait_for_rollout(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    resource: &str,
+    retries: u32,
+) -> Result<(), String> {
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        tracing::debug!(
+            "Waiting for rollout of `{}` in namespace `{}`, check {}/{}",
+            resource,
+            namespace,
+            attempt,
+            retries + 1
         );
+
+        let output = run_command_output(
+            runner,
+            Command::new("kubectl")
+                .args(["rollout", "status", "-n", namespace, resource])
+                .arg(format!("--timeout={}s", ROLLOUT_STATUS_TIMEOUT.as_secs())),
+            "kubectl rollout status",
+        )?;
+
+        if output.status.success() {
+            tracing::info!(
+                "Rollout of `{}` in namespace `{}` finished",
+                resource,
+                namespace
+            );
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if attempt > retries {
+            return Err(format!(
+                "Rollout of `{}` in namespace `{}` did not finish: {}",
+                resource, namespace, stderr
+            ));
+        }
+
+        tracing::warn!(
+            "Rollout of `{}` in namespace `{}` did not finish (check {}/{}), re-checking in {:?}",
+            resource,
+            namespace,
+            attempt,
+            retries + 1,
+            ROLLOUT_RECHECK_DELAY
+        );
+        std::thread::sleep(ROLLOUT_RECHECK_DELAY);
+    }
+}
+
+fn roll_out_with_retries(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    rollout: &Rollout,
+    retries: u32,
+) -> Result<(), String> {
+    let resource = &rollout.resource;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+        tracing::debug!(
+            "Rolling out `{}` in namespace `{}`, attempt {}/{}",
+            resource,
+            namespace,
+            attempt,
+            retries + 1
+        );
+
+        let output = run_command_output(
+            runner,
+            &mut rollout.command(namespace),
+            rollout.description(),
+        )?;
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if attempt > retries || !is_transient(&stderr) {
+            return Err(format!(
+                "Failed to {} `{}` in namespace `{}`: {}",
+                rollout.verb(),
+                resource,
+                namespace,
+                stderr
+            ));
+        }
+
+        tracing::warn!(
+            "Transient failure rolling out `{}` in namespace `{}` (attempt {}/{}), retrying in {:?}",
+            resource,
+            namespace,
+            attempt,
+            retries + 1,
+            backoff
+        );
+        std::thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
+
+fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    !stderr.contains("not found") && TRANSIENT_ERRORS.iter().any(|error| stderr.contains(error))
+}
+
+/// Check that every resource reports all of its desired replicas as ready.
+///
+/// Only resources exposing `status.readyReplicas`/`spec.replicas` are supported,
+/// i.e. deployments and statefulsets.
+pub fn check_ready(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    resources: &[String],
+) -> Result<(), String> {
+    let mut unhealthy = Vec::new();
+
+    for resource in resources {
         let output = run_command_output(
-            Command::new("kubectl").args(["rollout", "restart", "-n", namespace, resource]),
-            "kubectl rollout restart",
+            runner,
+            Command::new("kubectl").args([
+                "get",
+                "-n",
+                namespace,
+                resource,
+                "-o",
+                "jsonpath={.status.readyReplicas}/{.spec.replicas}",
+            ]),
+            "kubectl get",
         )?;
 
         if !output.status.success() {
             return Err(format!(
-                "Failed to restart `{}` in namespace `{}`: {}",
+                "Failed to get `{}` in namespace `{}`: {}",
                 resource,
                 namespace,
                 String::from_utf8_lossy(&output.stderr)
             ));
         }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (ready, desired) = parse_replicas(&stdout).ok_or_else(|| {
+            format!(
+                "Could not read replica counts for `{}` in namespace `{}`",
+                resource, namespace
+            )
+        })?;
+
+        tracing::debug!(
+            "Resource `{}` in namespace `{}` has {}/{} ready replica(s)",
+            resource,
+            namespace,
+            ready,
+            desired
+        );
+        if ready < desired {
+            unhealthy.push(format!("{} ({}/{} ready)", resource, ready, desired));
+        }
+    }
+
+    if !unhealthy.is_empty() {
+        return Err(format!(
+            "Unhealthy resource(s) in namespace `{}`: {}",
+            namespace,
+            unhealthy.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that every resource exists in the namespace, listing the missing ones in the error.
+pub fn check_exist(
+    runner: &dyn CommandRunner,
+    namespace: &str,
+    resources: &[String],
+) -> Result<(), String> {
+    let mut missing = Vec::new();
+
+    for resource in resources {
+        let output = run_command_output(
+            runner,
+            Command::new("kubectl").args(["get", "-n", namespace, resource, "-o", "name"]),
+            "kubectl get",
+        )?;
+
+        if output.status.success() {
+            continue;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.to_lowercase().contains("not found") {
+            missing.push(resource.as_str());
+        } else {
+            return Err(format!(
+                "Failed to get `{}` in namespace `{}`: {}",
+                resource, namespace, stderr
+            ));
+        }
+    }
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "Missing resource(s) in namespace `{}`: {}",
+            namespace,
+            missing.join(", ")
+        ));
     }
 
-    tracing::info!("Completed rollout restarts in namespace `{}`", namespace);
     Ok(())
 }
 
-fn run_command_output(command: &mut Command, description: &str) -> Result<Output, String> {
-    let output = command
-        .output()
+fn parse_replicas(output: &str) -> Option<(u32, u32)> {
+    let (ready, desired) = output.trim().split_once('/')?;
+    // readyReplicas is omitted entirely when no replicas are ready
+    let ready = if ready.is_empty() {
+        0
+    } else {
+        ready.parse().ok()?
+    };
+    let desired = desired.parse().ok()?;
+    Some((ready, desired))
+}
+
+fn run_command_output(
+    runner: &dyn CommandRunner,
+    command: &mut Command,
+    description: &str,
+) -> Result<Output, String> {
+    let output = runner
+        .output(command)
         .map_err(|err| format!("Failed to run {}: {}", description, err))?;
 
     if !output.status.success() && !output.stderr.is_empty() {
@@ -46,3 +424,170 @@ fn run_command_output(command: &mut Command, description: &str) -> Result<Output
 
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::mock::MockRunner;
+
+    fn restart(resource: &str) -> Rollout {
+        Rollout {
+            resource: resource.to_string(),
+            action: RolloutAction::Restart,
+        }
+    }
+
+    #[test]
+    fn roll_out_runs_each_action_and_waits_for_status() {
+        let runner = MockRunner::default();
+        let rollouts = [
+            restart("deployment/web"),
+            Rollout {
+                resource: "deployment/api".to_string(),
+                action: RolloutAction::SetImage {
+                    container: "*".to_string(),
+                    image: "registry.example.com/api:abc123".to_string(),
+                },
+            },
+            Rollout {
+                resource: "cronjob/cleanup".to_string(),
+                action: RolloutAction::Apply {
+                    manifest: PathBuf::from("/tmp/cleanup.yaml"),
+                },
+            },
+        ];
+
+        roll_out(&runner, "app", &rollouts, 0, Some(0), 1).unwrap();
+
+        assert_eq!(
+            runner.commands(),
+            [
+                "kubectl rollout restart -n app deployment/web",
+                "kubectl rollout status -n app deployment/web --timeout=300s",
+                "kubectl set image -n app deployment/api *=registry.example.com/api:abc123",
+                "kubectl rollout status -n app deployment/api --timeout=300s",
+                "kubectl apply -n app -f /tmp/cleanup.yaml",
+                "kubectl rollout status -n app cronjob/cleanup --timeout=300s",
+            ]
+        );
+    }
+
+    #[test]
+    fn roll_out_stops_at_first_failure_in_order() {
+        let runner = MockRunner::default();
+        runner.push_output(
+            1,
+            "",
+            "Error from server (NotFound): deployments.apps \"web\" not found",
+        );
+
+        let err = roll_out(
+            &runner,
+            "app",
+            &[restart("deployment/web"), restart("deployment/api")],
+            3,
+            None,
+            1,
+        )
+        .unwrap_err();
+
+        assert!(
+            err.contains("Failed to restart `deployment/web`"),
+            "{}",
+            err
+        );
+        // a missing resource isn't transient, so it's neither retried nor followed by the rest
+        assert_eq!(
+            runner.commands(),
+            ["kubectl rollout restart -n app deployment/web"]
+        );
+    }
+
+    #[test]
+    fn roll_out_reports_unfinished_rollout() {
+        let runner = MockRunner::default();
+        runner.push_output(0, "", "");
+        runner.push_output(
+            1,
+            "",
+            "error: deployment \"web\" exceeded its progress deadline",
+        );
+
+        let err =
+            roll_out(&runner, "app", &[restart("deployment/web")], 0, Some(0), 1).unwrap_err();
+
+        assert!(err.contains("did not finish"), "{}", err);
+        assert!(err.contains("progress deadline"), "{}", err);
+    }
+
+    #[test]
+    fn check_ready_reports_unhealthy_resources() {
+        let runner = MockRunner::default();
+        runner.push_output(0, "2/2", "");
+        // readyReplicas is left out when none are ready
+        runner.push_output(0, "/3", "");
+        let resources = ["deployment/web".to_string(), "statefulset/db".to_string()];
+
+        let err = check_ready(&runner, "app", &resources).unwrap_err();
+
+        assert_eq!(
+            err,
+            "Unhealthy resource(s) in namespace `app`: statefulset/db (0/3 ready)"
+        );
+        assert_eq!(
+            runner.commands(),
+            [
+                "kubectl get -n app deployment/web -o jsonpath={.status.readyReplicas}/{.spec.replicas}",
+                "kubectl get -n app statefulset/db -o jsonpath={.status.readyReplicas}/{.spec.replicas}",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_ready_accepts_ready_resources() {
+        let runner = MockRunner::default();
+        runner.push_output(0, "3/3", "");
+
+        check_ready(&runner, "app", &["deployment/web".to_string()]).unwrap();
+    }
+
+    #[test]
+    fn check_exist_lists_missing_resources() {
+        let runner = MockRunner::default();
+        runner.push_output(0, "deployment.apps/web", "");
+        runner.push_output(
+            1,
+            "",
+            "Error from server (NotFound): deployments.apps \"api\" not found",
+        );
+        let resources = ["deployment/web".to_string(), "deployment/api".to_string()];
+
+        let err = check_exist(&runner, "app", &resources).unwrap_err();
+
+        assert_eq!(
+            err,
+            "Missing resource(s) in namespace `app`: deployment/api"
+        );
+        assert_eq!(
+            runner.commands(),
+            [
+                "kubectl get -n app deployment/web -o name",
+                "kubectl get -n app deployment/api -o name",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_exist_fails_on_other_errors() {
+        let runner = MockRunner::default();
+        runner.push_output(
+            1,
+            "",
+            "error: You must be logged in to the server (Unauthorized)",
+        );
+
+        let err = check_exist(&runner, "app", &["deployment/web".to_string()]).unwrap_err();
+
+        assert!(err.starts_with("Failed to get `deployment/web`"), "{}", err);
+    }
+}
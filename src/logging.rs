@@ -0,0 +1,35 @@
+use crate::telemetry;
+
+use tracing_subscriber::{
+    EnvFilter, Registry, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+/// Build tool output is logged under its own `build_output` target.
+const DEFAULT_FILTER: &str = "build_hook=debug,build_output=info,tower_http=debug";
+
+/// Handle for swapping the log filter while the server is running.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Initialize tracing with a filter from `RUST_LOG`, falling back to the default filter. Spans
+/// are also exported as OTLP traces when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init() -> LogHandle {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let (filter, handle) = reload::Layer::new(filter);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(telemetry::layer())
+        .init();
+
+    handle
+}
+
+/// Replace the active log filter with a new directive, e.g. "build_hook=trace".
+pub fn set_filter(handle: &LogHandle, directive: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directive)
+        .map_err(|e| format!("Invalid log filter `{}`: {}", directive, e))?;
+    handle
+        .reload(filter)
+        .map_err(|e| format!("Could not apply log filter: {}", e))
+}
@@ -1,46 +1,180 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
+mod allowlist;
 mod api;
 mod auth;
 mod buildx;
+mod cancel;
+mod command;
 mod config;
+mod diag;
+mod events;
+mod glob;
+mod idempotency;
 mod kube;
+mod logging;
 mod project;
+mod proxy;
+mod schedule;
+mod shutdown;
+mod status;
+mod telemetry;
+mod webhook;
+
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use command::SystemRunner;
+use tracing::Instrument;
+
+/// Environment variable holding a URL notified when the service fails to start, e.g. to page
+/// someone when a deploy ships a broken config.
+const NOTIFY_WEBHOOK_URL: &str = "NOTIFY_WEBHOOK_URL";
+/// How long the startup failure notification may take before it's given up on.
+const NOTIFY_TIMEOUT_SECS: u64 = 10;
 
 #[tokio::main]
 async fn main() {
     // Initialize tracing
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "build_hook=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    let log_handle = logging::init();
 
     // read in env and config, exit if config is invalid in any way
     let config = match config::load() {
         Ok(cfg) => cfg,
-        Err(e) => {
-            tracing::error!("Could not load config: {}", e);
-            return;
+        Err(e) => fail_startup(format!("Could not load config: {}", e)),
+    };
+
+    // every span, including those of requests and builds, nests in the instance's
+    let instance_span = match &config.app.instance_name {
+        Some(name) => tracing::info_span!("instance", instance = %name),
+        None => tracing::Span::none(),
+    };
+    run(config, log_handle).instrument(instance_span).await;
+    telemetry::flush();
+    // a blocking build step that didn't stop when cancelled, e.g. a stalled clone, would keep
+    // the runtime from shutting down
+    std::process::exit(0);
+}
+
+/// Check the deployment targets, initialize the builders, and serve until shut down.
+async fn run(config: config::HookConfig, log_handle: logging::LogHandle) {
+    // a missing rollout target only fails after a full build, so catch it up front
+    for project in config.projects.values() {
+        if let Err(e) = project.check_resources() {
+            if config.app.require_resources {
+                fail_startup(format!(
+                    "Project `{}` can't be deployed: {}",
+                    project.slug(),
+                    e
+                ));
+            }
+            tracing::warn!(
+                "Project `{}` may fail to deploy: {}. Builds will fail at rollout until this is resolved.",
+                project.slug(),
+                e
+            );
         }
+    }
+
+    let status = match status::BuildStatus::load(config.app.status_file.clone()) {
+        Ok(status) => status,
+        Err(e) => fail_startup(format!("Could not load build status: {}", e)),
     };
 
-    let github_token = std::env::var("GITHUB_TOKEN").unwrap_or_default();
+    let github_token = match project::load_github_token() {
+        Ok(token) => token,
+        Err(e) => fail_startup(format!("Could not load GitHub token: {}", e)),
+    };
     if github_token.is_empty() {
         tracing::warn!(
-            "No GITHUB_TOKEN environment variable set, git operations may fail if authentication is required."
+            "No GITHUB_TOKEN or GITHUB_TOKEN_FILE set, git operations may fail if authentication is required."
         );
     }
 
     // Initialize buildx builder
-    if let Err(e) = buildx::initialize() {
-        tracing::warn!(
-            "Failed to initialize buildx builder: {}. Builds will fail until this is resolved.",
-            e
-        );
+    if config.app.backend == project::BuildBackend::Buildx {
+        let bootstrap_timeout = Duration::from_secs(config.app.bootstrap_timeout_secs);
+        match buildx::initialize(
+            &SystemRunner,
+            &config.app.builders(),
+            bootstrap_timeout,
+            config
+                .app
+                .builder_ready_timeout_secs
+                .map(Duration::from_secs),
+            config.app.registry_secret.as_ref(),
+        ) {
+            Ok(()) => {}
+            // a builder stuck bootstrapping won't recover on its own, so don't serve without it
+            Err(buildx::InitError::Timeout(e)) => {
+                fail_startup(format!("Failed to initialize buildx builder: {}", e))
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to initialize buildx builder: {}. Builds will fail until this is resolved.",
+                    e
+                );
+            }
+        }
+
+        if let Some(secret) = config.app.registry_secret.clone() {
+            tokio::spawn(
+                buildx::refresh_registry_credentials(&SystemRunner, secret).in_current_span(),
+            );
+        }
+        if let Some(prune) = config.app.prune.clone() {
+            let builders = config.app.builders().into_iter().map(|b| b.name).collect();
+            tokio::spawn(
+                buildx::prune_periodically(&SystemRunner, builders, prune).in_current_span(),
+            );
+        }
     }
 
-    api::start(config, github_token).await;
+    if let Err(e) = shutdown::install() {
+        tracing::warn!("{}. Running builds won't be stopped on shutdown.", e);
+    }
+
+    api::start(config, status, github_token, log_handle).await;
+}
+
+/// Log why the service can't start, notify `NOTIFY_WEBHOOK_URL` if set, and exit with status 1.
+fn fail_startup(message: String) -> ! {
+    tracing::error!("{}", message);
+    if let Ok(url) = std::env::var(NOTIFY_WEBHOOK_URL) {
+        notify_startup_failure(&url, &message);
+    }
+    std::process::exit(1);
+}
+
+/// POST the startup failure as JSON to `url`. Failures are only logged, the service exits either
+/// way.
+fn notify_startup_failure(url: &str, message: &str) {
+    let body = serde_json::json!({
+        "event": "startup_failed",
+        "error": message,
+    });
+    let output = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--max-time",
+            &NOTIFY_TIMEOUT_SECS.to_string(),
+            "--header",
+            "Content-Type: application/json",
+            "--data",
+        ])
+        .arg(body.to_string())
+        .arg(url)
+        .stdout(Stdio::null())
+        .output();
+    match output {
+        Ok(output) if output.status.success() => {
+            tracing::info!("Notified {} of the startup failure", NOTIFY_WEBHOOK_URL)
+        }
+        Ok(output) => tracing::warn!(
+            "Failed to notify {} of the startup failure: {}",
+            NOTIFY_WEBHOOK_URL,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) => tracing::warn!("Failed to run curl: {}", e),
+    }
 }
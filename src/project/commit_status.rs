@@ -0,0 +1,151 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Context the statuses are reported under, shown next to the check in GitHub.
+const STATUS_CONTEXT: &str = "build-hook";
+/// Longest description GitHub accepts for a commit status.
+const MAX_DESCRIPTION_LEN: usize = 140;
+
+/// State of a commit status.
+#[derive(Clone, Copy, Debug)]
+pub enum CommitState {
+    Pending,
+    Success,
+    Failure,
+}
+
+impl CommitState {
+    fn as_str(self) -> &'static str {
+        match self {
+            CommitState::Pending => "pending",
+            CommitState::Success => "success",
+            CommitState::Failure => "failure",
+        }
+    }
+}
+
+/// Commit a build's statuses are reported on, through GitHub's commit status API.
+pub struct CommitStatus<'a> {
+    owner: String,
+    repo: String,
+    sha: String,
+    github_token: &'a str,
+    env: &'a [(String, String)],
+}
+
+impl<'a> CommitStatus<'a> {
+    pub fn new(
+        url: &str,
+        sha: &str,
+        github_token: &'a str,
+        env: &'a [(String, String)],
+    ) -> Result<Self, String> {
+        let (owner, repo) =
+            github_repo(url).ok_or_else(|| format!("`{}` is not a GitHub repository URL", url))?;
+        Ok(CommitStatus {
+            owner,
+            repo,
+            sha: sha.to_string(),
+            github_token,
+            env,
+        })
+    }
+
+    /// Set the commit's status. Failures are only logged, they never fail the build.
+    pub fn report(&self, state: CommitState, description: &str) {
+        if let Err(e) = self.post(state, description) {
+            tracing::warn!(
+                "Failed to report `{}` status for {}/{}@{}: {}",
+                state.as_str(),
+                self.owner,
+                self.repo,
+                self.sha,
+                e
+            );
+        }
+    }
+
+    fn post(&self, state: CommitState, description: &str) -> Result<(), String> {
+        let body = serde_json::json!({
+            "state": state.as_str(),
+            "description": truncate(description, MAX_DESCRIPTION_LEN),
+            "context": STATUS_CONTEXT,
+        });
+        let url = format!(
+            "https://api.github.com/repos/{}/{}/statuses/{}",
+            self.owner, self.repo, self.sha
+        );
+
+        // the token goes through curl's config on stdin, so it never shows in the process list
+        let mut child = Command::new("curl")
+            .args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--config",
+                "-",
+                "--header",
+                "Accept: application/vnd.github+json",
+                "--data",
+            ])
+            .arg(body.to_string())
+            .arg(&url)
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to run curl: {}", e))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(
+                stdin,
+                "header = \"Authorization: Bearer {}\"",
+                self.github_token
+            )
+            .map_err(|e| format!("Failed to pass credentials to curl: {}", e))?;
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to wait for curl: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+
+        tracing::debug!(
+            "Reported `{}` status for {}/{}@{}",
+            state.as_str(),
+            self.owner,
+            self.repo,
+            self.sha
+        );
+        Ok(())
+    }
+}
+
+/// Owner and name of a repository from its `https://github.com/{owner}/{repo}` URL.
+pub fn github_repo(url: &str) -> Option<(String, String)> {
+    let rest = url.strip_prefix("https://")?;
+    // drop credentials embedded in the URL
+    let rest = rest
+        .rsplit_once('@')
+        .map_or(rest, |(_, host_path)| host_path);
+    let path = rest.strip_prefix("github.com/")?;
+    let mut parts = path.trim_end_matches('/').split('/');
+    let owner = parts.next().filter(|owner| !owner.is_empty())?;
+    let repo = parts.next()?;
+    let repo = repo.strip_suffix(".git").unwrap_or(repo);
+    if repo.is_empty() || parts.next().is_some() {
+        return None;
+    }
+    Some((owner.to_string(), repo.to_string()))
+}
+
+fn truncate(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+    let mut truncated: String = text.chars().take(max_len - 3).collect();
+    truncated.push_str("...");
+    truncated
+}
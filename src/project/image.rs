@@ -1,12 +1,220 @@
-use std::path::Path;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use super::reference;
+use crate::cancel::CancelHandle;
+use crate::command::{CommandRunner, Process};
+use crate::events::{BuildEvent, EventPublisher};
+
+/// Longest line of build output read at once, longer lines are split so a runaway line can't
+/// exhaust memory.
+const MAX_LINE_BYTES: u64 = 64 * 1024;
+/// Log target of build tool output, so it can be filtered apart from the service's own logs.
+const OUTPUT_TARGET: &str = "build_output";
+/// Heads captured output whose earlier lines were dropped to stay within the limit.
+const TRUNCATED_MARKER: &str = "[output truncated]";
+
+// Kaniko executor binary, as shipped in the `gcr.io/kaniko-project/executor` image
+const KANIKO_EXECUTOR: &str = "/kaniko/executor";
+
+/// Tool used to build container images.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildBackend {
+    /// Docker Buildx connected to the remote BuildKit daemon.
+    #[default]
+    Buildx,
+    /// Kaniko executor, for clusters that can't run privileged BuildKit pods.
+    Kaniko,
+}
+
+impl BuildBackend {
+    fn spawn(
+        self,
+        runner: &dyn CommandRunner,
+        build: &BuildImage,
+    ) -> Result<Box<dyn Process>, String> {
+        match self {
+            BuildBackend::Buildx => spawn_buildx(runner, build),
+            BuildBackend::Kaniko => spawn_kaniko(runner, build),
+        }
+    }
+}
+
+/// Network mode of `RUN` steps during a build.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// BuildKit's default sandboxed network.
+    #[default]
+    Default,
+    /// The BuildKit host's network, e.g. to reach an internal mirror.
+    Host,
+    /// No network access.
+    None,
+}
+
+impl NetworkMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            NetworkMode::Default => "default",
+            NetworkMode::Host => "host",
+            NetworkMode::None => "none",
+        }
+    }
+}
+
+/// Entitlements buildx can grant a build with `--allow`.
+pub const ENTITLEMENTS: &[&str] = &["network.host", "security.insecure"];
+
+/// Progress output style of `docker buildx build`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildProgress {
+    /// Let buildx pick the style from whether it writes to a terminal.
+    Auto,
+    /// One line per step, without terminal control sequences, for captured logs.
+    #[default]
+    Plain,
+    /// The interactive terminal display.
+    Tty,
+}
+
+impl BuildProgress {
+    fn as_str(self) -> &'static str {
+        match self {
+            BuildProgress::Auto => "auto",
+            BuildProgress::Plain => "plain",
+            BuildProgress::Tty => "tty",
+        }
+    }
+}
+
+/// How the build tool's progress output is written to the service's log.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildLogMode {
+    /// Every line, as it's written.
+    #[default]
+    Full,
+    /// The last lines once a build succeeded, every line once it failed.
+    Tail,
+    /// Nothing once a build succeeded, every line once it failed.
+    OnFailure,
+}
+
+/// What happens to the remaining images of a build once one fails.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFailurePolicy {
+    /// Stop at the failed image, leaving later images unbuilt.
+    #[default]
+    Abort,
+    /// Build every image whose dependencies built, and fail with every failure at the end.
+    Continue,
+}
+
+/// Where a built image is exported to.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageOutput {
+    /// Push to the image's registry.
+    #[default]
+    Registry,
+    /// Export an OCI layout tarball to a local path, e.g. for air-gapped transfers.
+    Oci,
+    /// Load into the local Docker daemon.
+    Docker,
+}
 
 pub struct BuildImage {
     pub tag: String,
+    /// Further image references pointing at the same built image.
+    pub additional_tags: Vec<String>,
     pub dockerfile_path: String,
     pub context_dir: String,
+    pub push: bool,
+    /// Where the built image is exported to.
+    pub output: ImageOutput,
+    /// Path the image is exported to with the `oci` output.
+    pub output_dest: Option<String>,
+    /// Name of the buildx builder to build with.
+    pub builder: String,
+    /// BuildKit secret ids mapped to the environment variables holding their values.
+    pub secrets: HashMap<String, String>,
+    /// Network mode for `RUN` steps.
+    pub network: NetworkMode,
+    /// Entitlements granted to the build, e.g. "security.insecure".
+    pub entitlements: Vec<String>,
+    /// Extra `host:ip` entries for `/etc/hosts` of `RUN` steps.
+    pub add_hosts: Vec<String>,
+    /// `.dockerignore` patterns left out of the build context on top of the repository's own.
+    pub exclude: Vec<String>,
+    /// Fail instead of pushing when a tag already exists in the registry.
+    pub immutable_tags: bool,
+    /// Registry reference the build cache is shared through, e.g. "registry.example.com/cache:web".
+    /// The cache is inlined into the image itself when unset.
+    pub cache_ref: Option<String>,
+    /// Labels set on the image, e.g. the webhook delivery that triggered the build.
+    pub labels: Vec<(String, String)>,
+    /// Fail the build when the pushed image is larger than this many bytes.
+    pub max_image_bytes: Option<u64>,
+    /// Proxy environment variables for the build process and its `RUN` steps.
+    pub proxy_env: Vec<(String, String)>,
+    /// CA bundle environment variables for the build process itself, e.g. `SSL_CERT_FILE`.
+    pub ca_env: Vec<(String, String)>,
+    /// Extra environment variables for the build process.
+    pub env: HashMap<String, String>,
+    /// Whether the build process starts from a clean environment.
+    pub clear_env: bool,
+    /// Progress output style requested from buildx.
+    pub progress: BuildProgress,
+    /// How the build output is logged.
+    pub log_mode: BuildLogMode,
+    /// Lines of output logged after a successful build in the tail mode.
+    pub log_tail_lines: usize,
+    /// Most bytes of output kept in memory outside the full log mode.
+    pub max_captured_bytes: usize,
+    /// File the build tool writes image metadata (e.g. the pushed digest) to.
+    pub metadata_file: PathBuf,
+    /// Positions of the images of the same build this one is built from, which must build first.
+    pub depends_on: Vec<usize>,
 }
 
-pub fn build_images(mut image_builds: Vec<BuildImage>, repo_dest: String) -> Result<(), String> {
+/// Result of a successful image build.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuiltImage {
+    /// Full image reference that was built.
+    pub tag: String,
+    /// Immutable digest of the pushed image, e.g. "sha256:...".
+    pub digest: Option<String>,
+    /// Time taken to build (and push) the image, in seconds.
+    pub duration_secs: f64,
+}
+
+/// Build every image in order, calling `on_built` with each image's position and result once it's
+/// built.
+///
+/// With the `continue` policy, images are still built after one fails, except those depending on
+/// a failed image, and the build fails with every failure once all were attempted.
+pub fn build_images(
+    runner: &dyn CommandRunner,
+    backend: BuildBackend,
+    image_builds: Vec<BuildImage>,
+    on_failure: ImageFailurePolicy,
+    events: &EventPublisher,
+    cancel: &CancelHandle,
+    on_built: &mut dyn FnMut(usize, &BuiltImage) -> Result<(), String>,
+) -> Result<Vec<BuiltImage>, String> {
+    if image_builds.is_empty() {
+        return Err("project.image must have at least one entry!".to_string());
+    }
+
     for build in &image_builds {
         if !Path::new(&build.dockerfile_path).is_file() {
             return Err(format!(
@@ -14,76 +222,556 @@ pub fn build_images(mut image_builds: Vec<BuildImage>, repo_dest: String) -> Res
                 build.tag, build.dockerfile_path
             ));
         }
-    }
 
-    let first_build = image_builds
-        .drain(0..1)
-        .next()
-        .ok_or_else(|| "project.image must have at least one entry!".to_string())?;
+        // only the variable names are reported, never the values
+        for (id, env) in &build.secrets {
+            if std::env::var_os(env).is_none() {
+                return Err(format!(
+                    "Secret `{}` for {} requires environment variable {} to be set",
+                    id, build.tag, env
+                ));
+            }
+        }
+    }
 
-    tracing::info!(
-        "building {} using {}",
-        first_build.tag,
-        first_build.dockerfile_path
-    );
+    // checked before anything is built, so a clash doesn't leave other images pushed
+    for build in &image_builds {
+        if build.immutable_tags && build.output == ImageOutput::Registry && build.push {
+            check_tags_absent(runner, build)?;
+        }
+    }
 
-    let mut child = spawn_build(
-        &first_build.context_dir,
-        &first_build.tag,
-        &first_build.dockerfile_path,
-    )?;
-    verify_build_started(&mut child)?;
+    let mut built = Vec::new();
+    let mut failed = vec![false; image_builds.len()];
+    let mut failures = Vec::new();
+    for (position, build) in image_builds.iter().enumerate() {
+        cancel.check()?;
+        if let Some(&dependency) = build.depends_on.iter().find(|&&dep| failed[dep]) {
+            failed[position] = true;
+            failures.push(format!(
+                "{} not built, {} failed",
+                build.tag, image_builds[dependency].tag
+            ));
+            continue;
+        }
 
-    handle_build_completion(child, &first_build.tag)?;
+        match build_image(runner, backend, build, events, cancel) {
+            Ok(image) => {
+                on_built(position, &image)?;
+                built.push(image);
+            }
+            Err(e) if on_failure == ImageFailurePolicy::Continue => {
+                tracing::error!("{}, building the remaining images", e);
+                failed[position] = true;
+                failures.push(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
 
-    for build in image_builds {
-        tracing::info!("building {} using {}", build.tag, build.dockerfile_path);
-        let mut next_child = spawn_build(&build.context_dir, &build.tag, &build.dockerfile_path)?;
-        verify_build_started(&mut next_child)
-            .map_err(|e| format!("Build process for {} exited immediately: {}", build.tag, e))?;
-        handle_build_completion(next_child, &build.tag)?;
+    if !failures.is_empty() {
+        return Err(format!(
+            "{} of {} image(s) failed: {}",
+            failures.len(),
+            image_builds.len(),
+            failures.join("; ")
+        ));
     }
+    Ok(built)
+}
 
-    if let Err(e) = std::fs::remove_dir_all(&repo_dest) {
-        tracing::warn!(
-            "Failed to remove temporary repository directory {}: {}",
-            repo_dest,
-            e
+/// An ignore file of the build context with an image's `exclude` patterns merged in, restored to
+/// its original content when dropped.
+struct MergedIgnoreFile {
+    path: PathBuf,
+    /// Content before the patterns were merged in, `None` when there was no ignore file.
+    original: Option<Vec<u8>>,
+}
+
+impl MergedIgnoreFile {
+    fn write(backend: BuildBackend, build: &BuildImage) -> Result<Option<Self>, String> {
+        if build.exclude.is_empty() {
+            return Ok(None);
+        }
+
+        // BuildKit prefers an ignore file named after the Dockerfile over the context's
+        let dockerfile_ignore = PathBuf::from(format!("{}.dockerignore", build.dockerfile_path));
+        let path = if backend == BuildBackend::Buildx && dockerfile_ignore.is_file() {
+            dockerfile_ignore
+        } else {
+            Path::new(&build.context_dir).join(".dockerignore")
+        };
+        let original = match std::fs::read(&path) {
+            Ok(content) => Some(content),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        let mut content = original.clone().unwrap_or_default();
+        if !content.is_empty() && !content.ends_with(b"\n") {
+            content.push(b'\n');
+        }
+        content.extend_from_slice(b"# image exclude patterns\n");
+        for pattern in &build.exclude {
+            content.extend_from_slice(pattern.as_bytes());
+            content.push(b'\n');
+        }
+        std::fs::write(&path, content)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        tracing::debug!(
+            "Excluding {} pattern(s) from the context of {} with {}",
+            build.exclude.len(),
+            build.tag,
+            path.display()
         );
+        Ok(Some(MergedIgnoreFile { path, original }))
+    }
+}
+
+impl Drop for MergedIgnoreFile {
+    fn drop(&mut self) {
+        let result = match &self.original {
+            Some(original) => std::fs::write(&self.path, original),
+            None => std::fs::remove_file(&self.path),
+        };
+        if let Err(e) = result {
+            tracing::warn!("Failed to restore {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Build a single image, waiting for the build process to finish.
+fn build_image(
+    runner: &dyn CommandRunner,
+    backend: BuildBackend,
+    build: &BuildImage,
+    events: &EventPublisher,
+    cancel: &CancelHandle,
+) -> Result<BuiltImage, String> {
+    tracing::info!("building {} using {}", build.tag, build.dockerfile_path);
+    events.publish(BuildEvent::ImageStarted {
+        tag: build.tag.clone(),
+    });
+
+    // restored once the build is done, so images sharing a context don't share exclusions
+    let _ignore_file = MergedIgnoreFile::write(backend, build)?;
+    let started = Instant::now();
+    let mut child = backend.spawn(runner, build)?;
+    cancel.set_process(child.id());
+    let progress = forward_progress(child.as_mut(), build, events);
+    let result = verify_build_started(child.as_mut())
+        .map_err(|e| format!("Build process for {} exited immediately: {}", build.tag, e))
+        .and_then(|()| handle_build_completion(child, build, started));
+    cancel.set_process(None);
+    if let Some(progress) = progress {
+        let captured = progress.join().unwrap_or_default();
+        log_captured_output(build, captured, result.is_ok());
+    }
+    let result = match build.max_image_bytes {
+        Some(max_bytes) => result.and_then(|built| {
+            check_image_size(runner, build, max_bytes)?;
+            Ok(built)
+        }),
+        None => result,
+    };
+
+    let (digest, duration_secs) = match &result {
+        Ok(built) => (built.digest.clone(), built.duration_secs),
+        Err(_) => (None, started.elapsed().as_secs_f64()),
+    };
+    events.publish(BuildEvent::ImageFinished {
+        tag: build.tag.clone(),
+        success: result.is_ok(),
+        digest,
+        duration_secs,
+    });
+    result
+}
+
+/// Check that none of the image's tags exist in the registry yet.
+fn check_tags_absent(runner: &dyn CommandRunner, build: &BuildImage) -> Result<(), String> {
+    for tag in std::iter::once(&build.tag).chain(&build.additional_tags) {
+        let output = runner
+            .output(std::process::Command::new("docker").args([
+                "buildx",
+                "imagetools",
+                "inspect",
+                "--builder",
+                &build.builder,
+                tag,
+            ]))
+            .map_err(|e| format!("Failed to run docker buildx imagetools inspect: {}", e))?;
+
+        if output.status.success() {
+            return Err(format!(
+                "Tag {} already exists, trigger with `force=true` to overwrite it",
+                tag
+            ));
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !stderr.to_lowercase().contains("not found") {
+            return Err(format!(
+                "Failed to check whether tag {} exists: {}",
+                tag,
+                stderr.trim()
+            ));
+        }
     }
 
     Ok(())
 }
 
-fn spawn_build(
-    context_dir: &str,
-    image_tag: &str,
-    dockerfile_path: &str,
-) -> Result<std::process::Child, String> {
-    std::process::Command::new("docker")
+/// Check the pushed image isn't larger than `max_bytes`. The pushed tag is left in place, as
+/// registries don't offer a common way to delete it.
+fn check_image_size(
+    runner: &dyn CommandRunner,
+    build: &BuildImage,
+    max_bytes: u64,
+) -> Result<(), String> {
+    let size = image_size(runner, &build.builder, &build.tag)?;
+    tracing::debug!("Image {} is {} bytes", build.tag, size);
+    if size > max_bytes {
+        return Err(format!(
+            "Image {} is {} bytes, over the project's `max_image_bytes` of {}; the pushed tag wasn't removed",
+            build.tag, size, max_bytes
+        ));
+    }
+    Ok(())
+}
+
+/// Size in bytes of the image at `reference`: its config and compressed layers. For a
+/// multi-platform image, the size of its largest platform image.
+fn image_size(runner: &dyn CommandRunner, builder: &str, reference: &str) -> Result<u64, String> {
+    let output = runner
+        .output(std::process::Command::new("docker").args([
+            "buildx",
+            "imagetools",
+            "inspect",
+            "--builder",
+            builder,
+            "--raw",
+            reference,
+        ]))
+        .map_err(|e| format!("Failed to run docker buildx imagetools inspect: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to inspect image {}: {}",
+            reference,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let manifest: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("Failed to parse manifest of {}: {}", reference, e))?;
+
+    if let Some(manifests) = manifest.get("manifests").and_then(|m| m.as_array()) {
+        let name = reference::image_name(reference);
+        let mut largest = 0;
+        for platform in manifests {
+            // attestations are attached as manifests of their own, they aren't images
+            let attestation = platform
+                .pointer("/annotations/vnd.docker.reference.type")
+                .and_then(|kind| kind.as_str())
+                == Some("attestation-manifest");
+            let Some(digest) = platform.get("digest").and_then(|d| d.as_str()) else {
+                continue;
+            };
+            if !attestation {
+                let size = image_size(runner, builder, &format!("{}@{}", name, digest))?;
+                largest = largest.max(size);
+            }
+        }
+        return Ok(largest);
+    }
+
+    let blob_size = |blob: &serde_json::Value| blob.get("size").and_then(|s| s.as_u64());
+    let config = manifest.get("config").and_then(blob_size).unwrap_or(0);
+    let layers: u64 = manifest
+        .get("layers")
+        .and_then(|layers| layers.as_array())
+        .map(|layers| layers.iter().filter_map(blob_size).sum())
+        .unwrap_or(0);
+    Ok(config + layers)
+}
+
+/// Relay the build tool's progress output as image progress events, and to the service's log in
+/// the full log mode. In the other modes the output is captured and returned instead.
+fn forward_progress(
+    child: &mut dyn Process,
+    build: &BuildImage,
+    events: &EventPublisher,
+) -> Option<JoinHandle<CapturedOutput>> {
+    let stderr = child.take_stderr()?;
+    let tag = build.tag.clone();
+    let log_mode = build.log_mode;
+    let max_captured_bytes = build.max_captured_bytes;
+    let events = events.clone();
+    Some(std::thread::spawn(move || {
+        let mut captured = CapturedOutput::default();
+        let mut reader = BufReader::new(stderr);
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match (&mut reader)
+                .take(MAX_LINE_BYTES)
+                .read_until(b'\n', &mut buf)
+            {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let line = String::from_utf8_lossy(&buf)
+                .trim_end_matches(['\n', '\r'])
+                .to_string();
+            if log_mode == BuildLogMode::Full {
+                tracing::info!(target: OUTPUT_TARGET, tag = %tag, "{}", line);
+            } else {
+                captured.push(line.clone(), max_captured_bytes);
+            }
+            events.publish(BuildEvent::ImageProgress {
+                tag: tag.clone(),
+                line,
+            });
+        }
+        captured
+    }))
+}
+
+/// Build output of one image captured outside the full log mode, keeping the latest lines within
+/// a byte limit.
+#[derive(Default)]
+struct CapturedOutput {
+    lines: VecDeque<String>,
+    bytes: usize,
+    /// Whether earlier lines were dropped to stay within the limit.
+    truncated: bool,
+}
+
+impl CapturedOutput {
+    fn push(&mut self, line: String, max_bytes: usize) {
+        self.bytes += line.len();
+        self.lines.push_back(line);
+        while self.bytes > max_bytes {
+            let Some(dropped) = self.lines.pop_front() else {
+                break;
+            };
+            self.bytes -= dropped.len();
+            self.truncated = true;
+        }
+    }
+
+    /// The captured lines, headed by a marker when earlier lines were dropped.
+    fn into_lines(self) -> Vec<String> {
+        let mut lines = Vec::from(self.lines);
+        if self.truncated {
+            lines.insert(0, TRUNCATED_MARKER.to_string());
+        }
+        lines
+    }
+}
+
+/// Log the output captured outside the full log mode, depending on how the build went: as info
+/// after a successful build, as warnings after a failed one.
+fn log_captured_output(build: &BuildImage, captured: CapturedOutput, success: bool) {
+    let log = |message: &str| {
+        if success {
+            tracing::info!(target: OUTPUT_TARGET, tag = %build.tag, "{}", message);
+        } else {
+            tracing::warn!(target: OUTPUT_TARGET, tag = %build.tag, "{}", message);
+        }
+    };
+    let truncated = captured.truncated;
+    let captured_lines = captured.into_lines();
+    let lines = match build.log_mode {
+        BuildLogMode::Full => return,
+        _ if !success => &captured_lines[..],
+        BuildLogMode::Tail => {
+            &captured_lines[captured_lines.len().saturating_sub(build.log_tail_lines)..]
+        }
+        BuildLogMode::OnFailure => return,
+    };
+
+    if lines.len() < captured_lines.len() {
+        log(&format!(
+            "[{} of {} lines of build output for {}]",
+            lines.len(),
+            captured_lines.len(),
+            build.tag
+        ));
+    }
+    // the marker only heads the logged lines when no earlier lines were left out anyway
+    if truncated && lines.len() == captured_lines.len() {
+        tracing::warn!(
+            "Build output for `{}` exceeded app.max_captured_output_bytes, earlier lines dropped",
+            build.tag
+        );
+    }
+    for line in lines {
+        log(line);
+    }
+}
+
+fn spawn_buildx(
+    runner: &dyn CommandRunner,
+    build: &BuildImage,
+) -> Result<Box<dyn Process>, String> {
+    let output = match build.output {
+        ImageOutput::Registry if build.push => "--push".to_string(),
+        // Without a push, the result is only kept in the build cache so the build can still be verified
+        ImageOutput::Registry => "--output=type=cacheonly".to_string(),
+        ImageOutput::Oci => format!(
+            "--output=type=oci,dest={}",
+            build.output_dest.as_deref().unwrap_or_default()
+        ),
+        ImageOutput::Docker => "--load".to_string(),
+    };
+
+    // a scoped cache is exported in full to its own reference, otherwise the image carries it
+    let (cache_from, cache_to) = match &build.cache_ref {
+        Some(cache_ref) => (
+            format!("type=registry,ref={}", cache_ref),
+            format!("type=registry,ref={},mode=max", cache_ref),
+        ),
+        None => (
+            format!("type=registry,ref={}", build.tag),
+            "type=inline".to_string(),
+        ),
+    };
+
+    let mut command = std::process::Command::new("docker");
+    command
         .args([
             "buildx",
             "build",
             "--builder",
-            "builder",
+            &build.builder,
             "--cache-from",
-            &format!("type=registry,ref={}", image_tag),
+            &cache_from,
             "--cache-to",
-            "type=inline",
-            "--push",
+            &cache_to,
+            &output,
             "-t",
-            image_tag,
+            &build.tag,
             "--file",
-            dockerfile_path,
-            context_dir,
+            &build.dockerfile_path,
+            "--progress",
+            build.progress.as_str(),
         ])
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .spawn()
+        .arg("--metadata-file")
+        .arg(&build.metadata_file);
+    for tag in &build.additional_tags {
+        command.arg("-t").arg(tag);
+    }
+    for (name, value) in &build.labels {
+        command.arg("--label").arg(format!("{}={}", name, value));
+    }
+
+    let mut secrets: Vec<_> = build.secrets.iter().collect();
+    secrets.sort();
+    for (id, env) in secrets {
+        command
+            .arg("--secret")
+            .arg(format!("id={},env={}", id, env));
+    }
+
+    command.arg(format!("--network={}", build.network.as_str()));
+    // host networking is an entitlement buildx has to request explicitly
+    let mut entitlements: Vec<&str> = build.entitlements.iter().map(String::as_str).collect();
+    if build.network == NetworkMode::Host {
+        entitlements.push("network.host");
+    }
+    entitlements.sort();
+    entitlements.dedup();
+    for entitlement in entitlements {
+        command.args(["--allow", entitlement]);
+    }
+    for host in &build.add_hosts {
+        command.arg("--add-host").arg(host);
+    }
+
+    command.arg(&build.context_dir);
+    apply_env(&mut command, build);
+
+    runner
+        .spawn(
+            command
+                .stdout(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::piped()),
+        )
         .map_err(|e| format!("Failed to execute docker buildx: {}", e))
 }
 
-fn verify_build_started(child: &mut std::process::Child) -> Result<(), String> {
+fn spawn_kaniko(
+    runner: &dyn CommandRunner,
+    build: &BuildImage,
+) -> Result<Box<dyn Process>, String> {
+    let mut command = std::process::Command::new(KANIKO_EXECUTOR);
+    command.args([
+        "--context",
+        &format!("dir://{}", build.context_dir),
+        "--dockerfile",
+        &build.dockerfile_path,
+        "--destination",
+        &build.tag,
+        "--cache=true",
+    ]);
+    for tag in &build.additional_tags {
+        command.arg("--destination").arg(tag);
+    }
+    command.arg("--digest-file").arg(&build.metadata_file);
+    for (name, value) in &build.labels {
+        command.arg("--label").arg(format!("{}={}", name, value));
+    }
+    for (name, value) in &build.proxy_env {
+        command
+            .arg("--build-arg")
+            .arg(format!("{}={}", name, value));
+    }
+    if !build.push {
+        command.arg("--no-push");
+    }
+    apply_env(&mut command, build);
+
+    runner
+        .spawn(
+            command
+                .stdout(std::process::Stdio::inherit())
+                .stderr(std::process::Stdio::piped()),
+        )
+        .map_err(|e| format!("Failed to execute kaniko executor: {}", e))
+}
+
+/// Environment variables kept from the service environment when `clear_env` is set.
+const KEPT_ENV: &[&str] = &["PATH", "HOME"];
+
+/// Set the build process environment: proxies and the project's `env`, optionally on top of a
+/// cleared environment.
+fn apply_env(command: &mut std::process::Command, build: &BuildImage) {
+    if build.clear_env {
+        command.env_clear();
+        // docker still needs its config (e.g. registry credentials) and the secret sources
+        let kept = std::env::vars().filter(|(name, _)| {
+            KEPT_ENV.contains(&name.as_str())
+                || name.starts_with("DOCKER_")
+                || build.secrets.values().any(|env| env == name)
+        });
+        command.envs(kept);
+    }
+
+    command.envs(build.proxy_env.iter().map(|(name, value)| (name, value)));
+    command.envs(build.ca_env.iter().map(|(name, value)| (name, value)));
+    command.envs(&build.env);
+
+    if !build.env.is_empty() {
+        let mut names: Vec<_> = build.env.keys().map(String::as_str).collect();
+        names.sort();
+        tracing::debug!(
+            "Build environment for {}: {} (values redacted)",
+            build.tag,
+            names.join(", ")
+        );
+    }
+}
+
+fn verify_build_started(child: &mut dyn Process) -> Result<(), String> {
     match child.try_wait() {
         Ok(Some(status)) => {
             if !status.success() {
@@ -102,13 +790,19 @@ fn verify_build_started(child: &mut std::process::Child) -> Result<(), String> {
     Ok(())
 }
 
-fn handle_build_completion(child: std::process::Child, image_tag: &str) -> Result<(), String> {
+fn handle_build_completion(
+    child: Box<dyn Process>,
+    build: &BuildImage,
+    started: Instant,
+) -> Result<BuiltImage, String> {
+    let image_tag = &build.tag;
     let output = match child.wait_with_output() {
         Ok(output) => output,
         Err(e) => {
             return Err(format!("Failed to wait for build process: {}", e));
         }
     };
+    let elapsed = started.elapsed();
 
     if !output.stdout.is_empty() {
         tracing::debug!(
@@ -127,13 +821,239 @@ fn handle_build_completion(child: std::process::Child, image_tag: &str) -> Resul
 
     if !output.status.success() {
         return Err(format!(
-            "Build failed for {} with exit code: {:?}",
+            "Build failed for {} with exit code: {:?} after {:.1?}",
             image_tag,
-            output.status.code()
+            output.status.code(),
+            elapsed
         ));
-    } else {
-        tracing::info!("Successfully built and pushed image: {}", image_tag);
     }
 
-    Ok(())
+    let digest = read_digest(&build.metadata_file);
+    match build.output {
+        ImageOutput::Registry if build.push => tracing::info!(
+            "Successfully built and pushed image: {} ({}) in {:.1?}",
+            image_tag,
+            digest.as_deref().unwrap_or("unknown digest"),
+            elapsed
+        ),
+        ImageOutput::Registry => tracing::info!(
+            "Successfully built (not pushed) image: {} in {:.1?}",
+            image_tag,
+            elapsed
+        ),
+        ImageOutput::Oci => tracing::info!(
+            "Successfully built image: {} and exported it to {} in {:.1?}",
+            image_tag,
+            build.output_dest.as_deref().unwrap_or_default(),
+            elapsed
+        ),
+        ImageOutput::Docker => tracing::info!(
+            "Successfully built image: {} and loaded it into the local daemon in {:.1?}",
+            image_tag,
+            elapsed
+        ),
+    }
+
+    Ok(BuiltImage {
+        tag: image_tag.clone(),
+        digest,
+        duration_secs: elapsed.as_secs_f64(),
+    })
+}
+
+/// Read the image digest from a build metadata file, then remove the file.
+///
+/// Accepts both the buildx JSON metadata (`containerimage.digest`) and the plain digest written
+/// by kaniko's `--digest-file`.
+fn read_digest(metadata_file: &Path) -> Option<String> {
+    let contents = match std::fs::read_to_string(metadata_file) {
+        Ok(contents) => contents,
+        Err(e) => {
+            tracing::debug!("No build metadata at {}: {}", metadata_file.display(), e);
+            return None;
+        }
+    };
+    if let Err(e) = std::fs::remove_file(metadata_file) {
+        tracing::debug!(
+            "Failed to remove build metadata {}: {}",
+            metadata_file.display(),
+            e
+        );
+    }
+
+    let contents = contents.trim();
+    let digest = match serde_json::from_str::<serde_json::Value>(contents) {
+        Ok(metadata) => metadata
+            .get("containerimage.digest")
+            .and_then(|digest| digest.as_str())
+            .map(str::to_string),
+        Err(_) => Some(contents.to_string()),
+    };
+
+    digest.filter(|digest| digest.starts_with("sha256:"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::mock::MockRunner;
+
+    fn image() -> BuildImage {
+        BuildImage {
+            tag: "registry.example.com/web:abc123".to_string(),
+            additional_tags: Vec::new(),
+            dockerfile_path: "/work/web/Dockerfile".to_string(),
+            context_dir: "/work/web".to_string(),
+            push: true,
+            output: ImageOutput::Registry,
+            output_dest: None,
+            builder: "builder".to_string(),
+            secrets: HashMap::new(),
+            network: NetworkMode::Default,
+            entitlements: Vec::new(),
+            add_hosts: Vec::new(),
+            exclude: Vec::new(),
+            immutable_tags: false,
+            cache_ref: None,
+            labels: Vec::new(),
+            max_image_bytes: None,
+            proxy_env: Vec::new(),
+            ca_env: Vec::new(),
+            env: HashMap::new(),
+            clear_env: false,
+            progress: BuildProgress::Plain,
+            log_mode: BuildLogMode::Full,
+            log_tail_lines: 20,
+            max_captured_bytes: 1024,
+            metadata_file: PathBuf::from("/tmp/metadata.json"),
+            depends_on: Vec::new(),
+        }
+    }
+
+    fn spawned(backend: BuildBackend, build: &BuildImage) -> String {
+        let runner = MockRunner::default();
+        backend.spawn(&runner, build).unwrap();
+        runner.commands().remove(0)
+    }
+
+    #[test]
+    fn spawn_buildx_pushes_with_inline_cache() {
+        assert_eq!(
+            spawned(BuildBackend::Buildx, &image()),
+            "docker buildx build --builder builder \
+             --cache-from type=registry,ref=registry.example.com/web:abc123 --cache-to type=inline \
+             --push -t registry.example.com/web:abc123 --file /work/web/Dockerfile \
+             --progress plain --metadata-file /tmp/metadata.json --network=default /work/web"
+        );
+    }
+
+    #[test]
+    fn spawn_buildx_passes_build_options() {
+        let build = BuildImage {
+            push: false,
+            additional_tags: vec!["registry.example.com/web:latest".to_string()],
+            cache_ref: Some("registry.example.com/cache:web".to_string()),
+            labels: vec![("build-hook.delivery".to_string(), "1234".to_string())],
+            secrets: HashMap::from([
+                ("npm".to_string(), "NPM_TOKEN".to_string()),
+                ("github".to_string(), "GITHUB_TOKEN".to_string()),
+            ]),
+            network: NetworkMode::Host,
+            entitlements: vec!["security.insecure".to_string(), "network.host".to_string()],
+            add_hosts: vec!["mirror:10.0.0.5".to_string()],
+            ..image()
+        };
+
+        assert_eq!(
+            spawned(BuildBackend::Buildx, &build),
+            "docker buildx build --builder builder \
+             --cache-from type=registry,ref=registry.example.com/cache:web \
+             --cache-to type=registry,ref=registry.example.com/cache:web,mode=max \
+             --output=type=cacheonly -t registry.example.com/web:abc123 \
+             --file /work/web/Dockerfile --progress plain --metadata-file /tmp/metadata.json \
+             -t registry.example.com/web:latest --label build-hook.delivery=1234 \
+             --secret id=github,env=GITHUB_TOKEN --secret id=npm,env=NPM_TOKEN \
+             --network=host --allow network.host --allow security.insecure \
+             --add-host mirror:10.0.0.5 /work/web"
+        );
+    }
+
+    #[test]
+    fn spawn_buildx_exports_oci_and_docker_outputs() {
+        let oci = BuildImage {
+            output: ImageOutput::Oci,
+            output_dest: Some("/exports/web.tar".to_string()),
+            ..image()
+        };
+        assert!(
+            spawned(BuildBackend::Buildx, &oci)
+                .contains(" --output=type=oci,dest=/exports/web.tar ")
+        );
+
+        let docker = BuildImage {
+            output: ImageOutput::Docker,
+            ..image()
+        };
+        assert!(spawned(BuildBackend::Buildx, &docker).contains(" --load "));
+    }
+
+    #[test]
+    fn spawn_kaniko_passes_destinations_and_build_args() {
+        let build = BuildImage {
+            push: false,
+            additional_tags: vec!["registry.example.com/web:latest".to_string()],
+            labels: vec![("build-hook.delivery".to_string(), "1234".to_string())],
+            proxy_env: vec![(
+                "HTTPS_PROXY".to_string(),
+                "http://proxy.internal:3128".to_string(),
+            )],
+            ..image()
+        };
+
+        assert_eq!(
+            spawned(BuildBackend::Kaniko, &build),
+            "/kaniko/executor --context dir:///work/web --dockerfile /work/web/Dockerfile \
+             --destination registry.example.com/web:abc123 --cache=true \
+             --destination registry.example.com/web:latest --digest-file /tmp/metadata.json \
+             --label build-hook.delivery=1234 \
+             --build-arg HTTPS_PROXY=http://proxy.internal:3128 --no-push"
+        );
+    }
+
+    #[test]
+    fn captured_output_keeps_everything_within_limit() {
+        let mut captured = CapturedOutput::default();
+        for line in ["#1 load", "#2 copy"] {
+            captured.push(line.to_string(), 14);
+        }
+
+        assert!(!captured.truncated);
+        assert_eq!(captured.bytes, 14);
+        assert_eq!(captured.into_lines(), ["#1 load", "#2 copy"]);
+    }
+
+    #[test]
+    fn captured_output_drops_earliest_lines_over_limit() {
+        let mut captured = CapturedOutput::default();
+        for line in ["#1 load", "#2 copy", "#3 run"] {
+            captured.push(line.to_string(), 14);
+        }
+
+        assert!(captured.truncated);
+        assert_eq!(captured.bytes, 13);
+        assert_eq!(
+            captured.into_lines(),
+            ["[output truncated]", "#2 copy", "#3 run"]
+        );
+    }
+
+    #[test]
+    fn captured_output_drops_line_longer_than_limit() {
+        let mut captured = CapturedOutput::default();
+        captured.push("#1 load".to_string(), 4);
+
+        assert!(captured.truncated);
+        assert_eq!(captured.bytes, 0);
+        assert_eq!(captured.into_lines(), ["[output truncated]"]);
+    }
 }
@@ -1,12 +1,34 @@
+mod commit_status;
 mod image;
+mod reference;
 mod repo;
+mod tag;
 
+pub use image::{BuildBackend, BuildLogMode, BuildProgress, BuiltImage, ImageOutput, NetworkMode};
+pub use reference::{validate_registry, validate_repository};
+pub use repo::{CloneLimit, GitCredential, load_github_token};
+
+use commit_status::{CommitState, CommitStatus};
+use image::ImageFailurePolicy;
+use repo::{CloneStrategy, Credentials};
+
+use crate::cancel::CancelHandle;
+use crate::command::SystemRunner;
+use crate::config::{AppConfig, ValidationError};
+use crate::events::{BuildEvent, EventPublisher};
+use crate::glob;
 use crate::kube;
-use serde::Deserialize;
-use std::path::{Component, Path};
+use crate::schedule::Schedule;
+use crate::webhook;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
 
 /// Configuration for a buildable project.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct ProjectConfig {
     /// Human-friendly project name.
     name: String,
@@ -21,20 +43,201 @@ pub struct ProjectConfig {
     image: Vec<ImageConfig>,
     /// Kubernets deployment targets to restart after builds succeed.
     deployments: DeploymentConfig,
+    /// Glob patterns for pushed branches that trigger a build, e.g. ["main", "release/*"].
+    /// When empty, every trigger builds `code.branch`, or the default branch when that's unset.
+    #[serde(default)]
+    branch_filter: Vec<String>,
+    /// Environment variables set on the build tool process, e.g. for scripts run by a
+    /// Dockerfile frontend. Values are never logged.
+    #[serde(default, serialize_with = "crate::config::redact_values")]
+    env: HashMap<String, String>,
+    /// Start the build tool from a clean environment instead of inheriting the service's, for
+    /// isolation. Only `PATH`, `HOME`, `DOCKER_*` variables, proxies, secrets, and `env` are kept.
+    #[serde(default)]
+    clear_env: bool,
+    /// Command run in the source directory before the images are built, e.g. a codegen step
+    /// like ["npm", "ci"]. The build fails if it exits non-zero.
+    #[serde(default)]
+    pre_build: Vec<String>,
+    /// Webhook event types that trigger a build, read from the `X-GitHub-Event` header.
+    /// Requests without the header, like manual triggers, always build.
+    #[serde(default = "default_events")]
+    events: Vec<String>,
+    /// Glob patterns of repository paths, e.g. ["services/api/**", "Cargo.lock"]. When set, a
+    /// build is skipped unless a matching path changed since the last successful build.
+    #[serde(default)]
+    paths: Vec<String>,
+    /// Largest size (in bytes) of a pushed image, counting its compressed layers, to catch
+    /// accidental bloat. Larger images fail the build once pushed.
+    max_image_bytes: Option<u64>,
+    /// Environment variables holding the webhook's HMAC secrets. When set, triggers must carry an
+    /// `X-Hub-Signature-256` matching one of them; several allow rotating a secret.
+    #[serde(default)]
+    webhook_secret_envs: Vec<String>,
+    /// Minimum time (in seconds) between builds started by triggers, so a flapping webhook
+    /// source can't start back-to-back builds. Triggers within it are rejected with `429`.
+    min_interval_secs: Option<u64>,
+    /// Cron expression (UTC) to also build on, e.g. "0 3 * * *" to rebuild nightly and pick up
+    /// base image updates.
+    schedule: Option<String>,
+    /// Whether a failed image stops the build (`abort`) or the remaining images are still built
+    /// (`continue`). Either way the build fails without the final rollout. Defaults to abort.
+    #[serde(default)]
+    on_image_failure: ImageFailurePolicy,
+}
+
+fn default_events() -> Vec<String> {
+    vec![webhook::PUSH_EVENT.to_string()]
+}
+
+/// Result of a successful project build.
+#[derive(Clone, Debug, Default)]
+pub struct BuildOutput {
+    /// Commit that was built, when building from git.
+    pub commit: Option<String>,
+    pub images: Vec<BuiltImage>,
+    /// Rollouts to the deployment resources, in the order they ran.
+    pub rollouts: Vec<RolloutResult>,
+    /// Why nothing was built, e.g. no path in `paths` changed.
+    pub skipped: Option<String>,
+}
+
+/// Outcome of rolling a build out to some of the project's resources at once.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RolloutResult {
+    pub namespace: String,
+    /// "<resource_type>/<resource_name>" of each resource rolled out to.
+    pub resources: Vec<String>,
+    pub success: bool,
+    /// Failure reason for failed rollouts.
+    pub error: Option<String>,
+}
+
+/// Per-trigger options for a project build.
+#[derive(Clone, Debug, Default)]
+pub struct BuildRequest {
+    /// Branch to build instead of `code.branch`, e.g. the branch from a push payload.
+    pub branch: Option<String>,
+    /// Names of the only images to build, instead of all of the project's images.
+    pub images: Option<Vec<String>>,
+    /// Push over existing tags of images with `immutable_tags`.
+    pub force: bool,
+    /// Commit of the project's last successful build, which `paths` are compared against.
+    pub previous_commit: Option<String>,
+    /// Webhook delivery that triggered the build.
+    pub trigger: TriggerMetadata,
+}
+
+/// Webhook delivery a build was triggered by, labelled on the built images to trace them back to
+/// it.
+#[derive(Clone, Debug, Default)]
+pub struct TriggerMetadata {
+    /// Provider delivery id, from `X-GitHub-Delivery`.
+    pub delivery_id: Option<String>,
+    /// Request id, from `X-Request-Id`.
+    pub request_id: Option<String>,
+    /// Provider event type, from `X-GitHub-Event`.
+    pub event: Option<String>,
+}
+
+impl TriggerMetadata {
+    /// Image labels for the known values, e.g. "build-hook.delivery-id".
+    fn labels(&self) -> Vec<(String, String)> {
+        [
+            ("delivery-id", &self.delivery_id),
+            ("request-id", &self.request_id),
+            ("event", &self.event),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = value.as_ref()?;
+            Some((format!("{}.{}", LABEL_PREFIX, name), value.clone()))
+        })
+        .collect()
+    }
+}
+
+impl BuildRequest {
+    /// Whether the request builds `image`.
+    fn builds_image(&self, image: &ImageConfig) -> bool {
+        self.images
+            .as_ref()
+            .is_none_or(|names| image.name.as_ref().is_some_and(|name| names.contains(name)))
+    }
+
+    /// Fold a newer trigger into this pending request: the newer target ref wins, pending
+    /// builds of different images widen to the whole project, and any forced trigger forces.
+    pub fn coalesce(&mut self, newer: BuildRequest) {
+        self.branch = newer.branch;
+        if self.images != newer.images {
+            self.images = None;
+        }
+        self.force |= newer.force;
+        // the build runs for the newest trigger, so it's labelled with it
+        self.trigger = newer.trigger;
+    }
 }
 
 /// Code repository settings for a project.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct CodeConfig {
     /// URL (https) to the project's GitHub repository.
-    url: String,
-    /// Target branch to pull code from.
-    branch: String,
+    /// Exactly one of `url` or `tarball` must be set.
+    #[serde(serialize_with = "crate::config::redact_url_credentials")]
+    url: Option<String>,
+    /// Target branch to pull code from. Defaults to the remote's default branch (its `HEAD`).
+    branch: Option<String>,
+    /// Name given to the cloned remote, defaults to "origin".
+    remote: Option<String>,
+    /// Whether the repository can be cloned without credentials.
+    /// Private repositories (`public = false`) require `GITHUB_TOKEN` or `GITHUB_TOKEN_FILE`.
+    /// Defaults to true.
+    #[serde(default = "default_public")]
+    public: bool,
+    /// URL (https) of a tarball to build from instead of a git repository, e.g. a release
+    /// artifact. A single top-level directory in the archive is used as the source root.
+    tarball: Option<String>,
+    /// Check out the repository's submodules, including nested ones, after cloning.
+    #[serde(default)]
+    submodules: bool,
+    /// Refuse to build a commit that isn't signed by a key in `allowed_signers` or `gpg_home`.
+    #[serde(default)]
+    require_signed_commits: bool,
+    /// SSH allowed signers file (`git config gpg.ssh.allowedSignersFile` format) trusted for
+    /// SSH-signed commits.
+    allowed_signers: Option<PathBuf>,
+    /// GnuPG home directory whose keyring holds the public keys trusted for GPG-signed commits.
+    gpg_home: Option<PathBuf>,
+    /// `name` of the `app.git_credentials` entry to clone with. Defaults to the entry for the
+    /// repository's host, then `GITHUB_TOKEN`.
+    credential: Option<String>,
+    /// Whether each build clones into an empty workspace ("fresh", the default) or fetches into
+    /// the clone kept from the previous build ("reuse").
+    #[serde(default)]
+    clone_strategy: CloneStrategy,
+    /// Report pending, success, and failure statuses for the built commit to GitHub's commit
+    /// status API. Requires a `github.com` URL and `GITHUB_TOKEN`.
+    #[serde(default)]
+    report_status: bool,
+    /// Expected SHA-256 checksum (hex) of the tarball, verified before building.
+    sha256: Option<String>,
+}
+
+fn default_public() -> bool {
+    true
+}
+
+impl CodeConfig {
+    fn remote(&self) -> &str {
+        self.remote.as_deref().unwrap_or(repo::DEFAULT_REMOTE)
+    }
 }
 
 /// Image build configuration for a project.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct ImageConfig {
+    /// Name used to build only this image with `POST /{slug}/image/{name}`.
+    name: Option<String>,
     /// Repository path under the configured registry.
     /// e.g. "my-org/my-app" for an image tagged as "gcr.io/my-org/my-app:latest"
     repository: String,
@@ -42,20 +245,155 @@ pub struct ImageConfig {
     ///
     /// If the Dockerfile is in the repo root, this should be just "Dockerfile".
     /// If it's in a subdirectory, specify the relative path, e.g. "services/api/Dockerfile".
-    location: String,
+    /// Exactly one of `location` or `dockerfile_content` must be set.
+    location: Option<String>,
+    /// Dockerfile written out for the build instead of one from the repository, for sources
+    /// without a Dockerfile. The repo root is the build context.
+    dockerfile_content: Option<String>,
     /// Tag applied to the built image, e.g. "latest" or "v1.2.3".
+    /// May use `{sha}`, `{short_sha}`, `{branch}`, `{date}`, and `{timestamp}`, resolved at
+    /// build time, e.g. "v1.2.3-{short_sha}".
     tag: String,
+    /// Additional tags pushed for the same built image, e.g. ["latest"].
+    /// Supports the same tokens as `tag`.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Registry to push this image to instead of `app.registry`.
+    registry: Option<String>,
+    /// Name of the buildx builder (from `app.builders`) to build this image with.
+    /// Defaults to the first configured builder.
+    builder: Option<String>,
+    /// Whether to push the built image to the registry.
+    ///
+    /// Set to `false` to only verify that the image builds, e.g. for a PR validation hook.
+    #[serde(default = "default_push")]
+    push: bool,
+    /// Where the built image goes: "registry" (pushed, the default), "oci" (an OCI layout
+    /// tarball at `output_dest`), or "docker" (loaded into the local daemon).
+    #[serde(default)]
+    output: ImageOutput,
+    /// Path the `oci` output is written to, e.g. "/exports/my-app.tar".
+    output_dest: Option<String>,
+    /// BuildKit secrets exposed to `RUN --mount=type=secret`, mapping secret ids to the names of
+    /// environment variables holding their values, e.g. { npm_token = "NPM_TOKEN" }.
+    #[serde(default)]
+    secrets: HashMap<String, String>,
+    /// Network mode for `RUN` steps: "default", "host", or "none". Defaults to "default".
+    #[serde(default)]
+    network: NetworkMode,
+    /// Extra privileges granted to `RUN` steps with buildx's `--allow`, e.g.
+    /// ["security.insecure"] for `RUN --security=insecure`. The BuildKit daemon must allow them.
+    #[serde(default)]
+    entitlements: Vec<String>,
+    /// Extra `/etc/hosts` entries for `RUN` steps, as "host:ip", e.g. ["mirror.internal:10.0.0.5"].
+    #[serde(default)]
+    add_hosts: Vec<String>,
+    /// `.dockerignore` patterns to leave out of the build context on top of the repository's
+    /// own ignore file, e.g. ["node_modules", "target"], for repos that can't change theirs.
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Refuse to push when a tag already exists in the registry, so released images aren't
+    /// overwritten. A trigger with `?force=true` pushes anyway.
+    #[serde(default)]
+    immutable_tags: bool,
+    /// Scope of a build cache shared through `<registry>/cache:<scope>`, e.g. "web", so images
+    /// with the same scope reuse each other's layers and other images don't. The cache is
+    /// inlined into the image when unset.
+    cache_scope: Option<String>,
+    /// Names of images of the project built before this one, e.g. ["base"] for an image
+    /// `FROM` the project's base image. Images build in config order otherwise.
+    #[serde(default)]
+    depends_on: Vec<String>,
+}
+
+fn default_push() -> bool {
+    true
 }
 
 /// Kubernetes deployment restart configuration.
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct DeploymentConfig {
     /// Kubernetes namespace where the resources are deployed.
     namespace: String,
-    /// Array of resource names to restart once builds succeed.
+    /// Array of resources to roll out to once builds succeed.
     ///
-    /// Must be written as "<resource_type>/<resource_name>", e.g. "deployment/my-app" or "statefulset/my-db".
-    resources: Vec<String>,
+    /// Must be written as "<resource_type>/<resource_name>", e.g. "deployment/my-app" or "statefulset/my-db",
+    /// which restarts the resource, or as a table choosing another `strategy`.
+    resources: Vec<ResourceConfig>,
+    /// Refuse to build when any resource is already unhealthy (not all replicas ready),
+    /// to avoid compounding an outage. Defaults to false.
+    #[serde(default)]
+    require_healthy: bool,
+    /// Times to retry a rollout restart that fails with a transient API server error,
+    /// with exponential backoff between attempts. Defaults to 3.
+    #[serde(default = "default_restart_retries")]
+    restart_retries: u32,
+    /// How many resources to restart at once. Defaults to 1, restarting them in order.
+    #[serde(default = "default_rollout_parallelism")]
+    rollout_parallelism: usize,
+    /// When set, wait for every rollout to finish with `kubectl rollout status`, re-checking a
+    /// failed status this many times, e.g. after a pod failed readiness while a dependency was
+    /// still starting, before failing the deploy. Defaults to not waiting.
+    rollout_retries: Option<u32>,
+    /// Roll out the resources paired with an image, through their `image`, as soon as that image
+    /// is built instead of after every image. Unpaired resources still roll out at the end.
+    /// Defaults to false.
+    #[serde(default)]
+    deploy_per_image: bool,
+}
+
+/// A resource to roll builds out to, either just its name to restart it or a table choosing how.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum ResourceConfig {
+    Restart(String),
+    Rollout(ResourceRollout),
+}
+
+/// A resource with its rollout strategy.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ResourceRollout {
+    /// "<resource_type>/<resource_name>", e.g. "deployment/my-app".
+    resource: String,
+    #[serde(default)]
+    strategy: RolloutStrategy,
+    /// Container whose image `set-image` updates. Defaults to "*", every container.
+    container: Option<String>,
+    /// `name` of the project image `set-image` deploys. Defaults to the project's only image.
+    image: Option<String>,
+    /// Manifest `apply` re-applies, relative to the repo root, e.g. "k8s/deployment.yaml".
+    manifest: Option<String>,
+}
+
+/// How a resource picks up a new build.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RolloutStrategy {
+    /// `kubectl rollout restart`, for resources pulling a moving tag like "latest".
+    #[default]
+    Restart,
+    /// `kubectl set image` to the pushed image, pinned by digest when it's known.
+    SetImage,
+    /// `kubectl apply` of a manifest from the repository.
+    Apply,
+}
+
+impl ResourceConfig {
+    /// "<resource_type>/<resource_name>" of the resource.
+    fn name(&self) -> &str {
+        match self {
+            ResourceConfig::Restart(resource) => resource,
+            ResourceConfig::Rollout(rollout) => &rollout.resource,
+        }
+    }
+}
+
+fn default_restart_retries() -> u32 {
+    3
+}
+
+fn default_rollout_parallelism() -> usize {
+    1
 }
 
 fn validate_https_url(url: &str) -> Result<(), String> {
@@ -92,101 +430,1449 @@ fn validate_https_url(url: &str) -> Result<(), String> {
     Ok(())
 }
 
+/// Directory the per-project build workspaces are created in.
+const WORKSPACE_ROOT: &str = "/tmp";
+/// Workspace of a project's clone kept between builds, next to the per-build workspaces.
+const REUSED_CLONE_DIR: &str = "clone";
+/// Name of the file a failed build's error is written to when its workspace is kept.
+const FAILURE_FILE: &str = "build-hook-failure.txt";
+/// Prefix of the image labels describing the trigger, e.g. "build-hook.delivery-id".
+const LABEL_PREFIX: &str = "build-hook";
+
+/// Remove the workspaces of earlier failed builds kept in `project_dir`, other than `current`.
+/// Workspaces of running builds have no failure file yet, so they're left alone.
+fn remove_failed_workspaces(project_dir: &str, current: &str) {
+    let Ok(entries) = std::fs::read_dir(project_dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path == Path::new(current) || !path.join(FAILURE_FILE).is_file() {
+            continue;
+        }
+        tracing::info!("Removing failed build workspace {}", path.display());
+        if let Err(e) = std::fs::remove_dir_all(&path) {
+            tracing::warn!(
+                "Failed to remove failed build workspace {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Remove a build's workspace, unless the build failed and failed workspaces are kept.
+fn clean_workspace<T>(app: &AppConfig, repo_dest: &str, result: &Result<T, String>) {
+    if let Err(e) = result
+        && app.keep_failed_workspace
+    {
+        let failure_path = Path::new(repo_dest).join(FAILURE_FILE);
+        if let Err(write_err) = std::fs::write(&failure_path, format!("{}\n", e)) {
+            tracing::warn!(
+                "Failed to write failure reason to {}: {}",
+                failure_path.display(),
+                write_err
+            );
+        }
+        tracing::info!("Keeping failed build workspace {} for debugging", repo_dest);
+        return;
+    }
+
+    if Path::new(repo_dest).exists()
+        && let Err(e) = std::fs::remove_dir_all(repo_dest)
+    {
+        tracing::warn!(
+            "Failed to remove temporary repository directory {}: {}",
+            repo_dest,
+            e
+        );
+    }
+}
+
 impl ProjectConfig {
-    pub fn validate(&self) -> Result<(), String> {
+    /// Check the project's settings, returning every problem found. `index` is the project's
+    /// position in the config, used in field paths when it has no slug.
+    pub fn validate(&self, index: usize, app: &AppConfig) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        let path = if self.slug.trim().is_empty() {
+            format!("projects[{}]", index)
+        } else {
+            format!("projects[{}]", self.slug)
+        };
+        let mut error = |field: &str, message: &str| {
+            errors.push(ValidationError::new(format!("{}.{}", path, field), message));
+        };
+
         // project.name should not be empty
         if self.name.trim().is_empty() {
-            return Err("project.name must not be empty!".to_string());
+            error("name", "must not be empty");
         }
 
         if self.slug.trim().is_empty() {
-            return Err("project.slug must not be empty!".to_string());
+            error("slug", "must not be empty");
+        }
+
+        match (&self.code.url, &self.code.tarball) {
+            (Some(url), None) => {
+                // project.code.url should be a valid HTTPS URL
+                if validate_https_url(url).is_err() {
+                    error("code.url", "must be a valid HTTPS URL");
+                }
+
+                // project.code.branch should not be empty when set
+                if self
+                    .code
+                    .branch
+                    .as_ref()
+                    .is_some_and(|branch| branch.trim().is_empty())
+                {
+                    error("code.branch", "must not be empty");
+                }
+
+                // statuses can only be reported to repositories on github.com
+                if self.code.report_status && commit_status::github_repo(url).is_none() {
+                    error(
+                        "code.report_status",
+                        "requires a `https://github.com/{owner}/{repo}` URL",
+                    );
+                }
+            }
+            (None, Some(tarball)) => {
+                // project.code.tarball should be a valid HTTPS URL
+                if validate_https_url(tarball).is_err() {
+                    error("code.tarball", "must be a valid HTTPS URL");
+                }
+
+                // a tarball has no submodules to check out
+                if self.code.submodules {
+                    error("code.submodules", "is only used with `code.url`");
+                }
+                if self.code.report_status {
+                    error("code.report_status", "is only used with `code.url`");
+                }
+                if self.code.clone_strategy != CloneStrategy::Fresh {
+                    error("code.clone_strategy", "is only used with `code.url`");
+                }
+                if self.code.require_signed_commits {
+                    error(
+                        "code.require_signed_commits",
+                        "is only used with `code.url`",
+                    );
+                }
+                if self.code.credential.is_some() {
+                    error("code.credential", "is only used with `code.url`");
+                }
+
+                // project.code.sha256 should be a hex SHA-256 digest when set
+                if let Some(sha256) = &self.code.sha256
+                    && (sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()))
+                {
+                    error("code.sha256", "must be a 64 character hex digest");
+                }
+            }
+            _ => error("code", "must set exactly one of `url` or `tarball`"),
         }
 
-        // project.code.url should be a valid HTTPS URL
-        validate_https_url(&self.code.url)
-            .map_err(|_| "`project.code.url` must be a valid HTTPS URL!".to_string())?;
+        if let Some(name) = &self.code.credential
+            && !app
+                .git_credentials
+                .iter()
+                .any(|credential| &credential.name == name)
+        {
+            error(
+                "code.credential",
+                &format!("`{}` is not one of `app.git_credentials`", name),
+            );
+        }
+
+        // signed commits are checked against the configured keys, at least one set is needed
+        if self.code.require_signed_commits
+            && self.code.allowed_signers.is_none()
+            && self.code.gpg_home.is_none()
+        {
+            error(
+                "code.require_signed_commits",
+                "needs `code.allowed_signers` or `code.gpg_home` to verify against",
+            );
+        }
+        if let Some(allowed_signers) = &self.code.allowed_signers
+            && !allowed_signers.is_file()
+        {
+            error(
+                "code.allowed_signers",
+                &format!("`{}` is not a file", allowed_signers.display()),
+            );
+        }
+        if let Some(gpg_home) = &self.code.gpg_home
+            && !gpg_home.is_dir()
+        {
+            error(
+                "code.gpg_home",
+                &format!("`{}` is not a directory", gpg_home.display()),
+            );
+        }
 
-        // project.code.branch should not be empty
-        if self.code.branch.trim().is_empty() {
-            return Err("project.code.branch must not be empty!".to_string());
+        // project.code.remote should not be empty when set
+        if self.code.remote().trim().is_empty() {
+            error("code.remote", "must not be empty");
         }
 
         if self.image.is_empty() {
-            return Err("project.image must have at least one entry!".to_string());
+            error("image", "must have at least one entry");
         }
 
-        for image in &self.image {
-            // project.image.repository should not be empty
-            if image.repository.trim().is_empty() {
-                return Err("project.image.repository must not be empty!".to_string());
-            }
+        let mut image_names = HashSet::new();
+        for (index, image) in self.image.iter().enumerate() {
+            let mut error = |field: &str, message: &str| {
+                error(&format!("image[{}].{}", index, field), message);
+            };
 
-            // project.image.location should not be empty
-            if image.location.trim().is_empty() {
-                return Err("project.image.location must not be empty!".to_string());
+            // project.image.name should be non-empty, unique, and usable in a URL path
+            if let Some(name) = &image.name {
+                if name.trim().is_empty() || name.contains('/') {
+                    error("name", "must not be empty or contain `/`");
+                }
+                if !image_names.insert(name) {
+                    error("name", &format!("`{}` is not unique", name));
+                }
             }
 
-            let location_path = Path::new(&image.location);
-            if location_path.is_absolute() {
-                return Err("project.image.location must be a relative path!".to_string());
+            // project.image.repository should be a valid image name, tags are checked once
+            // rendered
+            let repository = image
+                .repository
+                .strip_prefix('/')
+                .unwrap_or(&image.repository);
+            if repository.trim().is_empty() {
+                error("repository", "must not be empty");
+            } else if let Err(e) = reference::validate_repository(repository) {
+                error("repository", &e);
             }
-            if location_path
-                .components()
-                .any(|component| matches!(component, Component::ParentDir))
-            {
-                return Err("project.image.location must not contain parent paths!".to_string());
+
+            match (&image.location, &image.dockerfile_content) {
+                (Some(location), None) => {
+                    // project.image.location should not be empty
+                    if location.trim().is_empty() {
+                        error("location", "must not be empty");
+                    }
+
+                    let location_path = Path::new(location);
+                    if location_path.is_absolute() {
+                        error("location", "must be a relative path");
+                    }
+                    if location_path
+                        .components()
+                        .any(|component| matches!(component, Component::ParentDir))
+                    {
+                        error("location", "must not contain parent paths");
+                    }
+                    if !app.allowed_locations.is_empty()
+                        && !glob::matches_any(&app.allowed_locations, location)
+                    {
+                        error(
+                            "location",
+                            &format!(
+                                "`{}` doesn't match any of `app.allowed_locations`",
+                                location
+                            ),
+                        );
+                    }
+                }
+                (None, Some(content)) => {
+                    if content.trim().is_empty() {
+                        error("dockerfile_content", "must not be empty");
+                    }
+                    // an instance restricted to known Dockerfiles can't take arbitrary ones
+                    if !app.allowed_locations.is_empty() {
+                        error(
+                            "dockerfile_content",
+                            "is not allowed when `app.allowed_locations` is set",
+                        );
+                    }
+                }
+                _ => error(
+                    "location",
+                    "must set exactly one of `location` or `dockerfile_content`",
+                ),
             }
 
             // project.image.tag should not be empty
             if image.tag.trim().is_empty() {
-                return Err("project.image.tag must not be empty!".to_string());
+                error("tag", "must not be empty");
+            } else if let Err(e) = tag::validate(&image.tag, self.code.tarball.is_none()) {
+                error("tag", &e);
+            }
+
+            // project.image.tags entries should not be empty
+            for (index, tag) in image.tags.iter().enumerate() {
+                let field = format!("tags[{}]", index);
+                if tag.trim().is_empty() {
+                    error(&field, "must not be empty");
+                } else if let Err(e) = tag::validate(tag, self.code.tarball.is_none()) {
+                    error(&field, &e);
+                }
+            }
+
+            // project.image.registry should not be empty when set, like app.registry
+            if let Some(registry) = &image.registry {
+                if registry.trim().is_empty() {
+                    error("registry", "must not be empty");
+                } else if let Err(e) = reference::validate_registry(registry) {
+                    error("registry", &e);
+                }
+            }
+
+            // project.image.builder must reference a configured builder
+            if let Some(builder) = &image.builder
+                && !app.builders().iter().any(|b| &b.name == builder)
+            {
+                error(
+                    "builder",
+                    &format!("`{}` is not a configured builder", builder),
+                );
+            }
+
+            // project.image.secrets need both an id and an environment variable
+            if image
+                .secrets
+                .iter()
+                .any(|(id, env)| id.trim().is_empty() || env.trim().is_empty())
+            {
+                error(
+                    "secrets",
+                    "must map non-empty ids to non-empty variable names",
+                );
+            }
+            if !image.secrets.is_empty() && app.backend == BuildBackend::Kaniko {
+                error("secrets", "are not supported by the kaniko backend");
+            }
+            // project.image.output_dest is required by, and only used with, the oci output
+            match (image.output, &image.output_dest) {
+                (ImageOutput::Oci, Some(dest)) if !dest.trim().is_empty() => {}
+                (ImageOutput::Oci, _) => error("output_dest", "must be set for the oci output"),
+                (_, Some(_)) => error("output_dest", "is only used with the oci output"),
+                (_, None) => {}
+            }
+            if image.output != ImageOutput::Registry && app.backend == BuildBackend::Kaniko {
+                error("output", "must be registry with the kaniko backend");
+            }
+            // project.image.cache_scope tags the cache reference
+            if let Some(scope) = &image.cache_scope
+                && let Err(e) = reference::validate_tag(scope)
+            {
+                error("cache_scope", &e);
+            }
+            if image.cache_scope.is_some() && app.backend == BuildBackend::Kaniko {
+                error("cache_scope", "is not supported by the kaniko backend");
+            }
+
+            // project.image.depends_on must name other images of the project
+            for dependency in &image.depends_on {
+                if image.name.as_ref() == Some(dependency) {
+                    error("depends_on", "must not contain the image itself");
+                } else if !self.has_image(dependency) {
+                    error(
+                        "depends_on",
+                        &format!("`{}` is not an image of the project", dependency),
+                    );
+                }
+            }
+
+            // existing tags are looked up with `docker buildx imagetools`
+            if image.immutable_tags && app.backend == BuildBackend::Kaniko {
+                error("immutable_tags", "is not supported by the kaniko backend");
+            }
+            if image.network != NetworkMode::Default && app.backend == BuildBackend::Kaniko {
+                error("network", "is not supported by the kaniko backend");
+            }
+
+            // project.image.entitlements must be ones buildx knows how to grant
+            for entitlement in &image.entitlements {
+                if !image::ENTITLEMENTS.contains(&entitlement.as_str()) {
+                    error(
+                        "entitlements",
+                        &format!(
+                            "`{}` is not one of {}",
+                            entitlement,
+                            image::ENTITLEMENTS.join(", ")
+                        ),
+                    );
+                }
+            }
+            if !image.entitlements.is_empty() && app.backend == BuildBackend::Kaniko {
+                error("entitlements", "are not supported by the kaniko backend");
+            }
+
+            // project.image.add_hosts entries map a host name to an IP address
+            for entry in &image.add_hosts {
+                let valid = entry.split_once(':').is_some_and(|(host, ip)| {
+                    !host.trim().is_empty() && ip.parse::<IpAddr>().is_ok()
+                });
+                if !valid {
+                    error(
+                        "add_hosts",
+                        &format!("`{}` must be written as `host:ip`", entry),
+                    );
+                }
+            }
+            if !image.add_hosts.is_empty() && app.backend == BuildBackend::Kaniko {
+                error("add_hosts", "is not supported by the kaniko backend");
+            }
+
+            // project.image.exclude entries are written as lines of a .dockerignore
+            if image
+                .exclude
+                .iter()
+                .any(|pattern| pattern.trim().is_empty() || pattern.contains('\n'))
+            {
+                error("exclude", "patterns must be non-empty single lines");
+            }
+        }
+
+        if let Err(e) = self.build_order() {
+            error("image", &e);
+        }
+
+        // project.branch_filter patterns should not be empty
+        if self
+            .branch_filter
+            .iter()
+            .any(|pattern| pattern.trim().is_empty())
+        {
+            error("branch_filter", "must not contain empty patterns");
+        }
+
+        // project.paths are compared against git history, which a tarball doesn't have
+        if self.paths.iter().any(|pattern| pattern.trim().is_empty()) {
+            error("paths", "must not contain empty patterns");
+        }
+        if !self.paths.is_empty() && self.code.tarball.is_some() {
+            error("paths", "is only used with `code.url`");
+        }
+
+        // project.max_image_bytes is checked with `docker buildx imagetools`
+        if self.max_image_bytes == Some(0) {
+            error("max_image_bytes", "must be at least 1");
+        }
+        if self.max_image_bytes.is_some() && app.backend == BuildBackend::Kaniko {
+            error("max_image_bytes", "is not supported by the kaniko backend");
+        }
+
+        // project.webhook_secret_envs should name environment variables
+        if self
+            .webhook_secret_envs
+            .iter()
+            .any(|name| name.trim().is_empty())
+        {
+            error("webhook_secret_envs", "must not contain empty names");
+        }
+
+        // project.min_interval_secs of zero would never throttle
+        if self.min_interval_secs == Some(0) {
+            error("min_interval_secs", "must be at least 1");
+        }
+
+        if let Some(schedule) = &self.schedule {
+            match Schedule::parse(schedule) {
+                // e.g. "0 0 31 2 *", the lookahead covers leap days
+                Ok(parsed) if parsed.next_after(0).is_none() => {
+                    error("schedule", &format!("`{}` never fires", schedule))
+                }
+                Ok(_) => {}
+                Err(e) => error("schedule", &e),
             }
         }
 
+        // project.events should name event types, an empty list would never build from a webhook
+        if self.events.is_empty() {
+            error("events", "must have at least one event type");
+        }
+        if self.events.iter().any(|event| event.trim().is_empty()) {
+            error("events", "must not contain empty event types");
+        }
+
+        // project.pre_build should name a program when set
+        if self
+            .pre_build
+            .first()
+            .is_some_and(|program| program.trim().is_empty())
+        {
+            error("pre_build", "must start with a non-empty program");
+        }
+
+        // project.env names should be valid environment variable names
+        if self
+            .env
+            .keys()
+            .any(|name| name.trim().is_empty() || name.contains('='))
+        {
+            error("env", "names must be non-empty and must not contain `=`");
+        }
+
         // project.deployments.namespace should not be empty
         if self.deployments.namespace.trim().is_empty() {
-            return Err("project.deployments.namespace must not be empty!".to_string());
+            error("deployments.namespace", "must not be empty");
+        }
+
+        // project.deployments.rollout_parallelism of zero would never restart anything
+        if self.deployments.rollout_parallelism == 0 {
+            error("deployments.rollout_parallelism", "must be at least 1");
         }
 
         // need at least 1 item specified in project.deployments.resources
         if self.deployments.resources.is_empty() {
-            return Err("project.deployments.resources must have at least one item!".to_string());
+            error("deployments.resources", "must have at least one item");
         }
+        for (i, resource) in self.deployments.resources.iter().enumerate() {
+            let ResourceConfig::Rollout(rollout) = resource else {
+                continue;
+            };
+            let field = |name: &str| format!("deployments.resources[{}].{}", i, name);
 
-        Ok(())
+            if rollout.resource.trim().is_empty() {
+                error(&field("resource"), "must not be empty");
+            }
+            if rollout.strategy != RolloutStrategy::SetImage {
+                if rollout.container.is_some() {
+                    error(
+                        &field("container"),
+                        "is only used by the `set-image` strategy",
+                    );
+                }
+                if rollout.image.is_some() && !self.deployments.deploy_per_image {
+                    error(
+                        &field("image"),
+                        "is only used by the `set-image` strategy or `deployments.deploy_per_image`",
+                    );
+                }
+            }
+            if rollout.strategy != RolloutStrategy::Apply && rollout.manifest.is_some() {
+                error(&field("manifest"), "is only used by the `apply` strategy");
+            }
+
+            match rollout.strategy {
+                RolloutStrategy::Restart | RolloutStrategy::Apply if rollout.image.is_some() => {
+                    if let Err(e) = self.rollout_image(rollout) {
+                        error(&field("image"), &e);
+                    }
+                }
+                _ => {}
+            }
+            match rollout.strategy {
+                RolloutStrategy::Restart => {}
+                RolloutStrategy::SetImage => match self.rollout_image(rollout) {
+                    Ok(index) => {
+                        let image = &self.image[index];
+                        if image.output != ImageOutput::Registry || !image.push {
+                            error(
+                                &field("image"),
+                                "must be pushed to a registry for `set-image`",
+                            );
+                        }
+                    }
+                    Err(e) => error(&field("image"), &e),
+                },
+                RolloutStrategy::Apply => match &rollout.manifest {
+                    Some(manifest) => {
+                        let manifest_path = Path::new(manifest);
+                        if manifest.trim().is_empty() || manifest_path.is_absolute() {
+                            error(&field("manifest"), "must be a relative path");
+                        }
+                        if manifest_path
+                            .components()
+                            .any(|component| matches!(component, Component::ParentDir))
+                        {
+                            error(&field("manifest"), "must not contain parent paths");
+                        }
+                    }
+                    None => error(&field("manifest"), "is required by the `apply` strategy"),
+                },
+            }
+        }
+
+        errors
     }
 
-    pub fn build(&self, registry: &str, github_token: &str) -> Result<(), String> {
-        let repo_dest = format!("/tmp/{}", self.slug);
-        repo::clone_repo(github_token, &self.code.url, &repo_dest, &self.code.branch)
-            .map_err(|err| format!("Failed to clone repository: {}", err))?;
+    #[allow(clippy::too_many_arguments)]
+    pub fn build(
+        &self,
+        app: &AppConfig,
+        github_token: &str,
+        build_id: u64,
+        request: &BuildRequest,
+        clone_limit: Option<&CloneLimit>,
+        events: &EventPublisher,
+        cancel: &CancelHandle,
+    ) -> Result<BuildOutput, String> {
+        // a build starting while the server shuts down is cancelled before it clones anything
+        cancel.check()?;
 
-        let image_builds: Vec<image::BuildImage> = self
-            .image
+        // an anonymous clone of a private repo fails with a cryptic auth error, so fail early
+        let credential = self.git_credential(app);
+        let credentials = self.git_credentials(app, github_token);
+        if let Some(url) = &self.code.url
+            && !self.code.public
+            && credentials.token().is_empty()
+        {
+            return Err(match credential {
+                Some(credential) => format!(
+                    "Private repository `{}` requires `{}` of credential `{}` to be set",
+                    url, credential.token_env, credential.name
+                ),
+                None => format!(
+                    "Private repository `{}` requires GITHUB_TOKEN or GITHUB_TOKEN_FILE to be set",
+                    url
+                ),
+            });
+        }
+
+        // every build gets its own workspace, only a reused clone is shared between builds
+        let project_dir = format!("{}/{}", WORKSPACE_ROOT, self.slug);
+        let repo_dest = match self.code.clone_strategy {
+            CloneStrategy::Fresh => format!("{}/{}", project_dir, build_id),
+            CloneStrategy::Reuse => format!("{}/{}", project_dir, REUSED_CLONE_DIR),
+        };
+        std::fs::create_dir_all(&project_dir).map_err(|e| {
+            format!(
+                "Failed to create workspace directory {}: {}",
+                project_dir, e
+            )
+        })?;
+        remove_failed_workspaces(&project_dir, &repo_dest);
+
+        let mut result = self.build_in_workspace(
+            app,
+            &credentials,
+            request,
+            &repo_dest,
+            clone_limit,
+            events,
+            cancel,
+        );
+        // a killed build process fails with its own error, report the cancellation instead
+        if result.is_err() && cancel.is_cancelled() {
+            result = Err("Build cancelled".to_string());
+        }
+        // a reused clone is kept after a successful build, a failed one may be in a bad state
+        let keep_clone = self.code.clone_strategy == CloneStrategy::Reuse && result.is_ok();
+        if !keep_clone {
+            clean_workspace(app, &repo_dest, &result);
+        }
+        // only succeeds once no other build or kept workspace is left in it
+        let _ = std::fs::remove_dir(&project_dir);
+        result
+    }
+
+    /// Fetch the source into `repo_dest`, build its images, and restart the deployments.
+    #[allow(clippy::too_many_arguments)]
+    fn build_in_workspace(
+        &self,
+        app: &AppConfig,
+        credentials: &Credentials,
+        request: &BuildRequest,
+        repo_dest: &str,
+        clone_limit: Option<&CloneLimit>,
+        events: &EventPublisher,
+        cancel: &CancelHandle,
+    ) -> Result<BuildOutput, String> {
+        // git, curl, and pre-build commands get both the proxy and CA settings
+        let tool_env = [app.proxy.env(), app.ca_env()].concat();
+        let repo_dest = repo_dest.to_string();
+        let tag_values = {
+            // held until the source, submodules included, is fetched
+            let _clone_permit = match clone_limit {
+                Some(limit) => Some(limit.acquire(cancel)?),
+                None => None,
+            };
+            self.fetch_source(credentials, request, &repo_dest, &tool_env)?
+        };
+        cancel.check()?;
+        if let Some(reason) = self.skip_reason(request, &repo_dest)? {
+            tracing::info!("Skipping build of project `{}`: {}", self.slug, reason);
+            return Ok(BuildOutput {
+                commit: tag_values.sha,
+                images: Vec::new(),
+                rollouts: Vec::new(),
+                skipped: Some(reason),
+            });
+        }
+
+        let commit_status = self.commit_status(credentials.token(), &tag_values, &tool_env);
+        if let Some(status) = &commit_status {
+            status.report(CommitState::Pending, "Build started");
+        }
+        let result = self.build_and_roll_out(
+            app,
+            request,
+            &repo_dest,
+            &tag_values,
+            &tool_env,
+            events,
+            cancel,
+        );
+        if let Some(status) = &commit_status {
+            match &result {
+                Ok(_) => status.report(CommitState::Success, "Build and rollout succeeded"),
+                Err(e) => status.report(CommitState::Failure, e),
+            }
+        }
+
+        let (images, rollouts) = result?;
+        Ok(BuildOutput {
+            commit: tag_values.sha,
+            images,
+            rollouts,
+            skipped: None,
+        })
+    }
+
+    /// Where the build's statuses are reported, when `code.report_status` is set and the commit
+    /// is known.
+    fn commit_status<'a>(
+        &self,
+        github_token: &'a str,
+        tag_values: &tag::TagValues,
+        env: &'a [(String, String)],
+    ) -> Option<CommitStatus<'a>> {
+        if !self.code.report_status {
+            return None;
+        }
+        if github_token.is_empty() {
+            tracing::warn!(
+                "Not reporting commit status for project `{}`, it has no token",
+                self.slug
+            );
+            return None;
+        }
+        let url = self.code.url.as_ref()?;
+        let sha = tag_values.sha.as_ref()?;
+
+        match CommitStatus::new(url, sha, github_token, env) {
+            Ok(status) => Some(status),
+            Err(e) => {
+                tracing::warn!(
+                    "Not reporting commit status for project `{}`: {}",
+                    self.slug,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Run the pre-build command, build the images, and restart the deployments.
+    #[allow(clippy::too_many_arguments)]
+    fn build_and_roll_out(
+        &self,
+        app: &AppConfig,
+        request: &BuildRequest,
+        repo_dest: &str,
+        tag_values: &tag::TagValues,
+        tool_env: &[(String, String)],
+        events: &EventPublisher,
+        cancel: &CancelHandle,
+    ) -> Result<(Vec<BuiltImage>, Vec<RolloutResult>), String> {
+        let proxy_env = app.proxy.env();
+        let ca_env = app.ca_env();
+        self.run_pre_build(repo_dest, tool_env, cancel)?;
+        cancel.check()?;
+
+        let default_builder = app
+            .builders()
+            .first()
+            .map(|builder| builder.name.clone())
+            .unwrap_or_default();
+        // indexes of the images built this time, in build order
+        let image_indexes: Vec<usize> = self
+            .build_order()?
+            .into_iter()
+            .filter(|index| request.builds_image(&self.image[*index]))
+            .collect();
+        let image_builds = image_indexes
             .iter()
-            .map(|image| {
-                let image_tag = format!("{}/{}:{}", registry, image.repository, image.tag);
-                let dockerfile_path = Path::new(&repo_dest).join(&image.location);
-                let context_dir = dockerfile_path
-                    .parent()
-                    .unwrap_or_else(|| Path::new(&repo_dest))
-                    .to_string_lossy()
-                    .to_string();
-                image::BuildImage {
+            .map(|&index| (index, &self.image[index]))
+            .map(|(index, image)| {
+                let registry = image.registry.as_deref().unwrap_or(&app.registry);
+                let repository = reference::prefixed_repository(
+                    app.repository_prefix.as_deref(),
+                    &image.repository,
+                );
+                let tag = tag::render(&image.tag, tag_values)?;
+                let image_tag = reference::image_reference(registry, &repository, &tag);
+                reference::validate_reference(&image_tag)?;
+                let additional_tags = image
+                    .tags
+                    .iter()
+                    .map(|tag| {
+                        let tag = tag::render(tag, tag_values)?;
+                        let image_tag = reference::image_reference(registry, &repository, &tag);
+                        reference::validate_reference(&image_tag)?;
+                        Ok(image_tag)
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                // an inline Dockerfile is written next to the workspace, so it isn't part of the
+                // build context
+                let (dockerfile_path, context_dir) = match &image.location {
+                    Some(location) => {
+                        let dockerfile_path = Path::new(repo_dest).join(location);
+                        let context_dir = dockerfile_path
+                            .parent()
+                            .unwrap_or_else(|| Path::new(repo_dest))
+                            .to_string_lossy()
+                            .to_string();
+                        (dockerfile_path, context_dir)
+                    }
+                    None => (
+                        PathBuf::from(format!("{}-{}.Dockerfile", repo_dest, index)),
+                        repo_dest.to_string(),
+                    ),
+                };
+                Ok(image::BuildImage {
                     tag: image_tag,
+                    additional_tags,
                     dockerfile_path: dockerfile_path.to_string_lossy().to_string(),
                     context_dir,
+                    push: image.push,
+                    output: image.output,
+                    output_dest: image.output_dest.clone(),
+                    builder: image
+                        .builder
+                        .clone()
+                        .unwrap_or_else(|| default_builder.clone()),
+                    secrets: image.secrets.clone(),
+                    network: image.network,
+                    entitlements: image.entitlements.clone(),
+                    add_hosts: image.add_hosts.clone(),
+                    exclude: image.exclude.clone(),
+                    immutable_tags: image.immutable_tags && !request.force,
+                    cache_ref: image
+                        .cache_scope
+                        .as_ref()
+                        .map(|scope| reference::cache_reference(registry, scope)),
+                    labels: request.trigger.labels(),
+                    // only pushed images can be measured in the registry
+                    max_image_bytes: self
+                        .max_image_bytes
+                        .filter(|_| image.output == ImageOutput::Registry && image.push),
+                    proxy_env: proxy_env.clone(),
+                    ca_env: ca_env.clone(),
+                    env: self.env.clone(),
+                    clear_env: self.clear_env,
+                    progress: app.build_progress,
+                    log_mode: app.build_log_mode,
+                    log_tail_lines: app.build_log_tail_lines,
+                    max_captured_bytes: app.max_captured_output_bytes,
+                    metadata_file: PathBuf::from(format!("{}-{}.metadata.json", repo_dest, index)),
+                    depends_on: image
+                        .depends_on
+                        .iter()
+                        .filter_map(|name| {
+                            image_indexes
+                                .iter()
+                                .position(|&i| self.image[i].name.as_ref() == Some(name))
+                        })
+                        .collect(),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let inline_dockerfiles = self.write_inline_dockerfiles(repo_dest, request)?;
+        let per_image = self.deployments.deploy_per_image;
+        let mut results = Vec::new();
+        let built = image::build_images(
+            &SystemRunner,
+            app.backend,
+            image_builds,
+            self.on_image_failure,
+            events,
+            cancel,
+            &mut |position, image| {
+                if !per_image {
+                    return Ok(());
+                }
+                cancel.check()?;
+                let index = image_indexes[position];
+                let built = std::slice::from_ref(image);
+                let rollouts = self.rollouts(repo_dest, &[index], built, |resource| {
+                    self.resource_image(resource) == Some(index)
+                })?;
+                self.run_rollouts(&rollouts, events, &mut results)
+            },
+        );
+        for path in inline_dockerfiles {
+            let _ = std::fs::remove_file(path);
+        }
+        let built = built?;
+        cancel.check()?;
+
+        let rollouts = self.rollouts(repo_dest, &image_indexes, &built, |resource| {
+            !per_image || self.resource_image(resource).is_none()
+        })?;
+        self.run_rollouts(&rollouts, events, &mut results)?;
+        Ok((built, results))
+    }
+
+    /// Roll out to `rollouts`, publishing the rollout's events and adding its outcome to
+    /// `results`. Does nothing without rollouts.
+    fn run_rollouts(
+        &self,
+        rollouts: &[kube::Rollout],
+        events: &EventPublisher,
+        results: &mut Vec<RolloutResult>,
+    ) -> Result<(), String> {
+        if rollouts.is_empty() {
+            return Ok(());
+        }
+        let resources: Vec<String> = rollouts
+            .iter()
+            .map(|rollout| rollout.resource.clone())
+            .collect();
+        events.publish(BuildEvent::RolloutStarted {
+            namespace: self.deployments.namespace.clone(),
+            resources: resources.clone(),
+        });
+        let rollout = kube::roll_out(
+            &SystemRunner,
+            &self.deployments.namespace,
+            rollouts,
+            self.deployments.restart_retries,
+            self.deployments.rollout_retries,
+            self.deployments.rollout_parallelism,
+        );
+        events.publish(BuildEvent::RolloutFinished {
+            namespace: self.deployments.namespace.clone(),
+            success: rollout.is_ok(),
+        });
+        results.push(RolloutResult {
+            namespace: self.deployments.namespace.clone(),
+            resources,
+            success: rollout.is_ok(),
+            error: rollout.as_ref().err().cloned(),
+        });
+        rollout
+    }
+
+    /// The rollouts of the deployment resources matching `include` after building the images at
+    /// `image_indexes` into `built`.
+    ///
+    /// `set-image` resources whose image wasn't built this time, e.g. when building a single
+    /// image, are left as they are.
+    fn rollouts(
+        &self,
+        repo_dest: &str,
+        image_indexes: &[usize],
+        built: &[BuiltImage],
+        include: impl Fn(&ResourceConfig) -> bool,
+    ) -> Result<Vec<kube::Rollout>, String> {
+        let mut rollouts = Vec::new();
+        for resource in self.deployments.resources.iter().filter(|r| include(r)) {
+            let rollout = match resource {
+                ResourceConfig::Restart(resource) => kube::Rollout {
+                    resource: resource.clone(),
+                    action: kube::RolloutAction::Restart,
+                },
+                ResourceConfig::Rollout(rollout) => {
+                    let action = match rollout.strategy {
+                        RolloutStrategy::Restart => kube::RolloutAction::Restart,
+                        RolloutStrategy::SetImage => {
+                            let index = self.rollout_image(rollout)?;
+                            let Some(position) = image_indexes.iter().position(|i| *i == index)
+                            else {
+                                tracing::info!(
+                                    "Image `{}` wasn't built, leaving `{}` as it is",
+                                    self.image[index].repository,
+                                    rollout.resource
+                                );
+                                continue;
+                            };
+                            let image = &built[position];
+                            let image = match &image.digest {
+                                Some(digest) => {
+                                    format!("{}@{}", reference::image_name(&image.tag), digest)
+                                }
+                                None => image.tag.clone(),
+                            };
+                            kube::RolloutAction::SetImage {
+                                container: rollout
+                                    .container
+                                    .clone()
+                                    .unwrap_or_else(|| "*".to_string()),
+                                image,
+                            }
+                        }
+                        RolloutStrategy::Apply => kube::RolloutAction::Apply {
+                            manifest: Path::new(repo_dest)
+                                .join(rollout.manifest.as_deref().unwrap_or_default()),
+                        },
+                    };
+                    kube::Rollout {
+                        resource: rollout.resource.clone(),
+                        action,
+                    }
+                }
+            };
+            rollouts.push(rollout);
+        }
+        Ok(rollouts)
+    }
+
+    /// Index of the image `deploy_per_image` pairs a resource with, if any: the image it names, or
+    /// the image a `set-image` resource deploys.
+    fn resource_image(&self, resource: &ResourceConfig) -> Option<usize> {
+        match resource {
+            ResourceConfig::Rollout(rollout)
+                if rollout.image.is_some() || rollout.strategy == RolloutStrategy::SetImage =>
+            {
+                self.rollout_image(rollout).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Index of the image a `set-image` resource deploys.
+    fn rollout_image(&self, rollout: &ResourceRollout) -> Result<usize, String> {
+        match &rollout.image {
+            Some(name) => self
+                .image
+                .iter()
+                .position(|image| image.name.as_ref() == Some(name))
+                .ok_or_else(|| format!("`{}` doesn't name any of the project's images", name)),
+            None if self.image.len() == 1 => Ok(0),
+            None => Err("must name one of the project's images".to_string()),
+        }
+    }
+
+    /// Write the `dockerfile_content` of the images being built next to `repo_dest`, returning
+    /// the written files.
+    fn write_inline_dockerfiles(
+        &self,
+        repo_dest: &str,
+        request: &BuildRequest,
+    ) -> Result<Vec<PathBuf>, String> {
+        let mut written = Vec::new();
+        for (index, image) in self.image.iter().enumerate() {
+            let Some(content) = &image.dockerfile_content else {
+                continue;
+            };
+            if !request.builds_image(image) {
+                continue;
+            }
+
+            let path = PathBuf::from(format!("{}-{}.Dockerfile", repo_dest, index));
+            if let Err(e) = std::fs::write(&path, content) {
+                for path in written {
+                    let _ = std::fs::remove_file(path);
                 }
+                return Err(format!(
+                    "Failed to write inline Dockerfile {}: {}",
+                    path.display(),
+                    e
+                ));
+            }
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// Indexes of the images in the order they're built: every image after the images it
+    /// `depends_on`, in config order otherwise. Fails when the dependencies form a cycle.
+    fn build_order(&self) -> Result<Vec<usize>, String> {
+        let dependencies: Vec<Vec<usize>> = self
+            .image
+            .iter()
+            .map(|image| {
+                image
+                    .depends_on
+                    .iter()
+                    .filter_map(|name| {
+                        self.image
+                            .iter()
+                            .position(|other| other.name.as_ref() == Some(name))
+                    })
+                    .collect()
             })
             .collect();
 
-        image::build_images(image_builds, repo_dest)?;
-        kube::rollout_restart(&self.deployments.namespace, &self.deployments.resources)?;
+        let mut order = Vec::with_capacity(self.image.len());
+        let mut built = vec![false; self.image.len()];
+        while order.len() < self.image.len() {
+            // the first image in config order whose dependencies are all built
+            let next = (0..self.image.len())
+                .find(|&index| !built[index] && dependencies[index].iter().all(|&dep| built[dep]));
+            let Some(index) = next else {
+                let cycle: Vec<String> = (0..self.image.len())
+                    .filter(|&index| !built[index])
+                    .map(|index| match &self.image[index].name {
+                        Some(name) => format!("`{}`", name),
+                        None => format!("image[{}]", index),
+                    })
+                    .collect();
+                return Err(format!(
+                    "`depends_on` has a cycle among {}",
+                    cycle.join(", ")
+                ));
+            };
+            built[index] = true;
+            order.push(index);
+        }
+
+        Ok(order)
+    }
+
+    /// Why the build should be skipped, when `paths` is set and none of them changed since the
+    /// previous build. Builds go ahead when there's nothing to compare against.
+    fn skip_reason(
+        &self,
+        request: &BuildRequest,
+        repo_dest: &str,
+    ) -> Result<Option<String>, String> {
+        if self.paths.is_empty() {
+            return Ok(None);
+        }
+        let Some(previous) = &request.previous_commit else {
+            return Ok(None);
+        };
+
+        let Some(changed) = repo::changed_paths(repo_dest, previous)? else {
+            tracing::info!(
+                "Previous commit {} of project `{}` isn't in the fetched history, building",
+                previous,
+                self.slug
+            );
+            return Ok(None);
+        };
+        if changed
+            .iter()
+            .any(|path| glob::matches_any(&self.paths, path))
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(format!("No relevant changes since {}", previous)))
+    }
+
+    /// Run the `pre_build` command in `repo_dest`, if one is configured.
+    fn run_pre_build(
+        &self,
+        repo_dest: &str,
+        env: &[(String, String)],
+        cancel: &CancelHandle,
+    ) -> Result<(), String> {
+        let Some((program, args)) = self.pre_build.split_first() else {
+            return Ok(());
+        };
+
+        tracing::info!(
+            "Running pre-build command `{}` for project `{}`",
+            self.pre_build.join(" "),
+            self.slug
+        );
+        let mut child = Command::new(program)
+            .args(args)
+            .current_dir(repo_dest)
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .envs(&self.env)
+            .spawn()
+            .map_err(|e| format!("Failed to run pre-build command `{}`: {}", program, e))?;
+        cancel.set_process(Some(child.id()));
+        let status = child.wait();
+        cancel.set_process(None);
+
+        let status = status.map_err(|e| format!("Failed to wait for pre-build command: {}", e))?;
+        if !status.success() {
+            return Err(format!(
+                "Pre-build command `{}` failed with exit code: {:?}",
+                self.pre_build.join(" "),
+                status.code()
+            ));
+        }
+
         Ok(())
     }
 
+    /// Check the project's repository can be reached with the build's credentials, for the deep
+    /// health check. Projects built from a tarball have no repository and return `None`.
+    pub fn check_remote(&self, app: &AppConfig, github_token: &str) -> Option<Result<(), String>> {
+        let url = self.code.url.as_ref()?;
+        let env = [app.proxy.env(), app.ca_env()].concat();
+        let credentials = self.git_credentials(app, github_token);
+        Some(repo::check_remote(&credentials, url, &env))
+    }
+
+    /// The `app.git_credentials` entry the repository is cloned with: the one named by
+    /// `code.credential`, else the one for the repository's host.
+    fn git_credential<'a>(&self, app: &'a AppConfig) -> Option<&'a GitCredential> {
+        if let Some(name) = &self.code.credential {
+            return app
+                .git_credentials
+                .iter()
+                .find(|credential| &credential.name == name);
+        }
+        let host = repo::url_host(self.code.url.as_ref()?)?;
+        app.git_credentials.iter().find(|credential| {
+            credential
+                .host
+                .as_ref()
+                .is_some_and(|credential_host| credential_host.eq_ignore_ascii_case(host))
+        })
+    }
+
+    /// Credentials the repository is cloned with, falling back to `GITHUB_TOKEN` without a
+    /// matching `app.git_credentials` entry.
+    fn git_credentials(&self, app: &AppConfig, github_token: &str) -> Credentials {
+        match self.git_credential(app) {
+            Some(credential) => Credentials::from_credential(credential),
+            None => Credentials::github(github_token),
+        }
+    }
+
+    /// Clone the repository, or download the tarball, into `repo_dest`.
+    fn fetch_source(
+        &self,
+        credentials: &Credentials,
+        request: &BuildRequest,
+        repo_dest: &String,
+        env: &[(String, String)],
+    ) -> Result<tag::TagValues, String> {
+        if let Some(tarball) = &self.code.tarball {
+            if let Some(branch) = &request.branch {
+                tracing::warn!(
+                    "Ignoring branch `{}` for project `{}`, which builds from a tarball",
+                    branch,
+                    self.slug
+                );
+            }
+            repo::download_tarball(tarball, repo_dest, self.code.sha256.as_deref(), env)
+                .map_err(|err| format!("Failed to download tarball: {}", err))?;
+            return Ok(tag::TagValues::new(None, None));
+        }
+
+        let url = self
+            .code
+            .url
+            .as_ref()
+            .ok_or("project.code.url must be set!")?;
+        let branch = request.branch.as_deref().or(self.code.branch.as_deref());
+        repo::clone_repo(
+            credentials,
+            url,
+            repo_dest,
+            branch,
+            self.code.remote(),
+            self.code.clone_strategy,
+            env,
+        )
+        .map_err(|err| format!("Failed to clone repository: {}", err))?;
+
+        // checked before anything from the commit runs, including submodule hooks
+        if self.code.require_signed_commits {
+            repo::verify_commit(
+                repo_dest,
+                self.code.allowed_signers.as_deref(),
+                self.code.gpg_home.as_deref(),
+            )?;
+        }
+
+        if self.code.submodules {
+            repo::update_submodules(credentials, url, repo_dest, env)
+                .map_err(|err| format!("Failed to check out submodules: {}", err))?;
+        }
+
+        let branch = match branch {
+            Some(branch) => branch.to_string(),
+            None => {
+                let branch = repo::head_branch(repo_dest)?;
+                tracing::info!(
+                    "Resolved default branch `{}` for project `{}`",
+                    branch,
+                    self.slug
+                );
+                branch
+            }
+        };
+        let sha = repo::head_sha(repo_dest)?;
+        Ok(tag::TagValues::new(Some(sha), Some(branch)))
+    }
+
+    /// Whether the project has an image with the given name.
+    pub fn has_image(&self, name: &str) -> bool {
+        self.image
+            .iter()
+            .any(|image| image.name.as_deref() == Some(name))
+    }
+
+    /// Whether the project has a branch filter configured.
+    pub fn filters_branches(&self) -> bool {
+        !self.branch_filter.is_empty()
+    }
+
+    /// Whether a push to `branch` should trigger a build.
+    pub fn watches_branch(&self, branch: &str) -> bool {
+        !self.filters_branches() || glob::matches_any(&self.branch_filter, branch)
+    }
+
+    /// Environment variables holding the webhook secrets triggers are signed with, if any.
+    pub fn webhook_secret_envs(&self) -> &[String] {
+        &self.webhook_secret_envs
+    }
+
+    /// Minimum time between builds started by triggers, when configured.
+    pub fn min_interval(&self) -> Option<Duration> {
+        self.min_interval_secs.map(Duration::from_secs)
+    }
+
+    /// Cron schedule the project is also built on, when configured.
+    pub fn schedule(&self) -> Option<Schedule> {
+        // validated when the config is loaded
+        self.schedule
+            .as_deref()
+            .and_then(|schedule| Schedule::parse(schedule).ok())
+    }
+
+    /// Whether a webhook event of type `event` should trigger a build.
+    pub fn builds_on_event(&self, event: &str) -> bool {
+        self.events.iter().any(|watched| watched == event)
+    }
+
+    /// Check the deployment targets are healthy when the project requires it.
+    pub fn check_deployments(&self) -> Result<(), String> {
+        if !self.deployments.require_healthy {
+            return Ok(());
+        }
+
+        kube::check_ready(
+            &SystemRunner,
+            &self.deployments.namespace,
+            &self.resource_names(),
+        )
+    }
+
+    /// Check that the deployment resources to restart exist in the cluster.
+    pub fn check_resources(&self) -> Result<(), String> {
+        kube::check_exist(
+            &SystemRunner,
+            &self.deployments.namespace,
+            &self.resource_names(),
+        )
+    }
+
+    fn resource_names(&self) -> Vec<String> {
+        self.deployments
+            .resources
+            .iter()
+            .map(|resource| resource.name().to_string())
+            .collect()
+    }
+
     pub fn slug(&self) -> &str {
         &self.slug
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(extra: &str) -> AppConfig {
+        toml::from_str(&format!("registry = \"registry.example.com\"\n{}", extra)).unwrap()
+    }
+
+    fn project(contents: &str) -> ProjectConfig {
+        toml::from_str(&format!(
+            "name = \"Web\"\ndeployments = {{ namespace = \"default\", resources = [\"deployment/web\"] }}\n{}",
+            contents
+        ))
+        .unwrap()
+    }
+
+    fn problems(project: &ProjectConfig, index: usize, app: &AppConfig) -> Vec<String> {
+        project
+            .validate(index, app)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn paths_use_position_without_slug() {
+        let project = project(
+            r#"
+slug = ""
+code = { url = "https://github.com/example/web" }
+image = [{ repository = "web", location = "../web", tag = "latest" }]
+"#,
+        );
+
+        assert_eq!(
+            problems(&project, 1, &app("")),
+            [
+                "`projects[1].slug`: must not be empty",
+                "`projects[1].image[0].location`: must not contain parent paths",
+            ]
+        );
+    }
+
+    #[test]
+    fn paths_use_slug() {
+        let project = project(
+            r#"
+slug = "web"
+code = { url = "https://github.com/example/web", tarball = "https://example.com/web.tar.gz" }
+image = [
+    { name = "app", repository = "web", location = ".", tag = "latest" },
+    { name = "app", repository = "web", dockerfile_content = "FROM scratch", location = ".", tag = "latest" },
+]
+"#,
+        );
+
+        assert_eq!(
+            problems(&project, 0, &app("")),
+            [
+                "`projects[web].code`: must set exactly one of `url` or `tarball`",
+                "`projects[web].image[1].name`: `app` is not unique",
+                "`projects[web].image[1].location`: must set exactly one of `location` or `dockerfile_content`",
+            ]
+        );
+    }
+
+    #[test]
+    fn locations_must_match_allowed_locations() {
+        let project = project(
+            r#"
+slug = "web"
+code = { url = "https://github.com/example/web" }
+image = [
+    { repository = "web", location = "services/web", tag = "latest" },
+    { repository = "admin", location = "tools/admin", tag = "latest" },
+]
+"#,
+        );
+
+        assert_eq!(
+            problems(&project, 0, &app("allowed_locations = [\"services/*\"]")),
+            [
+                "`projects[web].image[1].location`: `tools/admin` doesn't match any of `app.allowed_locations`"
+            ]
+        );
+    }
+
+    #[test]
+    fn git_tag_tokens_require_git_source() {
+        let project = project(
+            r#"
+slug = "web"
+code = { tarball = "https://example.com/web.tar.gz" }
+image = [{ repository = "web", location = ".", tag = "{short_sha}" }]
+"#,
+        );
+
+        assert_eq!(
+            problems(&project, 0, &app("")),
+            ["`projects[web].image[0].tag`: tag token `{short_sha}` requires a git source"]
+        );
+    }
 }
@@ -0,0 +1,327 @@
+/// Longest image name (registry and repository) the distribution spec allows.
+const MAX_NAME_LEN: usize = 255;
+/// Longest tag the distribution spec allows.
+const MAX_TAG_LEN: usize = 128;
+/// Repository under the registry that scoped build caches are pushed to, tagged by scope.
+const CACHE_REPOSITORY: &str = "cache";
+
+/// Registry as used in image references: without a URL scheme or trailing slash, which docker
+/// doesn't accept, e.g. "registry.example.com/team" for "https://registry.example.com/team/".
+pub fn registry_host(registry: &str) -> &str {
+    let registry = registry.trim();
+    let registry = registry
+        .strip_prefix("https://")
+        .or_else(|| registry.strip_prefix("http://"))
+        .unwrap_or(registry);
+    registry.trim_end_matches('/')
+}
+
+/// Full reference of an image, e.g. "registry.example.com/my-app/web:latest".
+pub fn image_reference(registry: &str, repository: &str, tag: &str) -> String {
+    format!("{}/{}:{}", registry_host(registry), repository, tag)
+}
+
+/// Image name of a reference, without its tag, e.g. "localhost:5000/my-app" for
+/// "localhost:5000/my-app:latest".
+pub fn image_name(reference: &str) -> &str {
+    // the tag follows the last colon after the last slash, earlier colons are registry ports
+    let last_slash = reference.rfind('/').unwrap_or(0);
+    match reference[last_slash..].rfind(':') {
+        Some(colon) => &reference[..last_slash + colon],
+        None => reference,
+    }
+}
+
+/// Repository of an image under `prefix`, e.g. "org/team/api" for "api" under "org/team".
+/// Repositories starting with `/` are absolute and ignore the prefix.
+pub fn prefixed_repository(prefix: Option<&str>, repository: &str) -> String {
+    if let Some(absolute) = repository.strip_prefix('/') {
+        return absolute.to_string();
+    }
+    match prefix.map(|prefix| prefix.trim_matches('/')) {
+        Some(prefix) if !prefix.is_empty() => format!("{}/{}", prefix, repository),
+        _ => repository.to_string(),
+    }
+}
+
+/// Reference of the shared build cache for `scope` in `registry`, e.g.
+/// "registry.example.com/cache:web".
+pub fn cache_reference(registry: &str, scope: &str) -> String {
+    format!("{}/{}:{}", registry_host(registry), CACHE_REPOSITORY, scope)
+}
+
+/// Check a registry is a host, with an optional port and repository path prefix.
+pub fn validate_registry(registry: &str) -> Result<(), String> {
+    let registry = registry_host(registry);
+    let (host, path) = registry.split_once('/').unwrap_or((registry, ""));
+    validate_host(host)?;
+    if !path.is_empty() {
+        validate_repository(path)?;
+    }
+    Ok(())
+}
+
+/// Check a repository path is made of lowercase components like "my-org/my-app".
+pub fn validate_repository(repository: &str) -> Result<(), String> {
+    if repository.is_empty() {
+        return Err("repository must not be empty".to_string());
+    }
+    for component in repository.split('/') {
+        if !valid_path_component(component) {
+            return Err(format!(
+                "`{}` must be lowercase letters and digits, separated by `.`, `_`, `__`, or `-`",
+                component
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check a rendered image reference is a valid `registry/repository:tag`.
+pub fn validate_reference(reference: &str) -> Result<(), String> {
+    let invalid =
+        |reason: String| format!("`{}` is not a valid image reference: {}", reference, reason);
+
+    // the tag follows the last colon after the last slash, earlier colons are registry ports
+    let last_slash = reference.rfind('/').unwrap_or(0);
+    let (name, tag) = match reference[last_slash..].rfind(':') {
+        Some(colon) => (
+            &reference[..last_slash + colon],
+            &reference[last_slash + colon + 1..],
+        ),
+        None => return Err(invalid("missing tag".to_string())),
+    };
+    if name.len() > MAX_NAME_LEN {
+        return Err(invalid(format!(
+            "name is longer than {} characters",
+            MAX_NAME_LEN
+        )));
+    }
+    let (registry, repository) = name
+        .split_once('/')
+        .ok_or_else(|| invalid("missing registry".to_string()))?;
+    validate_host(registry).map_err(invalid)?;
+    validate_repository(repository).map_err(invalid)?;
+    validate_tag(tag).map_err(invalid)
+}
+
+/// Check a tag is valid in an image reference, e.g. "v1.2.3".
+pub fn validate_tag(tag: &str) -> Result<(), String> {
+    let valid = tag.len() <= MAX_TAG_LEN
+        && tag
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(format!(
+            "tag `{}` must be up to {} letters, digits, `_`, `.`, or `-`, not starting with `.` or `-`",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    Ok(())
+}
+
+fn validate_host(host: &str) -> Result<(), String> {
+    let (domain, port) = match host.rsplit_once(':') {
+        Some((domain, port)) => (domain, Some(port)),
+        None => (host, None),
+    };
+    let valid_domain = !domain.is_empty()
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_domain {
+        return Err(format!("`{}` is not a valid registry host", host));
+    }
+    if port.is_some_and(|port| port.parse::<u16>().is_err()) {
+        return Err(format!("`{}` has an invalid port", host));
+    }
+    Ok(())
+}
+
+/// Whether `component` is lowercase alphanumerics joined by single separators: `.`, `_`, `__`,
+/// or one or more `-`.
+fn valid_path_component(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    let alphanumeric = |c: &u8| c.is_ascii_lowercase() || c.is_ascii_digit();
+    if !bytes.first().is_some_and(alphanumeric) || !bytes.last().is_some_and(alphanumeric) {
+        return false;
+    }
+
+    let mut separator = String::new();
+    for c in component.chars() {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            if !matches!(separator.as_str(), "" | "." | "_" | "__")
+                && !separator.chars().all(|s| s == '-')
+            {
+                return false;
+            }
+            separator.clear();
+        } else if matches!(c, '.' | '_' | '-') {
+            separator.push(c);
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_registry_scheme_and_slash() {
+        for (registry, host) in [
+            ("registry.example.com", "registry.example.com"),
+            ("https://registry.example.com/", "registry.example.com"),
+            ("http://localhost:5000", "localhost:5000"),
+            (
+                " https://registry.example.com/team/ ",
+                "registry.example.com/team",
+            ),
+        ] {
+            assert_eq!(registry_host(registry), host, "{}", registry);
+        }
+        assert_eq!(
+            image_reference("https://localhost:5000/", "app", "latest"),
+            "localhost:5000/app:latest"
+        );
+    }
+
+    #[test]
+    fn image_name_drops_tag_but_keeps_port() {
+        for (reference, name) in [
+            (
+                "registry.example.com/app:latest",
+                "registry.example.com/app",
+            ),
+            ("localhost:5000/app:tag", "localhost:5000/app"),
+            ("localhost:5000/team/app:v1.2", "localhost:5000/team/app"),
+            ("localhost:5000/app", "localhost:5000/app"),
+            ("app:tag", "app"),
+            ("app", "app"),
+        ] {
+            assert_eq!(image_name(reference), name, "{}", reference);
+        }
+    }
+
+    #[test]
+    fn validates_registries() {
+        for registry in [
+            "registry.example.com",
+            "localhost:5000",
+            "https://localhost:5000/",
+            "registry.example.com/team/sub-team",
+            "10.0.0.5:443/app",
+        ] {
+            assert_eq!(validate_registry(registry), Ok(()), "{}", registry);
+        }
+
+        for (registry, error) in [
+            ("", "`` is not a valid registry host"),
+            (
+                "-registry.example.com",
+                "`-registry.example.com` is not a valid registry host",
+            ),
+            (
+                "registry..example.com",
+                "`registry..example.com` is not a valid registry host",
+            ),
+            (
+                "registry_example.com",
+                "`registry_example.com` is not a valid registry host",
+            ),
+            ("localhost:", "`localhost:` has an invalid port"),
+            ("localhost:99999", "`localhost:99999` has an invalid port"),
+            ("localhost:http", "`localhost:http` has an invalid port"),
+            (
+                "registry.example.com/Team",
+                "`Team` must be lowercase letters and digits, separated by `.`, `_`, `__`, or `-`",
+            ),
+        ] {
+            assert_eq!(
+                validate_registry(registry),
+                Err(error.to_string()),
+                "{}",
+                registry
+            );
+        }
+    }
+
+    #[test]
+    fn validates_repositories() {
+        for repository in ["app", "my-org/my-app", "a__b", "a---b", "v1.2/x_y"] {
+            assert_eq!(validate_repository(repository), Ok(()), "{}", repository);
+        }
+        for repository in ["", "App", "-app", "app-", "a___b", "a_-b", "a//b", "a/b/"] {
+            assert!(validate_repository(repository).is_err(), "{}", repository);
+        }
+    }
+
+    #[test]
+    fn validates_references() {
+        assert_eq!(validate_reference("localhost:5000/app:tag"), Ok(()));
+        assert_eq!(
+            validate_reference("registry.example.com/team/app:feature-login"),
+            Ok(())
+        );
+
+        for (reference, reason) in [
+            ("localhost:5000/app", "missing tag"),
+            ("app:tag", "missing registry"),
+            ("localhost:5000/app:-tag", "tag `-tag` must be up to 128"),
+            ("localhost:5000/App:tag", "`App` must be lowercase"),
+        ] {
+            let error = validate_reference(reference).unwrap_err();
+            assert!(
+                error.starts_with(&format!(
+                    "`{}` is not a valid image reference: {}",
+                    reference, reason
+                )),
+                "{}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn validates_tags() {
+        assert_eq!(validate_tag("_v1.2.3-rc"), Ok(()));
+        assert_eq!(validate_tag(&"a".repeat(MAX_TAG_LEN)), Ok(()));
+        for tag in [
+            "",
+            ".v1",
+            "-v1",
+            "v1/2",
+            "v1+build",
+            &"a".repeat(MAX_TAG_LEN + 1),
+        ] {
+            assert!(validate_tag(tag).is_err(), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn prefixes_repositories() {
+        assert_eq!(
+            prefixed_repository(Some("org/team/"), "api"),
+            "org/team/api"
+        );
+        assert_eq!(prefixed_repository(Some("/"), "api"), "api");
+        assert_eq!(prefixed_repository(None, "api"), "api");
+        assert_eq!(
+            prefixed_repository(Some("org"), "/shared/api"),
+            "shared/api"
+        );
+        assert_eq!(
+            cache_reference("https://registry.example.com/", "web"),
+            "registry.example.com/cache:web"
+        );
+    }
+}
@@ -1,14 +1,184 @@
 use std::fs;
 use std::path::Path;
 use std::process::{Command, Output};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::CancelHandle;
+
+pub const DEFAULT_REMOTE: &str = "origin";
+
+/// How often a build waiting for a clone slot checks whether it was cancelled.
+const CLONE_WAIT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Limit on the clones running at once across all builds, so a fan-out of builds doesn't
+/// saturate disk and network.
+pub struct CloneLimit {
+    available: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl CloneLimit {
+    pub fn new(limit: usize) -> Self {
+        CloneLimit {
+            available: Mutex::new(limit),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot, giving up when the build is cancelled. The slot is freed when the
+    /// permit is dropped.
+    pub fn acquire(&self, cancel: &CancelHandle) -> Result<ClonePermit<'_>, String> {
+        let mut available = self.available.lock().unwrap();
+        if *available == 0 {
+            tracing::info!("Clone limit reached, waiting for a running clone to finish");
+        }
+        while *available == 0 {
+            cancel.check()?;
+            available = self
+                .freed
+                .wait_timeout(available, CLONE_WAIT_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        *available -= 1;
+        Ok(ClonePermit { limit: self })
+    }
+}
+
+/// A slot of a `CloneLimit`, freed on drop.
+pub struct ClonePermit<'a> {
+    limit: &'a CloneLimit,
+}
+
+impl Drop for ClonePermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.available.lock().unwrap() += 1;
+        self.limit.freed.notify_one();
+    }
+}
+
+/// How the repository is fetched for each build.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CloneStrategy {
+    /// Clone into an empty workspace every build.
+    #[default]
+    Fresh,
+    /// Keep the clone between builds and fetch into it, cloning afresh when that fails.
+    Reuse,
+}
+
+/// Username sent with a token when a credential doesn't set one, as GitHub expects.
+const DEFAULT_USERNAME: &str = "x-access-token";
+
+/// Token for cloning from a git host, e.g. an internal GitLab, instead of `GITHUB_TOKEN`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct GitCredential {
+    /// Name projects pick the credential by with `code.credential`.
+    pub name: String,
+    /// Host (with its port, if any) whose repositories use the credential when a project doesn't
+    /// name one, e.g. "gitlab.example.com".
+    pub host: Option<String>,
+    /// Environment variable holding the token, so it isn't written to the config file.
+    pub token_env: String,
+    /// Username sent with the token, e.g. "oauth2" for GitLab OAuth tokens.
+    /// Defaults to "x-access-token".
+    pub username: Option<String>,
+}
+
+/// Username and token embedded in clone URLs. Clones are anonymous without a token.
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    username: String,
+    token: String,
+}
+
+impl Credentials {
+    /// Credentials from `GITHUB_TOKEN` or `GITHUB_TOKEN_FILE`, see `load_github_token`.
+    pub fn github(token: &str) -> Self {
+        Credentials {
+            username: DEFAULT_USERNAME.to_string(),
+            token: token.to_string(),
+        }
+    }
+
+    /// Credentials of `credential`, reading its token from the environment so it can be rotated
+    /// without a reload.
+    pub fn from_credential(credential: &GitCredential) -> Self {
+        Credentials {
+            username: credential
+                .username
+                .clone()
+                .unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
+            token: std::env::var(&credential.token_env)
+                .map(|token| token.trim().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+/// Host of a URL with its port, without credentials, e.g. "gitlab.example.com:8443".
+pub fn url_host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split('/').next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    Some(host).filter(|host| !host.is_empty())
+}
+
+/// Environment variable naming a file holding the GitHub token, e.g. a mounted secret.
+const GITHUB_TOKEN_FILE: &str = "GITHUB_TOKEN_FILE";
+const GITHUB_TOKEN: &str = "GITHUB_TOKEN";
+
+/// Resolve the GitHub token from `GITHUB_TOKEN_FILE` if set, falling back to `GITHUB_TOKEN`.
+///
+/// Returns an empty token when neither is set.
+pub fn load_github_token() -> Result<String, String> {
+    if let Some(path) = std::env::var_os(GITHUB_TOKEN_FILE) {
+        let path = Path::new(&path);
+        let token = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "Failed to read {} `{}`: {}",
+                GITHUB_TOKEN_FILE,
+                path.display(),
+                err
+            )
+        })?;
+        return Ok(token.trim().to_string());
+    }
+
+    Ok(std::env::var(GITHUB_TOKEN).unwrap_or_default())
+}
 
 pub fn clone_repo(
-    github_token: &str,
+    credentials: &Credentials,
     src: &String,
     dest: &String,
-    branch: &str,
+    branch: Option<&str>,
+    remote: &str,
+    strategy: CloneStrategy,
+    env: &[(String, String)],
 ) -> Result<(), String> {
     let dest_path = Path::new(dest);
+    if strategy == CloneStrategy::Reuse && dest_path.join(".git").is_dir() {
+        match update_clone(credentials, src, dest, branch, remote, env) {
+            Ok(()) => return Ok(()),
+            Err(err) => tracing::warn!(
+                "Failed to update existing clone at `{}`, cloning afresh: {}",
+                dest,
+                err
+            ),
+        }
+    }
+
     if dest_path.exists() {
         tracing::info!("Removing existing repo at `{}`", dest);
         if dest_path.is_dir() {
@@ -23,12 +193,19 @@ pub fn clone_repo(
 
     tracing::info!("Cloning `{}` to `{:?}`", src, dest);
 
-    let clone_url = with_github_credentials(src, github_token)?;
+    let clone_url = with_credentials(src, credentials)?;
+    let mut command = Command::new("git");
+    command.arg("clone");
+    // without a branch, git checks out the remote's default branch
+    if let Some(branch) = branch {
+        command.args(["--branch", branch]);
+    }
     let output = run_command_output(
-        Command::new("git")
-            .args(["clone", "--branch", branch, "--single-branch"])
+        command
+            .args(["--single-branch", "--origin", remote])
             .arg(clone_url)
             .arg(dest)
+            .envs(env.iter().map(|(name, value)| (name, value)))
             .env("GIT_TERMINAL_PROMPT", "0"),
         "git clone",
     )?;
@@ -40,6 +217,336 @@ pub fn clone_repo(
     Ok(())
 }
 
+/// Fetch into the existing clone at `dest` and reset it to the fetched commit, discarding local
+/// changes and following force pushes.
+fn update_clone(
+    credentials: &Credentials,
+    src: &str,
+    dest: &str,
+    branch: Option<&str>,
+    remote: &str,
+    env: &[(String, String)],
+) -> Result<(), String> {
+    tracing::info!("Fetching `{}` into existing clone `{}`", src, dest);
+
+    // the token may have been rotated since the clone
+    let clone_url = with_credentials(src, credentials)?;
+    run_git(dest, &["remote", "set-url", remote, &clone_url], env)?;
+    // without a branch, fetch whatever the remote's default branch is now
+    run_git(
+        dest,
+        &["fetch", "--force", remote, branch.unwrap_or("HEAD")],
+        env,
+    )?;
+    match branch {
+        Some(branch) => run_git(
+            dest,
+            &["checkout", "--force", "-B", branch, "FETCH_HEAD"],
+            env,
+        )?,
+        None => run_git(dest, &["reset", "--hard", "FETCH_HEAD"], env)?,
+    }
+    run_git(dest, &["clean", "-ffdx"], env)
+}
+
+/// Run a git command in the repository at `dest`, failing with its error output.
+fn run_git(dest: &str, args: &[&str], env: &[(String, String)]) -> Result<(), String> {
+    let output = run_command_output(
+        Command::new("git")
+            .args(["-C", dest])
+            .args(args)
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .env("GIT_TERMINAL_PROMPT", "0"),
+        &format!("git {}", args[0]),
+    )?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check the repository at `src` can be reached with `credentials`, listing its `HEAD` without
+/// cloning anything.
+pub fn check_remote(
+    credentials: &Credentials,
+    src: &str,
+    env: &[(String, String)],
+) -> Result<(), String> {
+    let remote_url = with_credentials(src, credentials)?;
+    let output = run_command_output(
+        Command::new("git")
+            .args(["ls-remote", "--exit-code"])
+            .arg(remote_url)
+            .arg("HEAD")
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .env("GIT_TERMINAL_PROMPT", "0"),
+        "git ls-remote",
+    )?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git ls-remote failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Initialize and check out the submodules of the repository at `dest`, recursively.
+///
+/// Submodules on the same host as `src` are fetched with the same credentials as the main clone.
+pub fn update_submodules(
+    credentials: &Credentials,
+    src: &str,
+    dest: &str,
+    env: &[(String, String)],
+) -> Result<(), String> {
+    tracing::info!("Updating submodules of `{}`", dest);
+
+    let mut command = Command::new("git");
+    command.args(["-C", dest]);
+    if let Some(host_url) = host_url(src) {
+        let credentials_url = with_credentials(&host_url, credentials)?;
+        if credentials_url != host_url {
+            command.args([
+                "-c",
+                &format!("url.{}.insteadOf={}", credentials_url, host_url),
+            ]);
+        }
+    }
+    let output = run_command_output(
+        command
+            .args(["submodule", "update", "--init", "--recursive"])
+            .envs(env.iter().map(|(name, value)| (name, value)))
+            .env("GIT_TERMINAL_PROMPT", "0"),
+        "git submodule update",
+    )?;
+
+    if !output.status.success() {
+        return Err("Failed to update submodules".to_string());
+    }
+
+    Ok(())
+}
+
+/// Scheme and host of a URL with a trailing slash, e.g. "https://github.com/".
+fn host_url(src: &str) -> Option<String> {
+    let (scheme, rest) = src.split_once("://")?;
+    let host = rest.split('/').next().filter(|host| !host.is_empty())?;
+    Some(format!("{}://{}/", scheme, host))
+}
+
+/// Paths changed between `from` and the checked out commit of the repository at `dest`.
+///
+/// Returns `None` when `from` isn't in the fetched history, e.g. after a force push.
+pub fn changed_paths(dest: &str, from: &str) -> Result<Option<Vec<String>>, String> {
+    let exists = run_command_output(
+        Command::new("git").args([
+            "-C",
+            dest,
+            "cat-file",
+            "-e",
+            &format!("{}^{{commit}}", from),
+        ]),
+        "git cat-file",
+    )?;
+    if !exists.status.success() {
+        return Ok(None);
+    }
+
+    let output = run_command_output(
+        Command::new("git").args(["-C", dest, "diff", "--name-only", from, "HEAD"]),
+        "git diff",
+    )?;
+    if !output.status.success() {
+        return Err("Failed to list changed paths".to_string());
+    }
+
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect(),
+    ))
+}
+
+/// Full SHA of the commit checked out in the repository at `dest`.
+pub fn head_sha(dest: &str) -> Result<String, String> {
+    let output = run_command_output(
+        Command::new("git").args(["-C", dest, "rev-parse", "HEAD"]),
+        "git rev-parse",
+    )?;
+
+    if !output.status.success() {
+        return Err("Failed to read checked out commit".to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Verify the checked out commit of the repository at `dest` is signed by a trusted key: an SSH
+/// key listed in `allowed_signers`, or a GPG key in the keyring of `gpg_home`.
+pub fn verify_commit(
+    dest: &str,
+    allowed_signers: Option<&Path>,
+    gpg_home: Option<&Path>,
+) -> Result<(), String> {
+    let mut command = Command::new("git");
+    command.args(["-C", dest]);
+    if let Some(allowed_signers) = allowed_signers {
+        command.arg("-c").arg(format!(
+            "gpg.ssh.allowedSignersFile={}",
+            allowed_signers.display()
+        ));
+    }
+    if let Some(gpg_home) = gpg_home {
+        command.env("GNUPGHOME", gpg_home);
+    }
+    let output = run_command_output(command.args(["verify-commit", "HEAD"]), "git verify-commit")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("no signature found");
+        return Err(format!(
+            "Commit isn't signed by a trusted key: {}",
+            reason.trim()
+        ));
+    }
+
+    tracing::info!("Verified signature of the checked out commit in `{}`", dest);
+    Ok(())
+}
+
+/// Name of the branch checked out in the repository at `dest`.
+pub fn head_branch(dest: &str) -> Result<String, String> {
+    let output = run_command_output(
+        Command::new("git").args(["-C", dest, "rev-parse", "--abbrev-ref", "HEAD"]),
+        "git rev-parse",
+    )?;
+
+    if !output.status.success() {
+        return Err("Failed to read checked out branch".to_string());
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Download a tarball and extract it to `dest`, verifying its SHA-256 checksum when given.
+///
+/// If the archive holds a single top-level directory (like GitHub source archives), that
+/// directory becomes `dest`.
+pub fn download_tarball(
+    src: &str,
+    dest: &String,
+    sha256: Option<&str>,
+    env: &[(String, String)],
+) -> Result<(), String> {
+    let archive = format!("{}.tar.gz", dest);
+    let extract_dir = format!("{}.extract", dest);
+    for path in [dest, &archive, &extract_dir] {
+        remove_path(path)?;
+    }
+
+    tracing::info!("Downloading `{}` to `{}`", src, archive);
+    let output = run_command_output(
+        Command::new("curl")
+            .args([
+                "--fail",
+                "--silent",
+                "--show-error",
+                "--location",
+                "--output",
+            ])
+            .arg(&archive)
+            .arg(src)
+            .envs(env.iter().map(|(name, value)| (name, value))),
+        "curl",
+    )?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to download tarball: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let result = verify_and_extract(&archive, &extract_dir, dest, sha256);
+    let _ = fs::remove_file(&archive);
+    let _ = fs::remove_dir_all(&extract_dir);
+    result
+}
+
+fn verify_and_extract(
+    archive: &str,
+    extract_dir: &str,
+    dest: &str,
+    sha256: Option<&str>,
+) -> Result<(), String> {
+    if let Some(expected) = sha256 {
+        let output = run_command_output(Command::new("sha256sum").arg(archive), "sha256sum")?;
+        if !output.status.success() {
+            return Err("Failed to compute tarball checksum".to_string());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let actual = stdout.split_whitespace().next().unwrap_or_default();
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(format!(
+                "Tarball checksum mismatch: expected {}, got {}",
+                expected, actual
+            ));
+        }
+        tracing::info!("Verified tarball checksum {}", actual);
+    }
+
+    fs::create_dir_all(extract_dir)
+        .map_err(|err| format!("Failed to create `{}`: {}", extract_dir, err))?;
+    let output = run_command_output(
+        Command::new("tar").args(["-xzf", archive, "-C", extract_dir]),
+        "tar",
+    )?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to extract tarball: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let entries = fs::read_dir(extract_dir)
+        .map_err(|err| format!("Failed to read `{}`: {}", extract_dir, err))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    let root = match entries.as_slice() {
+        [single] if single.is_dir() => single.clone(),
+        _ => Path::new(extract_dir).to_path_buf(),
+    };
+
+    fs::rename(&root, dest)
+        .map_err(|err| format!("Failed to move extracted source to `{}`: {}", dest, err))
+}
+
+fn remove_path(path: &str) -> Result<(), String> {
+    let path = Path::new(path);
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else if path.exists() {
+        fs::remove_file(path)
+    } else {
+        return Ok(());
+    }
+    .map_err(|err| format!("Failed to remove `{}`: {}", path.display(), err))
+}
+
 fn run_command_output(command: &mut Command, description: &str) -> Result<Output, String> {
     let output = command
         .output()
@@ -52,15 +559,15 @@ fn run_command_output(command: &mut Command, description: &str) -> Result<Output
     Ok(output)
 }
 
-fn with_github_credentials(src: &str, github_token: &str) -> Result<String, String> {
-    if github_token.is_empty() {
+fn with_credentials(src: &str, credentials: &Credentials) -> Result<String, String> {
+    if credentials.token.is_empty() {
         return Ok(src.to_string());
     }
 
     if let Some((scheme, rest)) = src.split_once("://") {
         return Ok(format!(
-            "{}://x-access-token:{}@{}",
-            scheme, github_token, rest
+            "{}://{}:{}@{}",
+            scheme, credentials.username, credentials.token, rest
         ));
     }
 
@@ -0,0 +1,224 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::schedule;
+
+/// Template tokens supported in `image.tag`.
+const TOKENS: &[&str] = &["sha", "short_sha", "branch", "date", "timestamp"];
+
+/// Tokens only available when building from a git repository.
+const GIT_TOKENS: &[&str] = &["sha", "short_sha", "branch"];
+
+/// Length of the `{short_sha}` token.
+const SHORT_SHA_LENGTH: usize = 7;
+
+/// Values substituted into image tag templates for a single build.
+pub struct TagValues {
+    /// Full commit SHA of the checked-out source, when built from git.
+    pub sha: Option<String>,
+    /// Branch that was built, when built from git.
+    pub branch: Option<String>,
+    /// Unix timestamp (seconds) of the build.
+    pub timestamp: u64,
+}
+
+impl TagValues {
+    pub fn new(sha: Option<String>, branch: Option<String>) -> Self {
+        TagValues {
+            sha,
+            branch,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Check that a tag template only uses known tokens and has balanced braces.
+pub fn validate(template: &str, git_source: bool) -> Result<(), String> {
+    for token in tokens(template)? {
+        if !TOKENS.contains(&token) {
+            return Err(format!(
+                "unknown tag token `{{{}}}`, expected one of: {}",
+                token,
+                TOKENS.join(", ")
+            ));
+        }
+        if !git_source && GIT_TOKENS.contains(&token) {
+            return Err(format!("tag token `{{{}}}` requires a git source", token));
+        }
+    }
+    Ok(())
+}
+
+/// Resolve the tokens of a tag template, e.g. "v1-{short_sha}" to "v1-abc1234".
+pub fn render(template: &str, values: &TagValues) -> Result<String, String> {
+    let mut tag = template.to_string();
+    for token in tokens(template)? {
+        let value = match token {
+            "sha" => values.sha.clone(),
+            "short_sha" => values
+                .sha
+                .as_ref()
+                .map(|sha| sha.chars().take(SHORT_SHA_LENGTH).collect()),
+            "branch" => values.branch.as_deref().map(sanitize),
+            "date" => Some(date(values.timestamp)),
+            "timestamp" => Some(values.timestamp.to_string()),
+            _ => return Err(format!("unknown tag token `{{{}}}`", token)),
+        }
+        .ok_or_else(|| format!("tag token `{{{}}}` requires a git source", token))?;
+        tag = tag.replace(&format!("{{{}}}", token), &value);
+    }
+    Ok(tag)
+}
+
+fn tokens(template: &str) -> Result<Vec<&str>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if rest[..start].contains('}') {
+            return Err(format!("unopened `}}` in tag `{}`", template));
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("unclosed `{{` in tag `{}`", template))?;
+        tokens.push(&rest[start + 1..start + end]);
+        rest = &rest[start + end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(format!("unopened `}}` in tag `{}`", template));
+    }
+    Ok(tokens)
+}
+
+/// Replace characters not allowed in image tags, e.g. "feature/login" to "feature-login".
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Format a unix timestamp as a UTC date, e.g. "20240131".
+fn date(timestamp: u64) -> String {
+    let (year, month, day) = schedule::civil_date((timestamp / 86_400) as i64);
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-02-29 13:20 UTC.
+    const TIMESTAMP: u64 = 1_709_212_800;
+
+    fn values(sha: Option<&str>, branch: Option<&str>) -> TagValues {
+        TagValues {
+            sha: sha.map(str::to_string),
+            branch: branch.map(str::to_string),
+            timestamp: TIMESTAMP,
+        }
+    }
+
+    #[test]
+    fn finds_tokens() {
+        assert_eq!(tokens("latest"), Ok(Vec::new()));
+        assert_eq!(
+            tokens("{branch}-{short_sha}"),
+            Ok(vec!["branch", "short_sha"])
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_braces() {
+        assert_eq!(
+            tokens("v1-{sha"),
+            Err("unclosed `{` in tag `v1-{sha`".to_string())
+        );
+        for template in ["v1-sha}", "v1}-{sha}", "{sha}}"] {
+            assert_eq!(
+                tokens(template),
+                Err(format!("unopened `}}` in tag `{}`", template))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tokens() {
+        assert_eq!(
+            validate("{version}", true),
+            Err(
+                "unknown tag token `{version}`, expected one of: sha, short_sha, branch, date, timestamp"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            render("{version}", &values(None, None)),
+            Err("unknown tag token `{version}`".to_string())
+        );
+    }
+
+    #[test]
+    fn git_tokens_require_git_source() {
+        assert!(validate("{date}-{timestamp}", false).is_ok());
+        assert_eq!(
+            validate("{branch}", false),
+            Err("tag token `{branch}` requires a git source".to_string())
+        );
+        assert_eq!(
+            render("{short_sha}", &values(None, None)),
+            Err("tag token `{short_sha}` requires a git source".to_string())
+        );
+    }
+
+    #[test]
+    fn renders_tokens() {
+        let values = values(
+            Some("0123456789abcdef0123456789abcdef01234567"),
+            Some("feature/login"),
+        );
+
+        assert_eq!(
+            render("{branch}-{short_sha}", &values).unwrap(),
+            "feature-login-0123456"
+        );
+        assert_eq!(
+            render("{sha}", &values).unwrap(),
+            "0123456789abcdef0123456789abcdef01234567"
+        );
+        assert_eq!(
+            render("{date}.{timestamp}", &values).unwrap(),
+            "20240229.1709212800"
+        );
+        assert_eq!(render("latest", &values).unwrap(), "latest");
+    }
+
+    #[test]
+    fn short_sha_keeps_shorter_shas() {
+        assert_eq!(
+            render("{short_sha}", &values(Some("abc"), None)).unwrap(),
+            "abc"
+        );
+    }
+
+    #[test]
+    fn sanitizes_branches() {
+        assert_eq!(sanitize("feature/login"), "feature-login");
+        assert_eq!(sanitize("release_1.2-rc"), "release_1.2-rc");
+        assert_eq!(sanitize("fix/ünïcode @home"), "fix--n-code--home");
+    }
+
+    #[test]
+    fn formats_dates() {
+        assert_eq!(date(0), "19700101");
+        assert_eq!(date(TIMESTAMP), "20240229");
+        assert_eq!(date(1_735_689_599), "20241231");
+        assert_eq!(date(1_735_689_600), "20250101");
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// HTTP proxy settings for git clones and image builds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct ProxyConfig {
+    /// Proxy for HTTPS requests, e.g. "http://proxy.internal:3128".
+    #[serde(serialize_with = "crate::config::redact_url_credentials")]
+    pub https_proxy: Option<String>,
+    /// Proxy for plain HTTP requests.
+    #[serde(serialize_with = "crate::config::redact_url_credentials")]
+    pub http_proxy: Option<String>,
+    /// Comma-separated hosts that bypass the proxy, e.g. "localhost,.svc.cluster.local".
+    pub no_proxy: Option<String>,
+}
+
+impl ProxyConfig {
+    /// Proxy environment variables to pass to child processes.
+    ///
+    /// Configured values take precedence over the service's own `HTTPS_PROXY`, `HTTP_PROXY` and
+    /// `NO_PROXY` environment (upper or lower case).
+    pub fn env(&self) -> Vec<(String, String)> {
+        [
+            ("HTTPS_PROXY", &self.https_proxy),
+            ("HTTP_PROXY", &self.http_proxy),
+            ("NO_PROXY", &self.no_proxy),
+        ]
+        .into_iter()
+        .filter_map(|(name, configured)| {
+            configured
+                .clone()
+                .or_else(|| std::env::var(name).ok())
+                .or_else(|| std::env::var(name.to_lowercase()).ok())
+                .filter(|value| !value.is_empty())
+                .map(|value| (name.to_string(), value))
+        })
+        .collect()
+    }
+}
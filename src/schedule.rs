@@ -0,0 +1,322 @@
+use std::ops::RangeInclusive;
+
+/// How far ahead to look for a schedule's next fire time, so schedules that can never fire, e.g.
+/// "0 0 31 2 *", don't loop forever.
+const MAX_LOOKAHEAD_DAYS: i64 = 366 * 8;
+
+/// A cron schedule in UTC, with the five standard fields: minute, hour, day of month, month, and
+/// day of week, e.g. "0 3 * * *" for every night at 03:00.
+///
+/// Fields take `*`, values, ranges (`1-5`), steps (`*/15`, `0-30/10`), and comma-separated lists
+/// of those. Day of week runs from 0 (Sunday) to 6, with 7 also Sunday.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    /// Whether day of month or day of week is restricted. When both are, cron fires on days
+    /// matching either.
+    day_of_month_restricted: bool,
+    day_of_week_restricted: bool,
+}
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(format!(
+                "`{}` must have 5 fields (minute, hour, day of month, month, day of week), found {}",
+                expression,
+                fields.len()
+            ));
+        };
+
+        let mut days_of_week = parse_field(day_of_week, "day of week", 0..=7)?;
+        // 7 is another name for Sunday
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        Ok(Schedule {
+            minutes: parse_field(minute, "minute", 0..=59)?,
+            hours: parse_field(hour, "hour", 0..=23)?,
+            days_of_month: parse_field(day_of_month, "day of month", 1..=31)?,
+            months: parse_field(month, "month", 1..=12)?,
+            days_of_week,
+            day_of_month_restricted: day_of_month != "*",
+            day_of_week_restricted: day_of_week != "*",
+        })
+    }
+
+    /// Whether the schedule fires in the minute holding the unix `timestamp`.
+    pub fn matches(&self, timestamp: u64) -> bool {
+        let minute_of_day = (timestamp % 86_400) / 60;
+        self.matches_day((timestamp / 86_400) as i64)
+            && self.hours[(minute_of_day / 60) as usize]
+            && self.minutes[(minute_of_day % 60) as usize]
+    }
+
+    /// Start of the first minute after the unix `timestamp` the schedule fires in, or `None` when
+    /// it doesn't fire in the next few years.
+    pub fn next_after(&self, timestamp: u64) -> Option<u64> {
+        let next_minute = timestamp / 60 * 60 + 60;
+        let first_day = (next_minute / 86_400) as i64;
+        for day in first_day..first_day + MAX_LOOKAHEAD_DAYS {
+            if !self.matches_day(day) {
+                continue;
+            }
+            let day_start = day as u64 * 86_400;
+            let minute_of_day = (0..24 * 60).find(|minute_of_day| {
+                day_start + minute_of_day * 60 >= next_minute
+                    && self.hours[(minute_of_day / 60) as usize]
+                    && self.minutes[(minute_of_day % 60) as usize]
+            });
+            if let Some(minute_of_day) = minute_of_day {
+                return Some(day_start + minute_of_day * 60);
+            }
+        }
+        None
+    }
+
+    /// Whether the schedule fires on `day`, counted in days since the unix epoch.
+    fn matches_day(&self, day: i64) -> bool {
+        let (_, month, day_of_month) = civil_date(day);
+        // the epoch was a Thursday
+        let day_of_week = (day + 4).rem_euclid(7) as usize;
+        let day_of_month = self.days_of_month[day_of_month as usize];
+        let day_of_week = self.days_of_week[day_of_week];
+        let day = match (self.day_of_month_restricted, self.day_of_week_restricted) {
+            (true, true) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        };
+        day && self.months[month as usize]
+    }
+}
+
+/// Format a unix timestamp as a UTC time to the minute, e.g. "2024-01-31 03:00 UTC".
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (year, month, day) = civil_date((timestamp / 86_400) as i64);
+    let minute_of_day = (timestamp % 86_400) / 60;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minute_of_day / 60,
+        minute_of_day % 60
+    )
+}
+
+/// Year, month, and day of `days` since the unix epoch, from Howard Hinnant's `civil_from_days`.
+pub fn civil_date(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// Values of one cron field, indexed by value, e.g. "1-5" sets `[1]` through `[5]`.
+fn parse_field(field: &str, name: &str, range: RangeInclusive<u32>) -> Result<Vec<bool>, String> {
+    let invalid = |reason: String| format!("{} `{}` {}", name, field, reason);
+    let mut values = vec![false; *range.end() as usize + 1];
+    for part in field.split(',') {
+        let (span, step) = match part.split_once('/') {
+            Some((span, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| invalid(format!("has an invalid step `{}`", step)))?;
+                (span, step)
+            }
+            None => (part, 1),
+        };
+
+        let parse_value = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| range.contains(value))
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "has `{}`, expected {} to {}",
+                        value,
+                        range.start(),
+                        range.end()
+                    ))
+                })
+        };
+        let (start, end) = match span {
+            "*" => (*range.start(), *range.end()),
+            _ => match span.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                // a stepped single value runs to the end of the range, e.g. "5/15"
+                None if step > 1 => (parse_value(span)?, *range.end()),
+                None => {
+                    let value = parse_value(span)?;
+                    (value, value)
+                }
+            },
+        };
+        if start > end {
+            return Err(invalid(format!("has a reversed range `{}`", span)));
+        }
+
+        for value in (start..=end).step_by(step as usize) {
+            values[value as usize] = true;
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const MONDAY: u64 = 1_704_067_200;
+    const DAY: u64 = 86_400;
+
+    /// Values a parsed field is set for.
+    fn values(field: &str, range: RangeInclusive<u32>) -> Vec<u32> {
+        parse_field(field, "field", range)
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, set)| **set)
+            .map(|(value, _)| value as u32)
+            .collect()
+    }
+
+    #[test]
+    fn parses_steps() {
+        assert_eq!(values("*/15", 0..=59), [0, 15, 30, 45]);
+        assert_eq!(values("5/15", 0..=59), [5, 20, 35, 50]);
+        assert_eq!(values("0-30/10", 0..=59), [0, 10, 20, 30]);
+        assert_eq!(
+            values("1-5,10,20-22", 0..=59),
+            [1, 2, 3, 4, 5, 10, 20, 21, 22]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        for (expression, error) in [
+            (
+                "0 0 * * 5-1",
+                "day of week `5-1` has a reversed range `5-1`",
+            ),
+            ("60 * * * *", "minute `60` has `60`, expected 0 to 59"),
+            ("0 24 * * *", "hour `24` has `24`, expected 0 to 23"),
+            ("0 0 0 * *", "day of month `0` has `0`, expected 1 to 31"),
+            ("0 0 * 13 *", "month `13` has `13`, expected 1 to 12"),
+            ("0 0 * * 8", "day of week `8` has `8`, expected 0 to 7"),
+            ("*/0 * * * *", "minute `*/0` has an invalid step `0`"),
+            ("a * * * *", "minute `a` has `a`, expected 0 to 59"),
+        ] {
+            assert_eq!(Schedule::parse(expression).unwrap_err(), error);
+        }
+        assert!(Schedule::parse("0 0 * *").unwrap_err().contains("found 4"));
+    }
+
+    #[test]
+    fn day_of_week_seven_is_sunday() {
+        assert_eq!(Schedule::parse("0 0 * * 7"), Schedule::parse("0 0 * * 0,7"));
+        let schedule = Schedule::parse("0 0 * * 7").unwrap();
+        // 2024-01-07
+        assert_eq!(schedule.next_after(MONDAY), Some(MONDAY + 6 * DAY));
+    }
+
+    #[test]
+    fn restricted_day_of_month_or_week_fires_on_either() {
+        // Fridays or the 13th
+        let schedule = Schedule::parse("0 0 13 * 5").unwrap();
+        let mut fires = Vec::new();
+        let mut timestamp = MONDAY;
+        for _ in 0..3 {
+            timestamp = schedule.next_after(timestamp).unwrap();
+            fires.push(format_timestamp(timestamp));
+        }
+        assert_eq!(
+            fires,
+            [
+                "2024-01-05 00:00 UTC",
+                "2024-01-12 00:00 UTC",
+                "2024-01-13 00:00 UTC",
+            ]
+        );
+
+        // with day of month unrestricted, only Fridays fire
+        let fridays = Schedule::parse("0 0 * * 5").unwrap();
+        assert!(!fridays.matches(MONDAY + 12 * DAY));
+        assert!(fridays.matches(MONDAY + 11 * DAY));
+    }
+
+    #[test]
+    fn matches_and_next_after() {
+        let schedule = Schedule::parse("*/15 3 * * *").unwrap();
+        assert!(schedule.matches(MONDAY + 3 * 3600 + 15 * 60 + 59));
+        assert!(!schedule.matches(MONDAY + 3 * 3600 + 16 * 60));
+        assert_eq!(
+            schedule.next_after(MONDAY + 3 * 3600 + 45 * 60),
+            Some(MONDAY + DAY + 3 * 3600)
+        );
+        assert_eq!(
+            schedule.next_after(MONDAY + 3 * 3600),
+            Some(MONDAY + 3 * 3600 + 15 * 60)
+        );
+    }
+
+    #[test]
+    fn impossible_schedule_never_fires() {
+        let schedule = Schedule::parse("0 0 31 2 *").unwrap();
+        assert_eq!(schedule.next_after(MONDAY), None);
+    }
+
+    #[test]
+    fn leap_day_fires_every_four_years() {
+        let schedule = Schedule::parse("0 0 29 2 *").unwrap();
+        let next = schedule.next_after(MONDAY + 60 * DAY).unwrap();
+        assert_eq!(format_timestamp(next), "2028-02-29 00:00 UTC");
+    }
+
+    #[test]
+    fn civil_date_around_leap_days_and_year_boundaries() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (11_016, (2000, 2, 29)),
+            (19_416, (2023, 2, 28)),
+            (19_417, (2023, 3, 1)),
+            (19_722, (2023, 12, 31)),
+            (19_723, (2024, 1, 1)),
+            (19_781, (2024, 2, 28)),
+            (19_782, (2024, 2, 29)),
+            (19_783, (2024, 3, 1)),
+            (20_088, (2024, 12, 31)),
+            (20_089, (2025, 1, 1)),
+        ] {
+            assert_eq!(civil_date(days), date, "{} days", days);
+        }
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(
+            format_timestamp(MONDAY + 59 * DAY + 3 * 3600 + 7 * 60 + 30),
+            "2024-02-29 03:07 UTC"
+        );
+    }
+}
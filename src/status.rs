@@ -0,0 +1,622 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::project::{BuildOutput, BuiltImage, RolloutResult};
+
+/// Maximum number of build records kept in history.
+const HISTORY_LIMIT: usize = 200;
+
+/// The status file is compacted once this many lines per retained record were appended to it,
+/// since every build appends a line for each state it goes through.
+const COMPACT_RATIO: usize = 4;
+
+/// Current state of a build.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// Finished without building, e.g. because no watched path changed.
+    Skipped,
+}
+
+/// Record of a single build, as returned by the status endpoints.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// Unique, increasing build id.
+    pub id: u64,
+    /// Slug of the project being built.
+    pub project: String,
+    pub state: BuildState,
+    /// Unix timestamp (seconds) when the build started, or was queued if it hasn't started yet.
+    pub started_at: u64,
+    /// Unix timestamp (seconds) when the build finished.
+    pub finished_at: Option<u64>,
+    /// Failure reason for failed builds.
+    pub error: Option<String>,
+    /// Commit that was built (or skipped), when building from git.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Why a skipped build didn't build anything.
+    #[serde(default)]
+    pub skipped: Option<String>,
+    /// Images built by a successful build, with their digests and durations.
+    #[serde(default)]
+    pub images: Vec<BuiltImage>,
+    /// Rollouts of a successful build to its deployment resources.
+    #[serde(default)]
+    pub rollouts: Vec<RolloutResult>,
+}
+
+impl BuildRecord {
+    /// Report of the build, once it finished.
+    pub fn report(&self) -> BuildReport<'_> {
+        BuildReport {
+            build_id: self.id,
+            project: &self.project,
+            state: self.state,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            commit: self.commit.as_deref(),
+            error: self.error.as_deref(),
+            skipped: self.skipped.as_deref(),
+            images: &self.images,
+            rollouts: &self.rollouts,
+        }
+    }
+}
+
+/// Machine-readable report of a finished build, for tooling that records deployment provenance.
+#[derive(Debug, Serialize)]
+pub struct BuildReport<'a> {
+    pub build_id: u64,
+    pub project: &'a str,
+    pub state: BuildState,
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    pub commit: Option<&'a str>,
+    pub error: Option<&'a str>,
+    pub skipped: Option<&'a str>,
+    /// Images built, with their digests and durations.
+    pub images: &'a [BuiltImage],
+    /// Rollouts to the deployment resources, in the order they ran.
+    pub rollouts: &'a [RolloutResult],
+}
+
+/// Write the report of a finished build to "<project>-<build id>.json" in `dir`, returning the
+/// file's path.
+pub fn write_report(dir: &Path, record: &BuildRecord) -> Result<PathBuf, String> {
+    let path = dir.join(format!("{}-{}.json", record.project, record.id));
+    let report = serde_json::to_string_pretty(&record.report()).map_err(|e| e.to_string())?;
+    fs::create_dir_all(dir)
+        .and_then(|()| fs::write(&path, report))
+        .map_err(|e| format!("Failed to write build report `{}`: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Build status and history tracking.
+///
+/// Records are kept in memory, and when a status file is configured every change is also appended
+/// to it as a JSON line so history survives restarts.
+pub struct BuildStatus {
+    inner: Mutex<StatusInner>,
+    path: Option<PathBuf>,
+}
+
+struct StatusInner {
+    next_id: u64,
+    history: VecDeque<BuildRecord>,
+    /// Lines appended to the status file since it was last compacted.
+    appended: usize,
+}
+
+impl BuildStatus {
+    /// Create the status store, loading previous records from `path` if set.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, String> {
+        let mut history = VecDeque::new();
+
+        if let Some(path) = &path
+            && path.exists()
+        {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Could not read status file `{}`: {}", path.display(), e))?;
+
+            // later lines for the same build replace earlier ones
+            let mut records: Vec<BuildRecord> = Vec::new();
+            let mut positions: HashMap<u64, usize> = HashMap::new();
+            for (number, line) in contents.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: BuildRecord = match serde_json::from_str(line) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        tracing::warn!(
+                            "Skipping invalid record on line {} of status file: {}",
+                            number + 1,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                match positions.get(&record.id) {
+                    Some(&position) => records[position] = record,
+                    None => {
+                        positions.insert(record.id, records.len());
+                        records.push(record);
+                    }
+                }
+            }
+
+            for mut record in records {
+                // builds can't survive a restart, so anything still pending was interrupted
+                if matches!(record.state, BuildState::Queued | BuildState::Running) {
+                    record.state = BuildState::Failed;
+                    record.error = Some("Interrupted by server restart".to_string());
+                }
+                history.push_back(record);
+            }
+            while history.len() > HISTORY_LIMIT {
+                history.pop_front();
+            }
+
+            tracing::info!(
+                "Loaded {} build record(s) from `{}`",
+                history.len(),
+                path.display()
+            );
+        }
+
+        let next_id = history
+            .iter()
+            .map(|record| record.id + 1)
+            .max()
+            .unwrap_or(1);
+        let mut inner = StatusInner {
+            next_id,
+            history,
+            appended: 0,
+        };
+        compact(path.as_deref(), &mut inner)?;
+
+        Ok(BuildStatus {
+            inner: Mutex::new(inner),
+            path,
+        })
+    }
+
+    /// Record a newly triggered build waiting to start, returning its id.
+    ///
+    /// With a `limit`, the build is refused with the current queue depth when that many builds of
+    /// the project are already waiting.
+    pub fn queue(&self, project: &str, limit: Option<usize>) -> Result<u64, usize> {
+        let mut inner = self.inner.lock().unwrap();
+        let depth = queue_depth(&inner.history, project);
+        if limit.is_some_and(|limit| depth >= limit) {
+            return Err(depth);
+        }
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+
+        let record = BuildRecord {
+            id,
+            project: project.to_string(),
+            state: BuildState::Queued,
+            started_at: now(),
+            finished_at: None,
+            commit: None,
+            skipped: None,
+            error: None,
+            images: Vec::new(),
+            rollouts: Vec::new(),
+        };
+        self.persist(&record);
+        inner.history.push_back(record);
+        while inner.history.len() > HISTORY_LIMIT {
+            inner.history.pop_front();
+        }
+        self.appended(&mut inner);
+
+        Ok(id)
+    }
+
+    /// Number of builds of a project waiting to start.
+    pub fn queue_depth(&self, project: &str) -> usize {
+        let inner = self.inner.lock().unwrap();
+        queue_depth(&inner.history, project)
+    }
+
+    /// Record that a queued build has started running.
+    pub fn running(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(record) = inner.history.iter_mut().find(|record| record.id == id) else {
+            tracing::warn!("No status record found for build {}", id);
+            return;
+        };
+
+        record.state = BuildState::Running;
+        record.started_at = now();
+        self.persist(record);
+        self.appended(&mut inner);
+    }
+
+    /// Record the result of a build, returning the finished record.
+    pub fn finish(&self, id: u64, result: &Result<BuildOutput, String>) -> Option<BuildRecord> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(record) = inner.history.iter_mut().find(|record| record.id == id) else {
+            tracing::warn!("No status record found for build {}", id);
+            return None;
+        };
+
+        record.finished_at = Some(now());
+        match result {
+            Ok(output) => {
+                record.state = if output.skipped.is_some() {
+                    BuildState::Skipped
+                } else {
+                    BuildState::Succeeded
+                };
+                record.commit = output.commit.clone();
+                record.images = output.images.clone();
+                record.rollouts = output.rollouts.clone();
+                record.skipped = output.skipped.clone();
+            }
+            Err(e) => {
+                record.state = BuildState::Failed;
+                record.error = Some(e.clone());
+            }
+        }
+        self.persist(record);
+        let record = record.clone();
+        self.appended(&mut inner);
+        Some(record)
+    }
+
+    /// Most recent build of each project, keyed by slug.
+    pub fn latest(&self) -> HashMap<String, BuildRecord> {
+        let inner = self.inner.lock().unwrap();
+        let mut latest = HashMap::new();
+        for record in &inner.history {
+            latest.insert(record.project.clone(), record.clone());
+        }
+        latest
+    }
+
+    /// Build with the given id, if it's still in the history.
+    pub fn get(&self, id: u64) -> Option<BuildRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner.history.iter().find(|record| record.id == id).cloned()
+    }
+
+    /// Commit of the most recent successful build of a project.
+    pub fn last_built_commit(&self, project: &str) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .history
+            .iter()
+            .rev()
+            .filter(|record| record.project == project && record.state == BuildState::Succeeded)
+            .find_map(|record| record.commit.clone())
+    }
+
+    /// Most recent build of a single project.
+    pub fn latest_for(&self, project: &str) -> Option<BuildRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .history
+            .iter()
+            .rev()
+            .find(|record| record.project == project)
+            .cloned()
+    }
+
+    /// Build history, newest first, optionally limited to a single project.
+    pub fn history(&self, project: Option<&str>) -> Vec<BuildRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .history
+            .iter()
+            .rev()
+            .filter(|record| project.is_none_or(|project| record.project == project))
+            .cloned()
+            .collect()
+    }
+
+    /// Count a line appended to the status file, compacting the file once it grew well past the
+    /// retained history.
+    fn appended(&self, inner: &mut StatusInner) {
+        inner.appended += 1;
+        if inner.appended <= inner.history.len() * COMPACT_RATIO {
+            return;
+        }
+        if let Err(e) = compact(self.path.as_deref(), inner) {
+            tracing::warn!("{}", e);
+        }
+    }
+
+    fn persist(&self, record: &BuildRecord) {
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let result = serde_json::to_string(record)
+            .map_err(|e| e.to_string())
+            .and_then(|line| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut file| writeln!(file, "{}", line))
+                    .map_err(|e| e.to_string())
+            });
+
+        // persistence is best-effort, a failed write shouldn't fail the build
+        if let Err(e) = result {
+            tracing::warn!(
+                "Failed to persist build {} to `{}`: {}",
+                record.id,
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Rewrite the status file at `path` with only the retained records.
+///
+/// The records are written to "<path>.tmp" first and moved over the file, so a crash while
+/// writing leaves the previous file intact.
+fn compact(path: Option<&Path>, inner: &mut StatusInner) -> Result<(), String> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+
+    let mut contents = String::new();
+    for record in &inner.history {
+        let line = serde_json::to_string(record)
+            .map_err(|e| format!("Could not serialize build record: {}", e))?;
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    fs::write(&temp_path, contents)
+        .and_then(|()| fs::rename(&temp_path, path))
+        .map_err(|e| format!("Could not write status file `{}`: {}", path.display(), e))?;
+    inner.appended = 0;
+    Ok(())
+}
+
+fn queue_depth(history: &VecDeque<BuildRecord>, project: &str) -> usize {
+    history
+        .iter()
+        .filter(|record| record.project == project && record.state == BuildState::Queued)
+        .count()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Path of a status file in the temp directory, unique to the test and removed on drop.
+    struct StatusFile(PathBuf);
+
+    impl StatusFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "build-hook-status-{}-{}.jsonl",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            StatusFile(path)
+        }
+
+        fn write(&self, contents: &str) {
+            fs::write(&self.0, contents).unwrap();
+        }
+
+        fn lines(&self) -> Vec<String> {
+            fs::read_to_string(&self.0)
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    impl Drop for StatusFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn record(id: u64, project: &str, state: &str) -> String {
+        format!(
+            r#"{{"id":{},"project":"{}","state":"{}","started_at":100,"finished_at":null,"error":null}}"#,
+            id, project, state
+        )
+    }
+
+    fn succeeded() -> Result<BuildOutput, String> {
+        Ok(BuildOutput {
+            commit: Some("abc123".to_string()),
+            images: Vec::new(),
+            rollouts: Vec::new(),
+            skipped: None,
+        })
+    }
+
+    #[test]
+    fn later_lines_replace_earlier_ones() {
+        let file = StatusFile::new("replay");
+        file.write(
+            &[
+                record(1, "api", "queued"),
+                record(2, "web", "queued"),
+                record(1, "api", "running"),
+                record(1, "api", "succeeded"),
+            ]
+            .join("\n"),
+        );
+
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let history = status.history(None);
+        assert_eq!(history.len(), 2);
+        assert_eq!(status.get(1).unwrap().state, BuildState::Succeeded);
+        // builds keep the order they were first recorded in
+        assert_eq!(history[0].id, 2);
+        assert_eq!(history[1].id, 1);
+        // ids continue after the highest loaded one
+        assert_eq!(status.queue("api", None), Ok(3));
+    }
+
+    #[test]
+    fn unfinished_builds_are_interrupted() {
+        let file = StatusFile::new("interrupted");
+        file.write(
+            &[
+                record(1, "api", "queued"),
+                record(2, "web", "running"),
+                record(3, "api", "failed"),
+            ]
+            .join("\n"),
+        );
+
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        for id in [1, 2] {
+            let record = status.get(id).unwrap();
+            assert_eq!(record.state, BuildState::Failed);
+            assert_eq!(
+                record.error.as_deref(),
+                Some("Interrupted by server restart")
+            );
+        }
+        assert_eq!(status.get(3).unwrap().error, None);
+        assert_eq!(status.queue_depth("api"), 0);
+    }
+
+    #[test]
+    fn invalid_lines_are_skipped() {
+        let file = StatusFile::new("invalid");
+        file.write(&format!(
+            "{}\nnot json\n\n{{\"id\":2}}\n{}\n",
+            record(1, "api", "succeeded"),
+            record(3, "web", "skipped")
+        ));
+
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let ids: Vec<u64> = status
+            .history(None)
+            .iter()
+            .map(|record| record.id)
+            .collect();
+        assert_eq!(ids, [3, 1]);
+    }
+
+    #[test]
+    fn load_compacts_to_one_line_per_build() {
+        let file = StatusFile::new("load-compact");
+        file.write(
+            &[
+                record(1, "api", "queued"),
+                record(1, "api", "running"),
+                record(1, "api", "succeeded"),
+                "garbage".to_string(),
+            ]
+            .join("\n"),
+        );
+
+        BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let lines = file.lines();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains(r#""state":"succeeded""#), "{}", lines[0]);
+        let mut temp_path = file.0.clone().into_os_string();
+        temp_path.push(".tmp");
+        assert!(!PathBuf::from(temp_path).exists());
+    }
+
+    #[test]
+    fn load_keeps_latest_history() {
+        let file = StatusFile::new("limit");
+        let lines: Vec<String> = (1..=HISTORY_LIMIT as u64 + 5)
+            .map(|id| record(id, "api", "succeeded"))
+            .collect();
+        file.write(&lines.join("\n"));
+
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let history = status.history(None);
+        assert_eq!(history.len(), HISTORY_LIMIT);
+        assert_eq!(history.last().unwrap().id, 6);
+        assert_eq!(file.lines().len(), HISTORY_LIMIT);
+    }
+
+    #[test]
+    fn changes_are_appended_and_survive_a_restart() {
+        let file = StatusFile::new("append");
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let id = status.queue("api", None).unwrap();
+        status.running(id);
+        status.finish(id, &succeeded()).unwrap();
+        // three states of one build, below the compaction threshold
+        assert_eq!(file.lines().len(), 3);
+
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+        let record = status.get(id).unwrap();
+        assert_eq!(record.state, BuildState::Succeeded);
+        assert_eq!(record.commit.as_deref(), Some("abc123"));
+        assert_eq!(status.last_built_commit("api").as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn appending_compacts_once_file_outgrows_history() {
+        let file = StatusFile::new("append-compact");
+        let status = BuildStatus::load(Some(file.0.clone())).unwrap();
+
+        let id = status.queue("api", None).unwrap();
+        for _ in 0..COMPACT_RATIO {
+            status.running(id);
+        }
+
+        // the line past COMPACT_RATIO lines per record rewrote the file to the record alone
+        assert_eq!(file.lines().len(), 1);
+        status.finish(id, &succeeded()).unwrap();
+        assert_eq!(file.lines().len(), 2);
+    }
+
+    #[test]
+    fn queue_limit_refuses_with_depth() {
+        let status = BuildStatus::load(None).unwrap();
+
+        assert_eq!(status.queue("api", Some(2)), Ok(1));
+        assert_eq!(status.queue("api", Some(2)), Ok(2));
+        assert_eq!(status.queue("api", Some(2)), Err(2));
+        assert_eq!(status.queue("web", Some(2)), Ok(3));
+        status.running(1);
+        assert_eq!(status.queue_depth("api"), 1);
+    }
+}
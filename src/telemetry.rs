@@ -0,0 +1,613 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Base URL of the OTLP/HTTP collector, e.g. "http://otel-collector:4318". Traces are only
+/// exported when it's set.
+const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+/// Service name reported with every span.
+const SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+const DEFAULT_SERVICE_NAME: &str = "build-hook";
+/// Finished spans waiting for export. Spans are dropped while the queue is full, e.g. when the
+/// collector is down, rather than growing without bound.
+const QUEUE_SIZE: usize = 4096;
+/// Most spans sent in one export request.
+const MAX_BATCH_SIZE: usize = 512;
+/// How long finished spans wait before a partial batch is exported.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long one export request may take before it's given up on.
+const EXPORT_TIMEOUT_SECS: u64 = 10;
+/// How long shutdown waits for the last spans to be exported.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Queue of the exporter thread, kept so the last spans can be flushed on shutdown.
+static EXPORT_QUEUE: OnceLock<SyncSender<Export>> = OnceLock::new();
+
+enum Export {
+    Span(SpanData),
+    /// Export everything queued so far, then reply.
+    Flush(mpsc::Sender<()>),
+}
+
+/// A span being recorded, kept in the span's extensions until it closes.
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    level: Level,
+    start_unix_nanos: u128,
+    end_unix_nanos: u128,
+    attributes: Vec<(String, AttributeValue)>,
+}
+
+/// Value of a span attribute, typed as OTLP types it.
+#[derive(Clone, Debug, PartialEq)]
+enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+/// Layer exporting closed spans as OTLP traces, with each span a child of the span it was
+/// created in, e.g. a build of the request that triggered it.
+pub struct OtlpLayer {
+    queue: SyncSender<Export>,
+    random: SystemRandom,
+}
+
+/// The OTLP export layer, when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Spans are posted as
+/// OTLP/HTTP JSON to "<endpoint>/v1/traces" from a background thread.
+pub fn layer() -> Option<OtlpLayer> {
+    let endpoint = std::env::var(ENDPOINT_ENV)
+        .ok()
+        .filter(|endpoint| !endpoint.trim().is_empty())?;
+    let url = format!("{}/v1/traces", endpoint.trim().trim_end_matches('/'));
+    let service_name =
+        std::env::var(SERVICE_NAME_ENV).unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+
+    let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+    let spawned = std::thread::Builder::new()
+        .name("otlp-export".to_string())
+        .spawn(move || {
+            let mut exporter = Exporter::default();
+            export_spans(spans, EXPORT_INTERVAL, |batch| {
+                exporter.post(&url, &service_name, batch)
+            })
+        });
+    if let Err(e) = spawned {
+        eprintln!(
+            "Failed to start the OTLP exporter, traces won't be exported: {}",
+            e
+        );
+        return None;
+    }
+    let _ = EXPORT_QUEUE.set(queue.clone());
+    Some(OtlpLayer::new(queue))
+}
+
+/// Export the spans finished so far, e.g. before the process exits. Does nothing when traces
+/// aren't exported.
+pub fn flush() {
+    let Some(queue) = EXPORT_QUEUE.get() else {
+        return;
+    };
+    let (done, flushed) = mpsc::channel();
+    if queue.send(Export::Flush(done)).is_ok() {
+        let _ = flushed.recv_timeout(FLUSH_TIMEOUT);
+    }
+}
+
+impl OtlpLayer {
+    fn new(queue: SyncSender<Export>) -> Self {
+        OtlpLayer {
+            queue,
+            random: SystemRandom::new(),
+        }
+    }
+
+    fn random_id<const N: usize>(&self) -> [u8; N] {
+        let mut id = [0; N];
+        // the system random source doesn't fail in practice, and an id of zeros is still unique
+        // enough to export
+        let _ = self.random.fill(&mut id);
+        id
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            extensions
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span_id))
+        });
+
+        let mut attributes = Vec::new();
+        attrs.record(&mut AttributeVisitor(&mut attributes));
+        let data = SpanData {
+            trace_id: parent.map_or_else(|| self.random_id(), |(trace_id, _)| trace_id),
+            span_id: self.random_id(),
+            parent_span_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            level: *attrs.metadata().level(),
+            start_unix_nanos: unix_nanos(),
+            end_unix_nanos: 0,
+            attributes,
+        };
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut AttributeVisitor(&mut data.attributes));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        data.end_unix_nanos = unix_nanos();
+        // a full queue means the collector can't keep up, so the span is dropped
+        let _ = self.queue.try_send(Export::Span(data));
+    }
+}
+
+/// Collects span fields as attributes, keeping numbers and booleans typed.
+struct AttributeVisitor<'a>(&'a mut Vec<(String, AttributeValue)>);
+
+impl AttributeVisitor<'_> {
+    fn push(&mut self, field: &Field, value: AttributeValue) {
+        self.0.push((field.name().to_string(), value));
+    }
+}
+
+impl Visit for AttributeVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        // OTLP integers are signed, so larger values keep their digits as a string
+        match i64::try_from(value) {
+            Ok(value) => self.push(field, AttributeValue::Int(value)),
+            Err(_) => self.push(field, AttributeValue::String(value.to_string())),
+        }
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, AttributeValue::Double(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, AttributeValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, AttributeValue::String(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.push(field, AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+/// Batch finished spans and hand each batch to `post`, at the latest `interval` after its first
+/// span, until the layer is dropped.
+fn export_spans(spans: Receiver<Export>, interval: Duration, mut post: impl FnMut(&[SpanData])) {
+    let mut batch = Vec::new();
+    let mut batch_started = Instant::now();
+    loop {
+        let wait = interval.saturating_sub(batch_started.elapsed());
+        match spans.recv_timeout(wait) {
+            Ok(Export::Span(span)) => {
+                if batch.is_empty() {
+                    batch_started = Instant::now();
+                }
+                batch.push(span);
+                if batch.len() < MAX_BATCH_SIZE {
+                    continue;
+                }
+            }
+            Ok(Export::Flush(done)) => {
+                while let Ok(Export::Span(span)) = spans.try_recv() {
+                    batch.push(span);
+                }
+                post_batch(&mut post, &mut batch);
+                let _ = done.send(());
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                post_batch(&mut post, &mut batch);
+                return;
+            }
+        }
+        post_batch(&mut post, &mut batch);
+        batch_started = Instant::now();
+    }
+}
+
+/// Post a non-empty batch in chunks of at most `MAX_BATCH_SIZE` spans, emptying it.
+fn post_batch(post: &mut impl FnMut(&[SpanData]), batch: &mut Vec<SpanData>) {
+    for chunk in batch.chunks(MAX_BATCH_SIZE) {
+        post(chunk);
+    }
+    batch.clear();
+}
+
+/// Posts batches to the collector, remembering whether the last export failed so an unreachable
+/// collector is only warned about once.
+#[derive(Default)]
+struct Exporter {
+    failing: bool,
+}
+
+impl Exporter {
+    /// Post spans to the collector as an OTLP/HTTP JSON export request.
+    ///
+    /// Events logged here aren't exported in turn, since the exporter thread is in no span.
+    fn post(&mut self, url: &str, service_name: &str, spans: &[SpanData]) {
+        match post_spans(url, &export_request(service_name, spans).to_string()) {
+            Ok(()) if self.failing => {
+                self.failing = false;
+                tracing::info!("Exporting traces to {} again", url);
+            }
+            Ok(()) => {}
+            Err(e) if self.failing => {
+                tracing::debug!("Dropped {} span(s): {}", spans.len(), e);
+            }
+            Err(e) => {
+                self.failing = true;
+                tracing::warn!(
+                    "Failed to export {} span(s) to {}, dropping spans until it succeeds: {}",
+                    spans.len(),
+                    url,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// Post an export request `body` to the collector at `url` with curl.
+fn post_spans(url: &str, body: &str) -> Result<(), String> {
+    let mut child = Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--max-time",
+            &EXPORT_TIMEOUT_SECS.to_string(),
+            "--header",
+            "Content-Type: application/json",
+            "--data-binary",
+            "@-",
+            url,
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run curl: {}", e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(body.as_bytes());
+    }
+    let output = child
+        .wait_with_output()
+        .map_err(|e| format!("Failed to wait for curl: {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(())
+}
+
+/// OTLP JSON export request for `spans`, with ids hex-encoded as the JSON encoding requires.
+fn export_request(service_name: &str, spans: &[SpanData]) -> serde_json::Value {
+    let spans: Vec<serde_json::Value> = spans
+        .iter()
+        .map(|span| {
+            let level = AttributeValue::String(span.level.as_str().to_string());
+            let mut attributes = vec![attribute("level", &level)];
+            attributes.extend(
+                span.attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value)),
+            );
+            serde_json::json!({
+                "traceId": hex(&span.trace_id),
+                "spanId": hex(&span.span_id),
+                "parentSpanId": span.parent_span_id.as_ref().map(|id| hex(id)).unwrap_or_default(),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": span.start_unix_nanos.to_string(),
+                "endTimeUnixNano": span.end_unix_nanos.to_string(),
+                "attributes": attributes,
+            })
+        })
+        .collect();
+
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute(
+                    "service.name",
+                    &AttributeValue::String(service_name.to_string()),
+                )],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+/// An OTLP `KeyValue`, with 64-bit integers as strings as the protobuf JSON mapping requires.
+fn attribute(key: &str, value: &AttributeValue) -> serde_json::Value {
+    let value = match value {
+        AttributeValue::String(value) => serde_json::json!({ "stringValue": value }),
+        AttributeValue::Int(value) => serde_json::json!({ "intValue": value.to_string() }),
+        AttributeValue::Double(value) => serde_json::json!({ "doubleValue": value }),
+        AttributeValue::Bool(value) => serde_json::json!({ "boolValue": value }),
+    };
+    serde_json::json!({ "key": key, "value": value })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    fn span_data(name: &'static str) -> SpanData {
+        SpanData {
+            trace_id: [0xab; 16],
+            span_id: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08],
+            parent_span_id: None,
+            name,
+            level: Level::INFO,
+            start_unix_nanos: 1_700_000_000_000_000_000,
+            end_unix_nanos: 1_700_000_001_000_000_000,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Run `record` under a subscriber exporting to a queue of `queue_size`, returning the
+    /// spans it queued in the order they closed.
+    fn record_spans(queue_size: usize, record: impl FnOnce()) -> Vec<SpanData> {
+        let (queue, spans) = mpsc::sync_channel(queue_size);
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer::new(queue));
+        tracing::subscriber::with_default(subscriber, record);
+        spans
+            .try_iter()
+            .map(|export| match export {
+                Export::Span(span) => span,
+                Export::Flush(_) => panic!("unexpected flush"),
+            })
+            .collect()
+    }
+
+    fn attribute_value<'a>(span: &'a SpanData, key: &str) -> Option<&'a AttributeValue> {
+        span.attributes
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+
+    #[test]
+    fn encodes_span_as_otlp_json() {
+        let mut span = span_data("build");
+        span.parent_span_id = Some([0xff, 0, 0, 0, 0, 0, 0, 0x10]);
+        span.attributes = vec![
+            (
+                "tag".to_string(),
+                AttributeValue::String("app:latest".to_string()),
+            ),
+            ("attempt".to_string(), AttributeValue::Int(2)),
+            ("ratio".to_string(), AttributeValue::Double(0.5)),
+            ("cached".to_string(), AttributeValue::Bool(true)),
+        ];
+
+        let request = export_request("hook", &[span]);
+        let resource = &request["resourceSpans"][0];
+        assert_eq!(
+            resource["resource"]["attributes"],
+            serde_json::json!([{ "key": "service.name", "value": { "stringValue": "hook" } }])
+        );
+        let encoded = &resource["scopeSpans"][0]["spans"][0];
+        assert_eq!(encoded["traceId"], "abababababababababababababababab");
+        assert_eq!(encoded["spanId"], "0102030405060708");
+        assert_eq!(encoded["parentSpanId"], "ff00000000000010");
+        assert_eq!(encoded["name"], "build");
+        assert_eq!(encoded["kind"], 1);
+        assert_eq!(encoded["startTimeUnixNano"], "1700000000000000000");
+        assert_eq!(encoded["endTimeUnixNano"], "1700000001000000000");
+        assert_eq!(
+            encoded["attributes"],
+            serde_json::json!([
+                { "key": "level", "value": { "stringValue": "INFO" } },
+                { "key": "tag", "value": { "stringValue": "app:latest" } },
+                { "key": "attempt", "value": { "intValue": "2" } },
+                { "key": "ratio", "value": { "doubleValue": 0.5 } },
+                { "key": "cached", "value": { "boolValue": true } },
+            ])
+        );
+    }
+
+    #[test]
+    fn encodes_root_span_without_parent() {
+        let request = export_request("hook", &[span_data("request")]);
+        assert_eq!(
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"][0]["parentSpanId"],
+            ""
+        );
+    }
+
+    #[test]
+    fn build_span_joins_the_request_trace() {
+        let spans = record_spans(16, || {
+            let request = tracing::info_span!("request", method = "POST", path = "/build/app");
+            let _entered = request.enter();
+            let build = tracing::info_span!("build", tag = "app:latest", attempt = 1u64);
+            build.in_scope(|| tracing::debug!("building"));
+            tracing::info_span!("other").in_scope(|| {});
+            drop(build);
+        });
+
+        let [build, other, request] = &spans[..] else {
+            panic!("expected 3 spans, got {}", spans.len());
+        };
+        assert_eq!(request.name, "request");
+        assert_eq!(request.parent_span_id, None);
+        for child in [build, other] {
+            assert_eq!(child.trace_id, request.trace_id);
+            assert_eq!(child.parent_span_id, Some(request.span_id));
+            assert_ne!(child.span_id, request.span_id);
+        }
+        assert_ne!(build.span_id, other.span_id);
+        assert!(build.end_unix_nanos >= build.start_unix_nanos);
+    }
+
+    #[test]
+    fn unrelated_requests_get_their_own_trace() {
+        let spans = record_spans(16, || {
+            tracing::info_span!("request").in_scope(|| {});
+            tracing::info_span!("request").in_scope(|| {});
+        });
+
+        assert_eq!(spans.len(), 2);
+        assert_ne!(spans[0].trace_id, spans[1].trace_id);
+    }
+
+    #[test]
+    fn records_typed_attributes() {
+        let spans = record_spans(16, || {
+            let span = tracing::info_span!(
+                "build",
+                tag = "app:latest",
+                attempt = 3u64,
+                offset = -1i64,
+                ratio = 0.25,
+                cached = false,
+                huge = u64::MAX,
+                status = tracing::field::Empty,
+            );
+            span.record("status", "success");
+        });
+
+        let [span] = &spans[..] else {
+            panic!("expected 1 span, got {}", spans.len());
+        };
+        let expected = [
+            ("tag", AttributeValue::String("app:latest".to_string())),
+            ("attempt", AttributeValue::Int(3)),
+            ("offset", AttributeValue::Int(-1)),
+            ("ratio", AttributeValue::Double(0.25)),
+            ("cached", AttributeValue::Bool(false)),
+            ("huge", AttributeValue::String(u64::MAX.to_string())),
+            ("status", AttributeValue::String("success".to_string())),
+        ];
+        for (key, value) in expected {
+            assert_eq!(attribute_value(span, key), Some(&value), "{}", key);
+        }
+    }
+
+    #[test]
+    fn drops_spans_when_the_queue_is_full() {
+        let spans = record_spans(1, || {
+            tracing::info_span!("first").in_scope(|| {});
+            tracing::info_span!("second").in_scope(|| {});
+        });
+
+        let names: Vec<_> = spans.iter().map(|span| span.name).collect();
+        assert_eq!(names, ["first"]);
+    }
+
+    #[test]
+    fn posts_full_batches_and_the_rest_on_disconnect() {
+        let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+        for _ in 0..MAX_BATCH_SIZE + 1 {
+            queue.send(Export::Span(span_data("span"))).unwrap();
+        }
+        drop(queue);
+
+        let mut batches = Vec::new();
+        export_spans(spans, Duration::from_secs(60), |batch| {
+            batches.push(batch.len())
+        });
+        assert_eq!(batches, [MAX_BATCH_SIZE, 1]);
+    }
+
+    #[test]
+    fn flush_posts_queued_spans_and_replies() {
+        let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+        let (done, flushed) = mpsc::channel();
+        queue.send(Export::Span(span_data("span"))).unwrap();
+        queue.send(Export::Span(span_data("span"))).unwrap();
+        queue.send(Export::Flush(done)).unwrap();
+        drop(queue);
+
+        let mut batches = Vec::new();
+        export_spans(spans, Duration::from_secs(60), |batch| {
+            batches.push(batch.len())
+        });
+        assert_eq!(batches, [2]);
+        flushed.try_recv().unwrap();
+    }
+
+    #[test]
+    fn posts_partial_batch_after_the_interval() {
+        let (queue, spans) = mpsc::sync_channel(QUEUE_SIZE);
+        let (posted, batches) = mpsc::channel();
+        let exporter = std::thread::spawn(move || {
+            export_spans(spans, Duration::from_millis(10), |batch| {
+                let _ = posted.send(batch.len());
+            })
+        });
+
+        queue.send(Export::Span(span_data("span"))).unwrap();
+        // the queue is still open, so only the interval can have posted the batch
+        assert_eq!(batches.recv_timeout(Duration::from_secs(5)), Ok(1));
+        drop(queue);
+        exporter.join().unwrap();
+        assert!(batches.try_recv().is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderMap;
+use ring::hmac;
+use serde::Deserialize;
+
+const BRANCH_REF_PREFIX: &str = "refs/heads/";
+/// Unique id GitHub sends with every delivery, reused when a delivery is retried.
+const DELIVERY_HEADER: &str = "x-github-delivery";
+/// Request id set by proxies and other providers, e.g. a CI run's id.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Type of event GitHub sends with every delivery, e.g. "push" or "pull_request".
+const EVENT_HEADER: &str = "x-github-event";
+/// Event that triggers a build unless a project configures other events.
+pub const PUSH_EVENT: &str = "push";
+/// Event GitHub sends when a webhook is created or tested.
+pub const PING_EVENT: &str = "ping";
+/// HMAC-SHA256 signature of the body, as "sha256=<hex>", sent when the webhook has a secret.
+const SIGNATURE_HEADER: &str = "x-hub-signature-256";
+/// Maximum number of delivery ids remembered per project.
+const DELIVERY_CACHE_SIZE: usize = 100;
+
+/// Subset of a git provider push event payload.
+#[derive(Debug, Default, Deserialize)]
+struct PushPayload {
+    /// Full ref that was pushed, e.g. "refs/heads/main".
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+}
+
+/// Payload of a trigger choosing which of the project's images to build.
+#[derive(Debug, Default, Deserialize)]
+struct ImagesPayload {
+    images: Option<Vec<String>>,
+}
+
+/// Extract the pushed branch from a webhook payload.
+///
+/// Returns `None` when the body is empty, isn't a push payload, or the pushed ref isn't a branch.
+pub fn pushed_branch(body: &[u8]) -> Option<String> {
+    if body.is_empty() {
+        return None;
+    }
+
+    let payload: PushPayload = serde_json::from_slice(body).ok()?;
+    payload
+        .git_ref?
+        .strip_prefix(BRANCH_REF_PREFIX)
+        .map(str::to_string)
+}
+
+/// Extract the names of the images to build from a `{"images": [...]}` payload.
+///
+/// Returns `None` when the body is empty, isn't JSON, or doesn't list images, e.g. a push payload.
+pub fn requested_images(body: &[u8]) -> Option<Vec<String>> {
+    if body.is_empty() {
+        return None;
+    }
+
+    let payload: ImagesPayload = serde_json::from_slice(body).ok()?;
+    payload.images
+}
+
+/// Check the body's signature matches one of the secrets held by the environment variables
+/// `secret_envs`. Several secrets are accepted so one can be rotated without downtime.
+pub fn verify_signature(
+    headers: &HeaderMap,
+    body: &[u8],
+    secret_envs: &[String],
+) -> Result<(), String> {
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("Missing webhook signature")?;
+    let signature = signature
+        .trim()
+        .strip_prefix("sha256=")
+        .and_then(decode_hex)
+        .ok_or("Malformed webhook signature")?;
+
+    let mut secrets = 0;
+    for secret_env in secret_envs {
+        let Ok(secret) = std::env::var(secret_env) else {
+            tracing::warn!("Webhook secret variable {} is not set", secret_env);
+            continue;
+        };
+        secrets += 1;
+        // compared in constant time by ring
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.trim().as_bytes());
+        if hmac::verify(&key, body, &signature).is_ok() {
+            return Ok(());
+        }
+    }
+
+    if secrets == 0 {
+        return Err("No webhook secret is set to verify the signature with".to_string());
+    }
+    Err("Invalid webhook signature".to_string())
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // from_str_radix alone would also take a sign, e.g. "+1"
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Read the provider delivery id from the request headers.
+pub fn delivery_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(DELIVERY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+/// Read the request id from the request headers.
+pub fn request_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::to_string)
+}
+
+/// Read the provider event type from the request headers.
+pub fn event_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(EVENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|event| !event.is_empty())
+        .map(str::to_ascii_lowercase)
+}
+
+/// Recently seen delivery ids per project, used to ignore retried deliveries.
+pub struct DeliveryCache {
+    window: Duration,
+    seen: Mutex<HashMap<String, VecDeque<(String, Instant)>>>,
+}
+
+impl DeliveryCache {
+    pub fn new(window: Duration) -> Self {
+        DeliveryCache {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `id` was already recorded for `project` within the window.
+    pub fn is_duplicate(&self, project: &str, id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap();
+        let Some(deliveries) = seen.get_mut(project) else {
+            return false;
+        };
+
+        self.expire(deliveries);
+        deliveries.iter().any(|(seen_id, _)| seen_id == id)
+    }
+
+    /// Remember `id` as handled for `project`, evicting the oldest id when full.
+    pub fn record(&self, project: &str, id: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        let deliveries = seen.entry(project.to_string()).or_default();
+
+        self.expire(deliveries);
+        deliveries.push_back((id.to_string(), Instant::now()));
+        while deliveries.len() > DELIVERY_CACHE_SIZE {
+            deliveries.pop_front();
+        }
+    }
+
+    fn expire(&self, deliveries: &mut VecDeque<(String, Instant)>) {
+        while deliveries
+            .front()
+            .is_some_and(|(_, seen_at)| seen_at.elapsed() > self.window)
+        {
+            deliveries.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"ref":"refs/heads/main"}"#;
+
+    /// Set a secret variable only this test reads, so tests running in parallel don't race.
+    fn set_secret(name: &str, secret: &str) -> String {
+        // SAFETY: every test uses its own variable names and nothing else reads them
+        unsafe { std::env::set_var(name, secret) };
+        name.to_string()
+    }
+
+    fn signed(secret: &str, body: &[u8]) -> HeaderMap {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let hex: String = hmac::sign(&key, body)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        signature(&format!("sha256={}", hex))
+    }
+
+    fn signature(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(SIGNATURE_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn accepts_signature_of_either_secret() {
+        let secrets = [
+            set_secret("WEBHOOK_TEST_ROTATION_OLD", "old-secret"),
+            set_secret("WEBHOOK_TEST_ROTATION_NEW", "new-secret\n"),
+        ];
+
+        verify_signature(&signed("old-secret", BODY), BODY, &secrets).unwrap();
+        // surrounding whitespace of a secret, e.g. from a mounted file, is ignored
+        verify_signature(&signed("new-secret", BODY), BODY, &secrets).unwrap();
+    }
+
+    #[test]
+    fn rejects_wrong_signature() {
+        let secrets = [set_secret("WEBHOOK_TEST_WRONG", "secret")];
+
+        assert_eq!(
+            verify_signature(&signed("other-secret", BODY), BODY, &secrets),
+            Err("Invalid webhook signature".to_string())
+        );
+        assert_eq!(
+            verify_signature(&signed("secret", b"{}"), BODY, &secrets),
+            Err("Invalid webhook signature".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_header() {
+        let secrets = [set_secret("WEBHOOK_TEST_MALFORMED", "secret")];
+
+        assert_eq!(
+            verify_signature(&HeaderMap::new(), BODY, &secrets),
+            Err("Missing webhook signature".to_string())
+        );
+        for value in ["sha1=abcd", "sha256=zz", "sha256=abc", "abcd"] {
+            assert_eq!(
+                verify_signature(&signature(value), BODY, &secrets),
+                Err("Malformed webhook signature".to_string()),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_when_no_secret_is_set() {
+        let secrets = ["WEBHOOK_TEST_UNSET".to_string()];
+
+        assert_eq!(
+            verify_signature(&signed("secret", BODY), BODY, &secrets),
+            Err("No webhook secret is set to verify the signature with".to_string())
+        );
+        assert_eq!(
+            verify_signature(&signed("secret", BODY), BODY, &[]),
+            Err("No webhook secret is set to verify the signature with".to_string())
+        );
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("00ff7A"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("+1"), None);
+        // a multi-byte character doesn't split into hex digits
+        assert_eq!(decode_hex("é0"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+}